@@ -0,0 +1,793 @@
+//! A small filter language over packet fields, e.g.
+//!
+//! ```text
+//! proto == tcp and port in 1..1024 and tag == "pci" and flags has SYN
+//! src in 10.0.0.0/8 and not (port in [22, 443]) and time > 2026-10-18T10:00:00Z
+//! ```
+//!
+//! Conditions are `<field> <operator> <value>` and combine with `and`, `or`, `not` and
+//! parentheses. Filters are checked when they are parsed, so a typo fails before any
+//! log is read.
+
+use crate::PacketInfo;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use std::fmt;
+use std::net::Ipv4Addr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterError {
+    /// Byte offset in the expression the error was found at.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Field names, bare values and keywords: `port`, `tcp`, `10.0.0.0/8`, `1..1024`.
+    Word(String),
+    /// A quoted string.
+    Text(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 11] = ["==", "!=", "<=", ">=", "<", ">", "(", ")", "[", "]", ","];
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => text.push(escaped),
+                        None => break,
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => {
+                        return Err(FilterError {
+                            position: start,
+                            message: "unterminated string".to_string(),
+                        })
+                    }
+                }
+            }
+            tokens.push((start, Token::Text(text)));
+            continue;
+        }
+
+        if let Some(op) = OPERATORS.iter().find(|op| input[start..].starts_with(**op)) {
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            tokens.push((start, Token::Op(op)));
+            continue;
+        }
+
+        let mut word = String::new();
+        while let Some(&(_, c)) = chars.peek() {
+            if c.is_alphanumeric() || "_.:/-+".contains(c) {
+                word.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        if word.is_empty() {
+            return Err(FilterError {
+                position: start,
+                message: format!("unexpected '{}'", c),
+            });
+        }
+        tokens.push((start, Token::Word(word)));
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Compare {
+    fn holds<T: PartialOrd>(self, left: T, right: T) -> bool {
+        match self {
+            Compare::Eq => left == right,
+            Compare::Ne => left != right,
+            Compare::Lt => left < right,
+            Compare::Le => left <= right,
+            Compare::Gt => left > right,
+            Compare::Ge => left >= right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberField {
+    Port,
+    SourcePort,
+    Count,
+    Ttl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AddressField {
+    Source,
+    Listener,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextField {
+    Protocol,
+    Tag,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimeField {
+    Time,
+    LastSeen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Number(NumberField),
+    Address(AddressField),
+    Text(TextField),
+    Time(TimeField),
+    Flags,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("port", Field::Number(NumberField::Port)),
+    ("target_port", Field::Number(NumberField::Port)),
+    ("sport", Field::Number(NumberField::SourcePort)),
+    ("source_port", Field::Number(NumberField::SourcePort)),
+    ("count", Field::Number(NumberField::Count)),
+    ("ttl", Field::Number(NumberField::Ttl)),
+    ("src", Field::Address(AddressField::Source)),
+    ("source_ip", Field::Address(AddressField::Source)),
+    ("listener", Field::Address(AddressField::Listener)),
+    ("listener_ip", Field::Address(AddressField::Listener)),
+    ("proto", Field::Text(TextField::Protocol)),
+    ("protocol", Field::Text(TextField::Protocol)),
+    ("tag", Field::Text(TextField::Tag)),
+    ("network_tag", Field::Text(TextField::Tag)),
+    ("time", Field::Time(TimeField::Time)),
+    ("timestamp", Field::Time(TimeField::Time)),
+    ("last_seen", Field::Time(TimeField::LastSeen)),
+    ("flags", Field::Flags),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Number(NumberField, Compare, u64),
+    /// Inclusive ranges, a single value is a range of one.
+    NumberIn(NumberField, Vec<(u64, u64)>),
+    /// Networks as (address, prefix), a single address is a /32.
+    AddressIn(AddressField, Vec<(Ipv4Addr, u8)>),
+    TextIn(TextField, Vec<String>),
+    Time(TimeField, Compare, DateTime<Utc>),
+    HasFlag(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Condition(Condition),
+}
+
+/// A parsed filter expression, see the module docs for the syntax.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    expr: Expr,
+    source: String,
+}
+
+struct Parser<'a> {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    input: &'a str,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map(|(position, _)| *position)
+            .unwrap_or(self.input.len())
+    }
+
+    fn error(&self, message: impl Into<String>) -> FilterError {
+        FilterError {
+            position: self.position(),
+            message: message.into(),
+        }
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).map(|(_, token)| token.clone());
+        self.next += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found =
+            matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword));
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), FilterError> {
+        match self.peek() {
+            Some(Token::Op(found)) if *found == op => {
+                self.next += 1;
+                Ok(())
+            }
+            _ => Err(self.error(format!("expected '{}'", op))),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.and()?;
+        while self.keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.not()?;
+        while self.keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, FilterError> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if self.peek() == Some(&Token::Op("(")) {
+            self.next += 1;
+            let expr = self.or()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        self.condition()
+    }
+
+    /// A value, quoted or bare.
+    fn value(&mut self) -> Result<(usize, String), FilterError> {
+        let position = self.position();
+        match self.advance() {
+            Some(Token::Word(value)) | Some(Token::Text(value)) => Ok((position, value)),
+            _ => {
+                self.next -= 1;
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+
+    /// `[a, b, c]` or a single value.
+    fn values(&mut self) -> Result<Vec<(usize, String)>, FilterError> {
+        if self.peek() != Some(&Token::Op("[")) {
+            return Ok(vec![self.value()?]);
+        }
+        self.next += 1;
+        let mut values = vec![self.value()?];
+        while self.peek() == Some(&Token::Op(",")) {
+            self.next += 1;
+            values.push(self.value()?);
+        }
+        self.expect("]")?;
+        Ok(values)
+    }
+
+    fn condition(&mut self) -> Result<Expr, FilterError> {
+        let (position, name) = self.value()?;
+        let field = FIELDS
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(&name))
+            .map(|(_, field)| *field)
+            .ok_or_else(|| FilterError {
+                position,
+                message: format!(
+                    "unknown field '{}', expected one of {}",
+                    name,
+                    FIELDS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            })?;
+
+        let operator_position = self.position();
+        let operator = match self.advance() {
+            Some(Token::Op(op)) if !matches!(op, "(" | ")" | "[" | "]" | ",") => op.to_string(),
+            Some(Token::Word(word)) if ["in", "has"].contains(&word.to_lowercase().as_str()) => {
+                word.to_lowercase()
+            }
+            _ => {
+                self.next -= 1;
+                return Err(self.error(format!("expected an operator after '{}'", name)));
+            }
+        };
+        let compare = match operator.as_str() {
+            "==" => Some(Compare::Eq),
+            "!=" => Some(Compare::Ne),
+            "<" => Some(Compare::Lt),
+            "<=" => Some(Compare::Le),
+            ">" => Some(Compare::Gt),
+            ">=" => Some(Compare::Ge),
+            _ => None,
+        };
+        let unsupported = FilterError {
+            position: operator_position,
+            message: format!("'{}' doesn't support '{}'", name, operator),
+        };
+
+        let condition = match (field, compare) {
+            (Field::Number(field), Some(compare)) => {
+                let (position, value) = self.value()?;
+                Condition::Number(field, compare, parse_number(position, &value)?)
+            }
+            (Field::Number(field), None) if operator == "in" => {
+                let ranges = self
+                    .values()?
+                    .iter()
+                    .map(|(position, value)| parse_range(*position, value))
+                    .collect::<Result<_, _>>()?;
+                Condition::NumberIn(field, ranges)
+            }
+            (Field::Address(field), Some(compare @ (Compare::Eq | Compare::Ne))) => {
+                let (position, value) = self.value()?;
+                let condition = Condition::AddressIn(field, vec![parse_network(position, &value)?]);
+                return Ok(negate_if(compare == Compare::Ne, condition));
+            }
+            (Field::Address(field), None) if operator == "in" => {
+                let networks = self
+                    .values()?
+                    .iter()
+                    .map(|(position, value)| parse_network(*position, value))
+                    .collect::<Result<_, _>>()?;
+                Condition::AddressIn(field, networks)
+            }
+            (Field::Text(field), Some(compare @ (Compare::Eq | Compare::Ne))) => {
+                let (_, value) = self.value()?;
+                let condition = Condition::TextIn(field, vec![value]);
+                return Ok(negate_if(compare == Compare::Ne, condition));
+            }
+            (Field::Text(field), None) if operator == "in" => {
+                let values = self.values()?.into_iter().map(|(_, value)| value).collect();
+                Condition::TextIn(field, values)
+            }
+            (Field::Time(field), Some(compare)) => {
+                let (position, value) = self.value()?;
+                Condition::Time(field, compare, parse_time(position, &value)?)
+            }
+            (Field::Flags, None) if operator == "has" => {
+                let (_, value) = self.value()?;
+                Condition::HasFlag(value)
+            }
+            _ => return Err(unsupported),
+        };
+        Ok(Expr::Condition(condition))
+    }
+}
+
+fn negate_if(negate: bool, condition: Condition) -> Expr {
+    let expr = Expr::Condition(condition);
+    if negate {
+        Expr::Not(Box::new(expr))
+    } else {
+        expr
+    }
+}
+
+fn parse_number(position: usize, value: &str) -> Result<u64, FilterError> {
+    value.parse().map_err(|_| FilterError {
+        position,
+        message: format!("'{}' is not a number", value),
+    })
+}
+
+/// `1..1024` or a single number.
+fn parse_range(position: usize, value: &str) -> Result<(u64, u64), FilterError> {
+    match value.split_once("..") {
+        Some((start, end)) => {
+            let (start, end) = (parse_number(position, start)?, parse_number(position, end)?);
+            if start > end {
+                return Err(FilterError {
+                    position,
+                    message: format!(
+                        "'{}' is an empty range, did you mean {}..{}",
+                        value, end, start
+                    ),
+                });
+            }
+            Ok((start, end))
+        }
+        None => {
+            let value = parse_number(position, value)?;
+            Ok((value, value))
+        }
+    }
+}
+
+/// `10.0.0.0/8` or a single address.
+fn parse_network(position: usize, value: &str) -> Result<(Ipv4Addr, u8), FilterError> {
+    let invalid = || FilterError {
+        position,
+        message: format!("'{}' is not an IPv4 address or network", value),
+    };
+    let (address, prefix) = value.split_once('/').unwrap_or((value, "32"));
+    let address: Ipv4Addr = address.parse().map_err(|_| invalid())?;
+    let prefix: u8 = prefix
+        .parse()
+        .ok()
+        .filter(|prefix| *prefix <= 32)
+        .ok_or_else(invalid)?;
+    Ok((address, prefix))
+}
+
+/// RFC 3339, or a date and time without offset, or just a date, both taken as UTC.
+fn parse_time(position: usize, value: &str) -> Result<DateTime<Utc>, FilterError> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(time.and_utc());
+        }
+    }
+    if let Some(time) = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+    {
+        return Ok(time.and_utc());
+    }
+    Err(FilterError {
+        position,
+        message: format!("'{}' is not a time, use e.g. 2026-10-18T10:00:00Z", value),
+    })
+}
+
+fn in_network(ip: Ipv4Addr, (network, prefix): (Ipv4Addr, u8)) -> bool {
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    u32::from(ip) & mask == u32::from(network) & mask
+}
+
+impl Condition {
+    fn matches(&self, packet: &PacketInfo) -> bool {
+        let number = |field: &NumberField| match field {
+            NumberField::Port => Some(packet.target_port as u64),
+            NumberField::SourcePort => Some(packet.source_port as u64),
+            NumberField::Count => Some(packet.count()),
+            NumberField::Ttl => packet.metadata.as_ref().map(|m| m.ttl as u64),
+        };
+        let address = |field: &AddressField| match field {
+            AddressField::Source => packet.source_ip,
+            AddressField::Listener => packet.listener_ip,
+        };
+        let time = |field: &TimeField| match field {
+            TimeField::Time => packet.timestamp,
+            TimeField::LastSeen => packet.last_seen(),
+        };
+
+        match self {
+            // Records without the field, like ttl without --capture-metadata, never match
+            Condition::Number(field, compare, value) => {
+                number(field).is_some_and(|number| compare.holds(number, *value))
+            }
+            Condition::NumberIn(field, ranges) => number(field).is_some_and(|number| {
+                ranges
+                    .iter()
+                    .any(|(start, end)| (*start..=*end).contains(&number))
+            }),
+            Condition::AddressIn(field, networks) => networks
+                .iter()
+                .any(|network| in_network(address(field), *network)),
+            Condition::TextIn(field, values) => {
+                let text = match field {
+                    TextField::Protocol => &packet.protocol,
+                    TextField::Tag => &packet.network_tag,
+                };
+                values.iter().any(|value| value.eq_ignore_ascii_case(text))
+            }
+            Condition::Time(field, compare, value) => compare.holds(time(field), *value),
+            Condition::HasFlag(flag) => packet
+                .flags
+                .iter()
+                .any(|found| found.eq_ignore_ascii_case(flag)),
+        }
+    }
+}
+
+impl Expr {
+    fn matches(&self, packet: &PacketInfo) -> bool {
+        match self {
+            Expr::And(left, right) => left.matches(packet) && right.matches(packet),
+            Expr::Or(left, right) => left.matches(packet) || right.matches(packet),
+            Expr::Not(expr) => !expr.matches(packet),
+            Expr::Condition(condition) => condition.matches(packet),
+        }
+    }
+}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Self, FilterError> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            next: 0,
+            input,
+        };
+        if parser.tokens.is_empty() {
+            return Err(parser.error("the filter is empty"));
+        }
+
+        let expr = parser.or()?;
+        if parser.next < parser.tokens.len() {
+            return Err(parser.error("expected 'and', 'or' or the end of the filter"));
+        }
+        Ok(Self {
+            expr,
+            source: input.trim().to_string(),
+        })
+    }
+
+    pub fn matches(&self, packet: &PacketInfo) -> bool {
+        self.expr.matches(packet)
+    }
+}
+
+/// The filter as it was written.
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Filter::parse(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PacketMetadata;
+
+    fn packet() -> PacketInfo {
+        PacketInfo {
+            listener_ip: Ipv4Addr::new(10, 1, 0, 5),
+            network_tag: "pci".to_string(),
+            source_ip: Ipv4Addr::new(10, 0, 1, 20),
+            source_port: 40000,
+            target_port: 443,
+            protocol: "tcp".to_string(),
+            flags: vec!["SYN".to_string()],
+            timestamp: "2026-10-18T10:00:00Z".parse().unwrap(),
+            metadata: None,
+            last_seen: None,
+            count: None,
+        }
+    }
+
+    fn matches(filter: &str, packet: &PacketInfo) -> bool {
+        Filter::parse(filter)
+            .unwrap_or_else(|e| panic!("'{}': {}", filter, e))
+            .matches(packet)
+    }
+
+    fn error(filter: &str) -> FilterError {
+        Filter::parse(filter).expect_err(filter)
+    }
+
+    #[test]
+    fn precedence() {
+        let packet = packet();
+        // and binds tighter than or
+        assert!(matches(
+            "proto == udp and port == 1 or port == 443",
+            &packet
+        ));
+        assert!(matches(
+            "port == 443 or proto == udp and port == 1",
+            &packet
+        ));
+        assert!(!matches(
+            "(port == 443 or proto == udp) and port == 1",
+            &packet
+        ));
+        // not binds tighter than and
+        assert!(!matches("not port == 443 and proto == tcp", &packet));
+        assert!(matches("not (port == 1 and proto == tcp)", &packet));
+        assert!(matches("not not port == 443", &packet));
+        // Keywords are case insensitive
+        assert!(matches("PORT == 443 AND NOT proto == udp", &packet));
+    }
+
+    #[test]
+    fn number_fields() {
+        let mut packet = packet();
+        packet.count = Some(5);
+        for filter in [
+            "port == 443",
+            "target_port != 80",
+            "port < 444",
+            "port <= 443",
+            "port > 442",
+            "port >= 443",
+            "port in 1..1024",
+            "port in [22, 400..500]",
+            "sport == 40000",
+            "source_port in 32768..60999",
+            "count >= 5",
+        ] {
+            assert!(matches(filter, &packet), "{}", filter);
+        }
+        for filter in ["port == 80", "port < 443", "port in [22, 80]", "count < 5"] {
+            assert!(!matches(filter, &packet), "{}", filter);
+        }
+
+        // ttl only matches records with metadata
+        assert!(!matches("ttl == 64", &packet));
+        assert!(!matches("ttl != 64", &packet));
+        packet.metadata = Some(PacketMetadata {
+            source_mac: String::new(),
+            destination_mac: String::new(),
+            ttl: 64,
+            ip_id: 0,
+            dscp: 0,
+            length: 60,
+            tcp_window: None,
+            tcp_options: vec![],
+        });
+        assert!(matches("ttl == 64", &packet));
+        assert!(matches("ttl in 60..64", &packet));
+    }
+
+    #[test]
+    fn address_fields() {
+        let packet = packet();
+        for filter in [
+            "src == 10.0.1.20",
+            "source_ip in 10.0.0.0/8",
+            "src in [192.168.0.0/16, 10.0.1.0/24]",
+            "src != 10.0.1.21",
+            "listener == 10.1.0.5",
+            "listener_ip in 0.0.0.0/0",
+        ] {
+            assert!(matches(filter, &packet), "{}", filter);
+        }
+        for filter in [
+            "src != 10.0.0.0/16",
+            "src in 10.0.2.0/24",
+            "listener == 10.0.1.20",
+        ] {
+            assert!(!matches(filter, &packet), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn text_fields_and_flags() {
+        let packet = packet();
+        for filter in [
+            "proto == tcp",
+            "protocol == TCP",
+            "proto in [udp, tcp]",
+            "tag == \"pci\"",
+            "network_tag != corp",
+            "flags has SYN",
+            "flags has syn",
+        ] {
+            assert!(matches(filter, &packet), "{}", filter);
+        }
+        for filter in ["proto != tcp", "tag in [corp, dmz]", "flags has ACK"] {
+            assert!(!matches(filter, &packet), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn time_fields() {
+        let mut packet = packet();
+        packet.last_seen = Some("2026-10-18T12:00:00Z".parse().unwrap());
+        for filter in [
+            "time == 2026-10-18T10:00:00Z",
+            "time >= 2026-10-18T12:00:00+02:00",
+            "timestamp < 2026-10-18T10:00:01",
+            "time > 2026-10-18",
+            "last_seen > \"2026-10-18 11:00:00\"",
+            "last_seen != 2026-10-18T10:00:00Z",
+        ] {
+            assert!(matches(filter, &packet), "{}", filter);
+        }
+        assert!(!matches("last_seen <= 2026-10-18T11:59:59Z", &packet));
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("", 0, "the filter is empty"),
+            (
+                "prot == tcp",
+                0,
+                "unknown field 'prot', expected one of port, ",
+            ),
+            ("port 443", 5, "expected an operator after 'port'"),
+            ("port ==", 7, "expected a value"),
+            ("port == https", 8, "'https' is not a number"),
+            (
+                "port in 1024..1",
+                8,
+                "'1024..1' is an empty range, did you mean 1..1024",
+            ),
+            ("port in [1, 2", 13, "expected ']'"),
+            (
+                "src in 10.0.0.0/33",
+                7,
+                "'10.0.0.0/33' is not an IPv4 address or network",
+            ),
+            ("time > yesterday", 7, "'yesterday' is not a time"),
+            ("tag == \"pci", 7, "unterminated string"),
+            ("proto < tcp", 6, "'proto' doesn't support '<'"),
+            ("flags == SYN", 6, "'flags' doesn't support '=='"),
+            ("(port == 1", 10, "expected ')'"),
+            (
+                "port == 1 port == 2",
+                10,
+                "expected 'and', 'or' or the end of the filter",
+            ),
+            ("port == 1 & port == 2", 10, "unexpected '&'"),
+        ];
+        for (filter, position, message) in cases {
+            let error = error(filter);
+            assert_eq!(error.position, position, "{}: {}", filter, error);
+            assert!(error.message.starts_with(message), "{}: {}", filter, error);
+        }
+        assert_eq!(
+            error("port ==").to_string(),
+            "expected a value at position 8"
+        );
+    }
+
+    #[test]
+    fn displays_source() {
+        let filter: Filter = "  proto == tcp and port < 1024 ".parse().unwrap();
+        assert_eq!(filter.to_string(), "proto == tcp and port < 1024");
+    }
+}
@@ -0,0 +1,346 @@
+use crate::PacketInfo;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq, Hash)]
+pub struct NodeDatum {
+    pub id: String,
+    pub label: String,
+    pub shape: String,
+    pub color: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LinkDatum {
+    pub id: String,
+    pub label: String,
+    pub source: String,
+    pub target: String,
+    pub active: bool,
+    pub color: String,
+}
+
+/// Scanners and listeners as nodes, with a link for every (scanner, listener, port)
+/// seen in the logs. This is what the viewer draws.
+#[derive(Clone, Debug, Serialize)]
+pub struct GraphData {
+    pub nodes: Vec<NodeDatum>,
+    pub links: Vec<LinkDatum>,
+}
+
+const NODE_COLOR: &str = "#35D068";
+const LINK_COLOR: &str = "#35D068";
+
+/// Builds the graph from packets. Nodes and links keep the order they were first seen
+/// in, so exports of the same logs are identical.
+pub fn build_graph<'a>(packets: impl IntoIterator<Item = &'a PacketInfo>) -> GraphData {
+    let mut graph = GraphData {
+        nodes: vec![],
+        links: vec![],
+    };
+    let mut node_ids = HashSet::new();
+    let mut link_ids = HashSet::new();
+
+    for packet_info in packets {
+        // Source / Scanner Node
+        let scanner = NodeDatum {
+            id: format!("{}:{}", packet_info.source_ip, "scanner"),
+            label: format!(
+                "{}:{}:{}",
+                packet_info.network_tag, packet_info.source_ip, "scanner",
+            ),
+            shape: "hexagon".to_string(),
+            color: NODE_COLOR.to_string(),
+        };
+
+        // Destination / listener node
+        let listener = NodeDatum {
+            id: format!("{}:{}", packet_info.listener_ip, "listener"),
+            label: format!(
+                "{}:{}:{}",
+                packet_info.network_tag, packet_info.listener_ip, "listener",
+            ),
+            shape: "square".to_string(),
+            color: NODE_COLOR.to_string(),
+        };
+
+        let link = LinkDatum {
+            id: format!(
+                "{}:{}:{}",
+                packet_info.source_ip, packet_info.listener_ip, packet_info.target_port,
+            ),
+            label: format!("{} -> {}", packet_info.source_port, packet_info.target_port),
+            source: scanner.id.clone(),
+            target: listener.id.clone(),
+            active: true,
+            color: LINK_COLOR.to_string(),
+        };
+
+        for node in [scanner, listener] {
+            if node_ids.insert(node.id.clone()) {
+                graph.nodes.push(node);
+            }
+        }
+        // Repeated probes of the same port are one link
+        if link_ids.insert(link.id.clone()) {
+            graph.links.push(link);
+        }
+    }
+    graph
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Mermaid's entity codes, labels are rendered as HTML.
+fn escape_mermaid(text: &str) -> String {
+    text.replace('#', "#35;")
+        .replace('"', "#quot;")
+        .replace('&', "#amp;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+}
+
+/// `#35D068` as (r, g, b), black if it isn't a hex color.
+fn rgb(color: &str) -> (u8, u8, u8) {
+    let channel = |range| {
+        color
+            .strip_prefix('#')
+            .and_then(|hex| hex.get(range))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .unwrap_or(0)
+    };
+    (channel(0..2), channel(2..4), channel(4..6))
+}
+
+impl GraphData {
+    /// Graphviz DOT, e.g. for `dot -Tsvg`.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph seg {\n    rankdir=LR;\n");
+        for node in &self.nodes {
+            let shape = match node.shape.as_str() {
+                "square" => "box",
+                shape => shape,
+            };
+            let _ = writeln!(
+                dot,
+                "    \"{}\" [label=\"{}\", shape={}, color=\"{}\"];",
+                escape_dot(&node.id),
+                escape_dot(&node.label),
+                shape,
+                escape_dot(&node.color)
+            );
+        }
+        for link in &self.links {
+            let _ = writeln!(
+                dot,
+                "    \"{}\" -> \"{}\" [label=\"{}\", color=\"{}\"];",
+                escape_dot(&link.source),
+                escape_dot(&link.target),
+                escape_dot(&link.label),
+                escape_dot(&link.color)
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// GEXF 1.3, which Gephi opens directly. Node roles are kept as an attribute.
+    pub fn to_gexf(&self) -> String {
+        let mut gexf = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <gexf xmlns=\"http://gexf.net/1.3\" xmlns:viz=\"http://gexf.net/1.3/viz\" version=\"1.3\">\n\
+             \x20 <graph defaultedgetype=\"directed\">\n\
+             \x20   <attributes class=\"node\">\n\
+             \x20     <attribute id=\"shape\" title=\"shape\" type=\"string\"/>\n\
+             \x20   </attributes>\n\
+             \x20   <nodes>\n",
+        );
+        for node in &self.nodes {
+            let (r, g, b) = rgb(&node.color);
+            let _ = writeln!(
+                gexf,
+                "      <node id=\"{}\" label=\"{}\">\n        <attvalues><attvalue for=\"shape\" value=\"{}\"/></attvalues>\n        <viz:color r=\"{}\" g=\"{}\" b=\"{}\"/>\n      </node>",
+                escape_xml(&node.id),
+                escape_xml(&node.label),
+                escape_xml(&node.shape),
+                r,
+                g,
+                b
+            );
+        }
+        gexf.push_str("    </nodes>\n    <edges>\n");
+        for link in &self.links {
+            let _ = writeln!(
+                gexf,
+                "      <edge id=\"{}\" source=\"{}\" target=\"{}\" label=\"{}\"/>",
+                escape_xml(&link.id),
+                escape_xml(&link.source),
+                escape_xml(&link.target),
+                escape_xml(&link.label)
+            );
+        }
+        gexf.push_str("    </edges>\n  </graph>\n</gexf>\n");
+        gexf
+    }
+
+    /// GraphML with the labels, shapes and colors as data keys.
+    pub fn to_graphml(&self) -> String {
+        let mut graphml = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n\
+             \x20 <key id=\"label\" for=\"all\" attr.name=\"label\" attr.type=\"string\"/>\n\
+             \x20 <key id=\"shape\" for=\"node\" attr.name=\"shape\" attr.type=\"string\"/>\n\
+             \x20 <key id=\"color\" for=\"all\" attr.name=\"color\" attr.type=\"string\"/>\n\
+             \x20 <graph id=\"seg\" edgedefault=\"directed\">\n",
+        );
+        for node in &self.nodes {
+            let _ = writeln!(
+                graphml,
+                "    <node id=\"{}\">\n      <data key=\"label\">{}</data>\n      <data key=\"shape\">{}</data>\n      <data key=\"color\">{}</data>\n    </node>",
+                escape_xml(&node.id),
+                escape_xml(&node.label),
+                escape_xml(&node.shape),
+                escape_xml(&node.color)
+            );
+        }
+        for link in &self.links {
+            let _ = writeln!(
+                graphml,
+                "    <edge id=\"{}\" source=\"{}\" target=\"{}\">\n      <data key=\"label\">{}</data>\n      <data key=\"color\">{}</data>\n    </edge>",
+                escape_xml(&link.id),
+                escape_xml(&link.source),
+                escape_xml(&link.target),
+                escape_xml(&link.label),
+                escape_xml(&link.color)
+            );
+        }
+        graphml.push_str("  </graph>\n</graphml>\n");
+        graphml
+    }
+
+    /// A Mermaid flowchart, for Markdown reports. Node ids are replaced with `n0`,
+    /// `n1`, ... since Mermaid doesn't allow dots and colons in them.
+    pub fn to_mermaid(&self) -> String {
+        let ids: HashMap<&str, String> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| (node.id.as_str(), format!("n{}", idx)))
+            .collect();
+
+        let mut mermaid = String::from("flowchart LR\n");
+        for node in &self.nodes {
+            let (open, close) = match node.shape.as_str() {
+                "hexagon" => ("{{", "}}"),
+                _ => ("[", "]"),
+            };
+            let _ = writeln!(
+                mermaid,
+                "    {}{}\"{}\"{}",
+                ids[node.id.as_str()],
+                open,
+                escape_mermaid(&node.label),
+                close
+            );
+        }
+        for link in &self.links {
+            let (Some(source), Some(target)) =
+                (ids.get(link.source.as_str()), ids.get(link.target.as_str()))
+            else {
+                continue;
+            };
+            let _ = writeln!(
+                mermaid,
+                "    {} -->|\"{}\"| {}",
+                source,
+                escape_mermaid(&link.label),
+                target
+            );
+        }
+        mermaid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One scanner and one listener whose ids and labels need escaping everywhere.
+    fn graph() -> GraphData {
+        let node = |id: &str, label: &str, shape: &str| NodeDatum {
+            id: id.to_string(),
+            label: label.to_string(),
+            shape: shape.to_string(),
+            color: NODE_COLOR.to_string(),
+        };
+        GraphData {
+            nodes: vec![
+                node("scan \"1\"", "a \"b\" <c> & d", "hexagon"),
+                node("listen & <2>", "pci #1", "square"),
+            ],
+            links: vec![LinkDatum {
+                id: "l \"1\"".to_string(),
+                label: "40000 -> 22".to_string(),
+                source: "scan \"1\"".to_string(),
+                target: "listen & <2>".to_string(),
+                active: true,
+                color: LINK_COLOR.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn dot_escaping() {
+        let expected = r##"digraph seg {
+    rankdir=LR;
+    "scan \"1\"" [label="a \"b\" <c> & d", shape=hexagon, color="#35D068"];
+    "listen & <2>" [label="pci #1", shape=box, color="#35D068"];
+    "scan \"1\"" -> "listen & <2>" [label="40000 -> 22", color="#35D068"];
+}
+"##;
+        assert_eq!(graph().to_dot(), expected);
+    }
+
+    #[test]
+    fn gexf_escaping() {
+        let gexf = graph().to_gexf();
+        assert!(gexf.contains(
+            "<node id=\"scan &quot;1&quot;\" label=\"a &quot;b&quot; &lt;c&gt; &amp; d\">"
+        ));
+        assert!(gexf.contains("<viz:color r=\"53\" g=\"208\" b=\"104\"/>"));
+        assert!(gexf.contains(
+            "<edge id=\"l &quot;1&quot;\" source=\"scan &quot;1&quot;\" target=\"listen &amp; &lt;2&gt;\" label=\"40000 -&gt; 22\"/>"
+        ));
+    }
+
+    #[test]
+    fn graphml_escaping() {
+        let graphml = graph().to_graphml();
+        assert!(graphml.contains(
+            "<node id=\"scan &quot;1&quot;\">\n      <data key=\"label\">a &quot;b&quot; &lt;c&gt; &amp; d</data>"
+        ));
+        assert!(graphml.contains(
+            "<edge id=\"l &quot;1&quot;\" source=\"scan &quot;1&quot;\" target=\"listen &amp; &lt;2&gt;\">\n      <data key=\"label\">40000 -&gt; 22</data>"
+        ));
+    }
+
+    #[test]
+    fn mermaid_escaping() {
+        let expected = r##"flowchart LR
+    n0{{"a #quot;b#quot; #lt;c#gt; #amp; d"}}
+    n1["pci #35;1"]
+    n0 -->|"40000 -#gt; 22"| n1
+"##;
+        assert_eq!(graph().to_mermaid(), expected);
+    }
+}
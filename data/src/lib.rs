@@ -2,6 +2,14 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::net::Ipv4Addr;
 
+mod filter;
+mod graph;
+mod schema;
+
+pub use filter::{Filter, FilterError};
+pub use graph::*;
+pub use schema::*;
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct PacketInfo {
     pub listener_ip: Ipv4Addr,
@@ -12,4 +20,67 @@ pub struct PacketInfo {
     pub protocol: String,   // "tcp" or "udp"
     pub flags: Vec<String>, // Todo need a good way to display the flags
     pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<PacketMetadata>,
+    /// Set on aggregated records, `timestamp` is then the first time the packet was seen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Number of packets an aggregated record stands for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+}
+
+impl PacketInfo {
+    /// Number of packets this record represents, 1 unless it was aggregated.
+    pub fn count(&self) -> u64 {
+        self.count.unwrap_or(1)
+    }
+
+    /// Last time this packet was seen, which is `timestamp` unless it was aggregated.
+    pub fn last_seen(&self) -> DateTime<Utc> {
+        self.last_seen.unwrap_or(self.timestamp)
+    }
+}
+
+/// Packet and byte counts of the listener's firewall rules, written to the log
+/// periodically and when the listener exits. The firewall counts independently of the
+/// capture, so comparing the two shows whether the capture missed anything.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CounterSummary {
+    pub listener_ip: Ipv4Addr,
+    pub network_tag: String,
+    pub timestamp: DateTime<Utc>,
+    /// Set on the summary written at shutdown.
+    #[serde(rename = "final")]
+    pub is_final: bool,
+    /// Packets the capture had seen when the counters were read.
+    pub captured: u64,
+    pub rules: Vec<RuleCounter>,
+}
+
+/// Hits on one of the listener's firewall rules.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RuleCounter {
+    pub chain: String,
+    /// The rule's comment, or its handle when it has none.
+    pub rule: String,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Extra header fields captured when the listener runs with `--capture-metadata`.
+/// Useful for working out which router a probe traversed (TTL) and for
+/// fingerprinting the scanning stack (IP ID, window, option order).
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct PacketMetadata {
+    pub source_mac: String,
+    pub destination_mac: String,
+    pub ttl: u8,
+    pub ip_id: u16,
+    pub dscp: u8,
+    pub length: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_window: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tcp_options: Vec<String>,
 }
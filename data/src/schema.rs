@@ -0,0 +1,289 @@
+use crate::{CounterSummary, PacketInfo};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Lines};
+use std::path::Path;
+
+/// The schema version written by this build. Bump this and append a migration to
+/// `MIGRATIONS` whenever the shape of a log record changes.
+pub const LOG_SCHEMA_VERSION: u64 = 4;
+
+/// Migrations indexed by the version they upgrade *from*.
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+];
+
+/// Versioned envelope around a single line of a listener log.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LogRecord {
+    pub version: u64,
+    #[serde(flatten)]
+    pub entry: LogEntry,
+}
+
+/// What a log line holds, tagged with `kind`.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LogEntry {
+    Packet(PacketInfo),
+    Counters(CounterSummary),
+}
+
+impl From<PacketInfo> for LogEntry {
+    fn from(packet: PacketInfo) -> Self {
+        LogEntry::Packet(packet)
+    }
+}
+
+impl From<CounterSummary> for LogEntry {
+    fn from(summary: CounterSummary) -> Self {
+        LogEntry::Counters(summary)
+    }
+}
+
+impl LogRecord {
+    pub fn new(entry: impl Into<LogEntry>) -> Self {
+        Self {
+            version: LOG_SCHEMA_VERSION,
+            entry: entry.into(),
+        }
+    }
+
+    /// The captured packet, `None` for other kinds of records.
+    pub fn packet(&self) -> Option<&PacketInfo> {
+        match &self.entry {
+            LogEntry::Packet(packet) => Some(packet),
+            _ => None,
+        }
+    }
+
+    pub fn into_packet(self) -> Option<PacketInfo> {
+        match self.entry {
+            LogEntry::Packet(packet) => Some(packet),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LogError {
+    Io(io::Error),
+    Json {
+        line: usize,
+        source: serde_json::Error,
+    },
+    UnsupportedVersion {
+        line: usize,
+        version: u64,
+    },
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogError::Io(e) => write!(f, "failed to read log: {}", e),
+            LogError::Json { line, source } => {
+                write!(f, "failed to parse record on line {}: {}", line, source)
+            }
+            LogError::UnsupportedVersion { line, version } => write!(
+                f,
+                "record on line {} has schema version {} but this build only understands up to {}",
+                line, version, LOG_SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<io::Error> for LogError {
+    fn from(e: io::Error) -> Self {
+        LogError::Io(e)
+    }
+}
+
+/// Unversioned logs were bare `PacketInfo` objects, so there is nothing to change
+/// beyond stamping the version.
+fn migrate_v0_to_v1(_record: &mut Map<String, Value>) {}
+
+/// v2 added the optional `metadata` object, which older records simply don't have.
+fn migrate_v1_to_v2(_record: &mut Map<String, Value>) {}
+
+/// v3 added the optional `count` and `last_seen` fields of aggregated records.
+/// Every older record stands for exactly one packet, which is what their absence means.
+fn migrate_v2_to_v3(_record: &mut Map<String, Value>) {}
+
+/// v4 tagged every record with a `kind` so logs can hold counter summaries as well.
+/// Everything before that was a packet.
+fn migrate_v3_to_v4(record: &mut Map<String, Value>) {
+    record
+        .entry("kind")
+        .or_insert_with(|| Value::from("packet"));
+}
+
+/// Returns the schema version of a raw record. Records without a version field
+/// predate the envelope and are treated as version 0.
+pub fn record_version(record: &Value) -> u64 {
+    record.get("version").and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Upgrades a raw record in place to `LOG_SCHEMA_VERSION`, returning the version it
+/// started at. Fails with the record's version if it is newer than this build.
+pub fn upgrade_record(record: &mut Value) -> Result<u64, u64> {
+    let original = record_version(record);
+    if original > LOG_SCHEMA_VERSION {
+        return Err(original);
+    }
+
+    if let Value::Object(fields) = record {
+        for version in original..LOG_SCHEMA_VERSION {
+            MIGRATIONS[version as usize](fields);
+            fields.insert("version".to_string(), Value::from(version + 1));
+        }
+    }
+
+    Ok(original)
+}
+
+/// Parses a single log line of any known schema version into the current schema.
+/// `line` is only used for error reporting.
+pub fn parse_record(text: &str, line: usize) -> Result<LogRecord, LogError> {
+    let mut value: Value =
+        serde_json::from_str(text).map_err(|source| LogError::Json { line, source })?;
+    upgrade_record(&mut value).map_err(|version| LogError::UnsupportedVersion { line, version })?;
+    serde_json::from_value(value).map_err(|source| LogError::Json { line, source })
+}
+
+/// Iterates over the records of a listener log, upgrading older schema versions
+/// transparently and skipping blank lines.
+pub struct LogReader<R> {
+    lines: Lines<R>,
+    line: usize,
+}
+
+impl<R: BufRead> LogReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            line: 0,
+        }
+    }
+}
+
+impl LogReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> Iterator for LogReader<R> {
+    type Item = Result<LogRecord, LogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let text = match self.lines.next()? {
+                Ok(text) => text,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line += 1;
+
+            if text.trim().is_empty() {
+                continue;
+            }
+
+            return Some(parse_record(&text, self.line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET: &str = r#""listener_ip":"192.0.2.2","network_tag":"pci","source_ip":"10.0.1.5","source_port":40000,"target_port":22,"protocol":"tcp","flags":["SYN"],"timestamp":"2026-07-14T10:00:01Z""#;
+
+    fn upgrade(line: &str) -> (u64, LogRecord) {
+        let mut value: Value = serde_json::from_str(line).unwrap();
+        let original = upgrade_record(&mut value).unwrap();
+        assert_eq!(value["version"], LOG_SCHEMA_VERSION);
+        (original, serde_json::from_value(value).unwrap())
+    }
+
+    fn upgrade_packet(line: &str) -> (u64, PacketInfo) {
+        let (original, record) = upgrade(line);
+        (original, record.into_packet().unwrap())
+    }
+
+    #[test]
+    fn v0_bare_packet() {
+        let (original, packet) = upgrade_packet(&format!("{{{}}}", PACKET));
+        assert_eq!(original, 0);
+        assert_eq!(packet.network_tag, "pci");
+        assert_eq!(packet.target_port, 22);
+        assert_eq!(packet.count(), 1);
+        assert!(packet.metadata.is_none());
+    }
+
+    #[test]
+    fn v1_versioned_packet() {
+        let (original, packet) = upgrade_packet(&format!(r#"{{"version":1,{}}}"#, PACKET));
+        assert_eq!(original, 1);
+        assert_eq!(packet.source_port, 40000);
+        assert!(packet.last_seen.is_none());
+    }
+
+    #[test]
+    fn v2_packet_with_metadata() {
+        let line = format!(
+            r#"{{"version":2,{},"metadata":{{"source_mac":"aa:bb:cc:dd:ee:ff","destination_mac":"11:22:33:44:55:66","ttl":63,"ip_id":7,"dscp":0,"length":60}}}}"#,
+            PACKET
+        );
+        let (original, packet) = upgrade_packet(&line);
+        assert_eq!(original, 2);
+        let metadata = packet.metadata.unwrap();
+        assert_eq!(metadata.ttl, 63);
+        assert_eq!(metadata.tcp_window, None);
+    }
+
+    #[test]
+    fn v3_aggregated_packet() {
+        let line = format!(
+            r#"{{"version":3,{},"count":5,"last_seen":"2026-07-14T10:05:00Z"}}"#,
+            PACKET
+        );
+        let (original, packet) = upgrade_packet(&line);
+        assert_eq!(original, 3);
+        assert_eq!(packet.count(), 5);
+        assert_eq!(
+            packet.last_seen.unwrap().to_rfc3339(),
+            "2026-07-14T10:05:00+00:00"
+        );
+    }
+
+    #[test]
+    fn v4_counter_summary() {
+        let line = r#"{"version":4,"kind":"counters","listener_ip":"192.0.2.2","network_tag":"pci","timestamp":"2026-07-14T10:00:30Z","final":true,"captured":3,"rules":[{"chain":"input","rule":"tcp","packets":3,"bytes":180}]}"#;
+        let (original, record) = upgrade(line);
+        assert_eq!(original, 4);
+        let LogEntry::Counters(summary) = record.entry else {
+            panic!("expected a counter summary");
+        };
+        assert!(summary.is_final);
+        assert_eq!(summary.captured, 3);
+        assert_eq!(summary.rules[0].packets, 3);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let line = format!(r#"{{"version":{},{}}}"#, LOG_SCHEMA_VERSION + 1, PACKET);
+        assert!(matches!(
+            parse_record(&line, 7),
+            Err(LogError::UnsupportedVersion { line: 7, version }) if version == LOG_SCHEMA_VERSION + 1
+        ));
+    }
+}
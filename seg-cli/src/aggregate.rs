@@ -0,0 +1,88 @@
+use data::PacketInfo;
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct AggregateKey {
+    source_ip: Ipv4Addr,
+    listener_ip: Ipv4Addr,
+    target_port: u16,
+    protocol: String,
+}
+
+impl AggregateKey {
+    fn new(packet: &PacketInfo) -> Self {
+        Self {
+            source_ip: packet.source_ip,
+            listener_ip: packet.listener_ip,
+            target_port: packet.target_port,
+            protocol: packet.protocol.clone(),
+        }
+    }
+}
+
+/// Collapses repeated packets (SYN retransmits, repeated probes) into one record
+/// per (source, listener, port, protocol) for each window. The record keeps the
+/// first packet's timestamp, source port and metadata, and accumulates the count,
+/// last seen time and the union of flags.
+pub struct Aggregator {
+    window: chrono::Duration,
+    records: HashMap<AggregateKey, PacketInfo>,
+}
+
+impl Aggregator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window: chrono::Duration::from_std(window).unwrap_or(chrono::Duration::MAX),
+            records: HashMap::new(),
+        }
+    }
+
+    /// Folds a packet into its open record. If the key's previous window has already
+    /// closed, that record is returned so it can be written out.
+    pub fn observe(&mut self, packet: PacketInfo) -> Option<PacketInfo> {
+        let key = AggregateKey::new(&packet);
+
+        match self.records.get_mut(&key) {
+            Some(record) if packet.timestamp - record.timestamp < self.window => {
+                record.count = Some(record.count() + 1);
+                record.last_seen = Some(packet.timestamp);
+                for flag in packet.flags {
+                    if !record.flags.contains(&flag) {
+                        record.flags.push(flag);
+                    }
+                }
+                None
+            }
+            _ => self.records.insert(key, packet),
+        }
+    }
+
+    /// Removes and returns the records whose window has closed by `now`, oldest first.
+    pub fn drain_expired(&mut self, now: DateTime<Utc>) -> Vec<PacketInfo> {
+        let window = self.window;
+        let expired: Vec<AggregateKey> = self
+            .records
+            .iter()
+            .filter(|(_, record)| now - record.timestamp >= window)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut drained: Vec<PacketInfo> = expired
+            .iter()
+            .filter_map(|key| self.records.remove(key))
+            .collect();
+        drained.sort_by_key(|record| record.timestamp);
+        drained
+    }
+
+    /// Removes and returns every open record, oldest first. Used on shutdown.
+    pub fn drain_all(&mut self) -> Vec<PacketInfo> {
+        let mut drained: Vec<PacketInfo> = self.records.drain().map(|(_, record)| record).collect();
+        drained.sort_by_key(|record| record.timestamp);
+        drained
+    }
+}
@@ -0,0 +1,370 @@
+//! Classic BPF programs attached to the listener's capture socket, so the kernel
+//! drops uninteresting frames before they are copied into userspace.
+
+use crate::error::SegError;
+use crate::network::ScanProtocol;
+
+use libc::{
+    sock_filter, sock_fprog, BPF_ABS, BPF_B, BPF_H, BPF_IND, BPF_JA, BPF_JEQ, BPF_JMP, BPF_JSET,
+    BPF_K, BPF_LD, BPF_LDX, BPF_MSH, BPF_RET, BPF_W,
+};
+use std::io;
+use std::net::Ipv4Addr;
+use std::process::Command;
+
+// Offsets into an ethernet frame carrying IPv4
+const ETHERTYPE_OFFSET: u32 = 12;
+const IP_HEADER_OFFSET: u32 = 14;
+const IP_FRAGMENT_OFFSET: u32 = IP_HEADER_OFFSET + 6;
+const IP_PROTOCOL_OFFSET: u32 = IP_HEADER_OFFSET + 9;
+const IP_DESTINATION_OFFSET: u32 = IP_HEADER_OFFSET + 16;
+
+const ETHERTYPE_IPV4: u32 = 0x0800;
+const PROTOCOL_TCP: u32 = 6;
+const PROTOCOL_UDP: u32 = 17;
+
+/// Snap length returned for accepted packets, i.e. the whole packet.
+const ACCEPT: u32 = 0x40000;
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Builds the listener's filter: IPv4 frames addressed to `local_ip`, carrying the
+/// protocols we listen for, with neither port in `excluded_ports`.
+///
+/// Every check falls through to its own `ret #0` so no jump ever needs to span the
+/// program, which keeps long exclusion lists within BPF's 8 bit jump offsets.
+pub fn listener_filter(
+    local_ip: Ipv4Addr,
+    protocol: &ScanProtocol,
+    excluded_ports: &[u16],
+) -> Vec<sock_filter> {
+    let reject = stmt(BPF_RET | BPF_K, 0);
+    let mut program = vec![
+        // IPv4 only
+        stmt(BPF_LD | BPF_H | BPF_ABS, ETHERTYPE_OFFSET),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, ETHERTYPE_IPV4, 1, 0),
+        reject,
+        // Addressed to the listener
+        stmt(BPF_LD | BPF_W | BPF_ABS, IP_DESTINATION_OFFSET),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, u32::from(local_ip), 1, 0),
+        reject,
+        stmt(BPF_LD | BPF_B | BPF_ABS, IP_PROTOCOL_OFFSET),
+    ];
+
+    match protocol {
+        ScanProtocol::TCP => program.extend([jump(BPF_JMP | BPF_JEQ | BPF_K, PROTOCOL_TCP, 1, 0)]),
+        ScanProtocol::UDP => program.extend([jump(BPF_JMP | BPF_JEQ | BPF_K, PROTOCOL_UDP, 1, 0)]),
+        ScanProtocol::BOTH => program.extend([
+            jump(BPF_JMP | BPF_JEQ | BPF_K, PROTOCOL_TCP, 2, 0),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, PROTOCOL_UDP, 1, 0),
+        ]),
+    }
+    program.push(reject);
+
+    if !excluded_ports.is_empty() {
+        program.extend([
+            // Later fragments don't carry ports
+            stmt(BPF_LD | BPF_H | BPF_ABS, IP_FRAGMENT_OFFSET),
+            jump(BPF_JMP | BPF_JSET | BPF_K, 0x1fff, 0, 1),
+            reject,
+            // X = IP header length
+            stmt(BPF_LDX | BPF_B | BPF_MSH, IP_HEADER_OFFSET),
+        ]);
+
+        // TCP and UDP both start with the source then destination port
+        for port in excluded_ports {
+            program.extend([
+                stmt(BPF_LD | BPF_H | BPF_IND, IP_HEADER_OFFSET),
+                jump(BPF_JMP | BPF_JEQ | BPF_K, *port as u32, 2, 0),
+                stmt(BPF_LD | BPF_H | BPF_IND, IP_HEADER_OFFSET + 2),
+                jump(BPF_JMP | BPF_JEQ | BPF_K, *port as u32, 0, 1),
+                reject,
+            ]);
+        }
+    }
+
+    program.push(stmt(BPF_RET | BPF_K, ACCEPT));
+    program
+}
+
+/// Chains two programs so a packet must be accepted by both. Every accepting return
+/// in `first` becomes a jump to the start of `second`.
+pub fn combine(first: Vec<sock_filter>, second: Vec<sock_filter>) -> Vec<sock_filter> {
+    let len = first.len();
+    let mut program: Vec<sock_filter> = first
+        .into_iter()
+        .enumerate()
+        .map(|(idx, insn)| {
+            if insn.code as u32 == BPF_RET | BPF_K && insn.k != 0 {
+                stmt(BPF_JMP | BPF_JA, (len - idx - 1) as u32)
+            } else {
+                insn
+            }
+        })
+        .collect();
+    program.extend(second);
+    program
+}
+
+/// Parses decimal bytecode as printed by `tcpdump -ddd`: an instruction count
+/// followed by one `code jt jf k` line per instruction.
+pub fn parse_bytecode(text: &str) -> Result<Vec<sock_filter>, String> {
+    let mut lines = text
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+
+    let count: usize = lines
+        .next()
+        .ok_or("empty BPF program")?
+        .parse()
+        .map_err(|_| "BPF program must start with an instruction count".to_string())?;
+
+    let program = lines
+        .map(|line| {
+            let fields: Vec<u32> = line
+                .split_whitespace()
+                .map(|field| field.parse::<u32>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("invalid BPF instruction '{}'", line))?;
+            match fields[..] {
+                [code, jt, jf, k] => Ok(sock_filter {
+                    code: u16::try_from(code)
+                        .map_err(|_| format!("invalid BPF opcode in '{}'", line))?,
+                    jt: u8::try_from(jt)
+                        .map_err(|_| format!("BPF jump offset out of range in '{}'", line))?,
+                    jf: u8::try_from(jf)
+                        .map_err(|_| format!("BPF jump offset out of range in '{}'", line))?,
+                    k,
+                }),
+                _ => Err(format!("invalid BPF instruction '{}'", line)),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if program.len() != count {
+        return Err(format!(
+            "BPF program declares {} instructions but has {}",
+            count,
+            program.len()
+        ));
+    }
+    Ok(program)
+}
+
+/// Turns a user supplied filter into a program. Precompiled `tcpdump -ddd` output is
+/// used as is, anything else is treated as a pcap expression and compiled by tcpdump.
+pub fn compile_filter(
+    interface_name: &str,
+    filter: &str,
+) -> crate::error::Result<Vec<sock_filter>> {
+    if filter
+        .trim_start()
+        .starts_with(|c: char| c.is_ascii_digit())
+    {
+        if let Ok(program) = parse_bytecode(filter) {
+            return Ok(program);
+        }
+    }
+
+    let output = Command::new("tcpdump")
+        .args(["-i", interface_name, "-ddd", filter])
+        .output()
+        .map_err(|e| {
+            SegError::command("tcpdump", "Failed to run tcpdump to compile the filter", e)
+        })?;
+    if !output.status.success() {
+        return Err(SegError::Input(format!(
+            "tcpdump could not compile filter '{}': {}",
+            filter,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    parse_bytecode(&String::from_utf8_lossy(&output.stdout))
+        .map_err(|e| SegError::Input(format!("Invalid BPF filter: {}", e)))
+}
+
+/// Opens a raw `AF_PACKET` socket with `program` attached. The descriptor is handed
+/// to pnet, which takes ownership of it.
+pub fn open_filtered_socket(program: &[sock_filter]) -> io::Result<i32> {
+    if program.len() > libc::BPF_MAXINSNS as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("BPF program is too long ({} instructions)", program.len()),
+        ));
+    }
+
+    let protocol = (libc::ETH_P_ALL as u16).to_be() as i32;
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let fprog = sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_ptr() as *mut sock_filter,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &fprog as *const sock_fprog as *const libc::c_void,
+            std::mem::size_of::<sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        let error = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(error);
+    }
+
+    Ok(fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Instructions as `(code, jt, jf, k)`, the way `tcpdump -dd` prints them.
+    fn insns(program: &[sock_filter]) -> Vec<(u16, u8, u8, u32)> {
+        program
+            .iter()
+            .map(|insn| (insn.code, insn.jt, insn.jf, insn.k))
+            .collect()
+    }
+
+    const LISTENER_IP: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+
+    /// IPv4 to 192.0.2.2, then loads the IP protocol.
+    const PREAMBLE: [(u16, u8, u8, u32); 7] = [
+        (0x28, 0, 0, 12),
+        (0x15, 1, 0, 0x0800),
+        (0x06, 0, 0, 0),
+        (0x20, 0, 0, 30),
+        (0x15, 1, 0, 0xc000_0202),
+        (0x06, 0, 0, 0),
+        (0x30, 0, 0, 23),
+    ];
+
+    fn expected(rest: &[(u16, u8, u8, u32)]) -> Vec<(u16, u8, u8, u32)> {
+        PREAMBLE.iter().chain(rest).copied().collect()
+    }
+
+    #[test]
+    fn single_protocol() {
+        let tcp = listener_filter(LISTENER_IP, &ScanProtocol::TCP, &[]);
+        assert_eq!(
+            insns(&tcp),
+            expected(&[(0x15, 1, 0, 6), (0x06, 0, 0, 0), (0x06, 0, 0, ACCEPT)])
+        );
+
+        let udp = listener_filter(LISTENER_IP, &ScanProtocol::UDP, &[]);
+        assert_eq!(
+            insns(&udp),
+            expected(&[(0x15, 1, 0, 17), (0x06, 0, 0, 0), (0x06, 0, 0, ACCEPT)])
+        );
+    }
+
+    #[test]
+    fn both_protocols_with_excluded_ports() {
+        let program = listener_filter(LISTENER_IP, &ScanProtocol::BOTH, &[22, 53]);
+        assert_eq!(
+            insns(&program),
+            expected(&[
+                (0x15, 2, 0, 6),
+                (0x15, 1, 0, 17),
+                (0x06, 0, 0, 0),
+                // Fragment offset, then X = IP header length
+                (0x28, 0, 0, 20),
+                (0x45, 0, 1, 0x1fff),
+                (0x06, 0, 0, 0),
+                (0xb1, 0, 0, 14),
+                // Source or destination port 22
+                (0x48, 0, 0, 14),
+                (0x15, 2, 0, 22),
+                (0x48, 0, 0, 16),
+                (0x15, 0, 1, 22),
+                (0x06, 0, 0, 0),
+                // Source or destination port 53
+                (0x48, 0, 0, 14),
+                (0x15, 2, 0, 53),
+                (0x48, 0, 0, 16),
+                (0x15, 0, 1, 53),
+                (0x06, 0, 0, 0),
+                (0x06, 0, 0, ACCEPT),
+            ])
+        );
+    }
+
+    #[test]
+    fn combine_retargets_every_accept() {
+        let first = vec![
+            jump(BPF_JMP | BPF_JEQ | BPF_K, 6, 0, 2),
+            stmt(BPF_RET | BPF_K, ACCEPT),
+            stmt(BPF_RET | BPF_K, 0),
+            stmt(BPF_RET | BPF_K, 96),
+        ];
+        let second = vec![stmt(BPF_RET | BPF_K, 0xffff)];
+
+        let program = combine(first, second);
+        assert_eq!(
+            insns(&program),
+            vec![
+                (0x15, 0, 2, 6),
+                // Each accept jumps to the first instruction of the second program
+                (0x05, 0, 0, 2),
+                (0x06, 0, 0, 0),
+                (0x05, 0, 0, 0),
+                (0x06, 0, 0, 0xffff),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_tcpdump_output() {
+        let expected = vec![(0x28, 0, 0, 12), (0x06, 0, 0, 0)];
+        let program = parse_bytecode("2\n40 0 0 12\n6 0 0 0\n").unwrap();
+        assert_eq!(insns(&program), expected);
+
+        // The comma separated form accepted by `iptables -m bpf`
+        let program = parse_bytecode("2,40 0 0 12,6 0 0 0").unwrap();
+        assert_eq!(insns(&program), expected);
+    }
+
+    #[test]
+    fn rejects_malformed_bytecode() {
+        for text in [
+            "",
+            "\n\n",
+            "tcp port 22",
+            "2\n40 0 0 12\n",
+            "1\n40 0 0 12\n6 0 0 0\n",
+            "1\n40 0 12\n",
+            "1\n40 0 0 12 7\n",
+            "1\n40 0 0 -12\n",
+            "1\nldh [12]\n",
+            "1\n6 256 0 0\n",
+            "1\n65536 0 0 0\n",
+        ] {
+            assert!(parse_bytecode(text).is_err(), "accepted {:?}", text);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use crate::bpf::open_filtered_socket;
+use crate::consts::{CAPTURE_RETRY_DELAY, MAX_CAPTURE_ERRORS};
+use crate::error::{Context, Result, SegError};
+use crate::network::ScanProtocol;
+use crate::util::*;
+
+use data::*;
+
+use libc::sock_filter;
+use pnet::datalink::{self, DataLinkReceiver, NetworkInterface};
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+
+/// Everything the capture loop needs to decide whether and how to record a packet.
+pub struct CaptureOptions {
+    pub local_ip: Ipv4Addr,
+    pub network_tag: String,
+    pub access_port: u16,
+    pub excluded_ports: Vec<u16>,
+    pub protocol: ScanProtocol,
+    pub capture_metadata: bool,
+    pub quiet: bool,
+}
+
+/// Counters shared between the capture thread and the log writer.
+#[derive(Default)]
+pub struct CaptureStats {
+    /// Packets that matched and were handed to the writer queue.
+    pub captured: AtomicU64,
+    /// Packets lost because the writer queue was full.
+    pub dropped: AtomicU64,
+    /// Records written to the log (aggregated records count once).
+    pub written: AtomicU64,
+    /// Highest number of packets waiting in the writer queue.
+    pub queue_peak: AtomicUsize,
+}
+
+impl CaptureStats {
+    pub fn report(&self) -> String {
+        format!(
+            "captured {}, written {}, dropped {}, queue peak {}",
+            self.captured.load(Ordering::Relaxed),
+            self.written.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.queue_peak.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Finds the named interface and the IPv4 address the listener should watch for.
+pub fn find_interface(interface_name: &str) -> Result<(NetworkInterface, Ipv4Addr)> {
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(|iface: &NetworkInterface| iface.name == interface_name)
+        .ok_or_else(|| {
+            SegError::Interface(format!("interface {} does not exist", interface_name))
+        })?;
+
+    if !interface.is_up() {
+        return Err(SegError::Interface(format!(
+            "interface {} is down",
+            interface_name
+        )));
+    }
+    if interface.is_loopback() {
+        return Err(SegError::Interface(format!(
+            "interface {} is a loopback interface",
+            interface_name
+        )));
+    }
+
+    let local_ip = interface
+        .ips
+        .iter()
+        .find_map(|ip| match ip.ip() {
+            std::net::IpAddr::V4(ipv4) => Some(ipv4),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            SegError::Interface(format!("interface {} has no IPv4 address", interface_name))
+        })?;
+
+    Ok((interface, local_ip))
+}
+
+/// Opens a capture channel on the interface with `program` filtering frames in the kernel.
+pub fn open_capture(
+    interface: &NetworkInterface,
+    program: &[sock_filter],
+) -> Result<Box<dyn DataLinkReceiver>> {
+    use pnet::datalink::Channel::Ethernet;
+
+    let socket_fd =
+        open_filtered_socket(program).context("Failed to open filtered capture socket")?;
+
+    // Time out reads regularly so the capture thread notices when it should stop
+    let config = datalink::Config {
+        read_timeout: Some(Duration::from_millis(500)),
+        socket_fd: Some(socket_fd),
+        ..Default::default()
+    };
+    match datalink::channel(interface, config) {
+        Ok(Ethernet(_, rx)) => Ok(rx),
+        Ok(_) => Err(SegError::Interface(format!(
+            "interface {} does not provide an ethernet channel",
+            interface.name
+        ))),
+        Err(e) => Err(SegError::io(
+            format!("Failed to open capture channel on {}", interface.name),
+            e,
+        )),
+    }
+}
+
+/// Capture loop, meant to run on its own thread. Matching packets are handed to the
+/// log writer through a bounded queue; if the writer can't keep up packets are
+/// counted as dropped rather than stalling the capture. Gives up with the last error
+/// once reads have failed `MAX_CAPTURE_ERRORS` times in a row.
+pub fn capture_packets(
+    mut rx: Box<dyn DataLinkReceiver>,
+    options: CaptureOptions,
+    queue: Sender<PacketInfo>,
+    stats: Arc<CaptureStats>,
+    running: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let capacity = queue.max_capacity();
+    let mut errors = 0;
+
+    while running.load(Ordering::Relaxed) {
+        let frame = match rx.next() {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => {
+                errors += 1;
+                if errors >= MAX_CAPTURE_ERRORS {
+                    return Err(e);
+                }
+                eprintln!("Failed to read from capture channel: {}", e);
+                // Back off so a broken socket doesn't spin
+                std::thread::sleep(CAPTURE_RETRY_DELAY);
+                continue;
+            }
+        };
+        errors = 0;
+
+        let Some(packet_info) = packet_from_frame(frame, &options) else {
+            continue;
+        };
+
+        if !options.quiet {
+            println!(
+                "{}: {}:{} -> {}:{}",
+                packet_info.protocol.to_uppercase(),
+                packet_info.source_ip,
+                packet_info.source_port,
+                packet_info.listener_ip,
+                packet_info.target_port,
+            );
+        }
+
+        match queue.try_send(packet_info) {
+            Ok(()) => {
+                stats.captured.fetch_add(1, Ordering::Relaxed);
+                stats
+                    .queue_peak
+                    .fetch_max(capacity - queue.capacity(), Ordering::Relaxed);
+            }
+            Err(TrySendError::Full(_)) => {
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Closed(_)) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a captured ethernet frame into a log record if it is a TCP or UDP packet
+/// addressed to the listener that isn't traffic on the access port.
+pub fn packet_from_frame(frame: &[u8], options: &CaptureOptions) -> Option<PacketInfo> {
+    let ethernet = EthernetPacket::new(frame)?;
+    // Handle IPv4 packets
+    let ip_packet = Ipv4Packet::new(ethernet.payload())?;
+    if ip_packet.get_destination() != options.local_ip {
+        return None;
+    }
+
+    let (source_port, target_port, protocol, flags, tcp_packet) =
+        match ip_packet.get_next_level_protocol() {
+            IpNextHeaderProtocols::Udp if !matches!(options.protocol, ScanProtocol::TCP) => {
+                let udp_packet = UdpPacket::new(ip_packet.payload())?;
+                (
+                    udp_packet.get_source(),
+                    udp_packet.get_destination(),
+                    "udp",
+                    vec![],
+                    None,
+                )
+            }
+            IpNextHeaderProtocols::Tcp if !matches!(options.protocol, ScanProtocol::UDP) => {
+                let tcp_packet = TcpPacket::new(ip_packet.payload())?;
+                (
+                    tcp_packet.get_source(),
+                    tcp_packet.get_destination(),
+                    "tcp",
+                    tcp_flags_to_iter(tcp_packet.get_flags())
+                        .map(String::from)
+                        .collect(),
+                    Some(tcp_packet),
+                )
+            }
+            _ => return None,
+        };
+
+    // The kernel filter already drops these, but a user supplied filter could be anything
+    let excluded =
+        |port: u16| port == options.access_port || options.excluded_ports.contains(&port);
+    if excluded(source_port) || excluded(target_port) {
+        return None;
+    }
+
+    let metadata = options
+        .capture_metadata
+        .then(|| packet_metadata(&ethernet, &ip_packet, tcp_packet.as_ref()));
+
+    Some(PacketInfo {
+        listener_ip: ip_packet.get_destination(),
+        network_tag: options.network_tag.clone(),
+        source_ip: ip_packet.get_source(),
+        source_port,
+        target_port,
+        protocol: protocol.to_string(),
+        flags,
+        timestamp: chrono::Utc::now(),
+        metadata,
+        last_seen: None,
+        count: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A capture channel whose reads fail until `failures` runs out, then time out.
+    struct FailingReceiver {
+        failures: u32,
+        running: Arc<AtomicBool>,
+    }
+
+    impl DataLinkReceiver for FailingReceiver {
+        fn next(&mut self) -> io::Result<&[u8]> {
+            if self.failures == 0 {
+                self.running.store(false, Ordering::Relaxed);
+                return Err(ErrorKind::TimedOut.into());
+            }
+            self.failures -= 1;
+            Err(io::Error::from_raw_os_error(libc::ENETDOWN))
+        }
+    }
+
+    fn capture(failures: u32) -> io::Result<()> {
+        let running = Arc::new(AtomicBool::new(true));
+        let rx = FailingReceiver {
+            failures,
+            running: running.clone(),
+        };
+        let options = CaptureOptions {
+            local_ip: Ipv4Addr::new(192, 0, 2, 2),
+            network_tag: "pci".to_string(),
+            access_port: 22,
+            excluded_ports: vec![],
+            protocol: ScanProtocol::BOTH,
+            capture_metadata: false,
+            quiet: true,
+        };
+        let (queue, _rx) = tokio::sync::mpsc::channel(1);
+        capture_packets(
+            Box::new(rx),
+            options,
+            queue,
+            Arc::new(CaptureStats::default()),
+            running,
+        )
+    }
+
+    #[test]
+    fn gives_up_after_repeated_read_errors() {
+        let error = capture(u32::MAX).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::ENETDOWN));
+
+        // Fewer failures than the limit are retried
+        assert!(capture(MAX_CAPTURE_ERRORS - 1).is_ok());
+    }
+}
@@ -1,2 +1,19 @@
-pub const NFT_RULES_TEMPLATE: &str = include_str!("rules.nft");
+pub const IPTABLES_RULES_TEMPLATE: &str = include_str!("rules.iptables");
+pub const IPTABLES_RESPONDER_TEMPLATE: &str = include_str!("responder.iptables");
+pub const FIREWALLD_RULES_TEMPLATE: &str = include_str!("rules.firewalld");
+#[allow(dead_code)]
 pub const MAX_SOCKETS: u16 = 1024; // A sensible limit based on typical default OS constraints
+pub const LOG_QUEUE_CAPACITY: usize = 65536; // Enough to absorb a full port sweep burst
+pub const LOG_BATCH_SIZE: usize = 1024;
+pub const MAX_CAPTURE_ERRORS: u32 = 10; // consecutive read failures before the capture gives up
+pub const CAPTURE_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+pub const STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+pub const SEG_TABLE: &str = "seg"; // nft table holding all of seg's rules
+pub const SEG_INPUT_CHAIN: &str = "SEG_INPUT"; // iptables chains holding seg's rules
+pub const SEG_PREROUTING_CHAIN: &str = "SEG_PREROUTING";
+pub const RULESET_BACKUP_PATH: &str = "/var/lib/seg/ruleset.nft";
+pub const IPTABLES_BACKUP_PATH: &str = "/var/lib/seg/ruleset.iptables";
+pub const FIREWALLD_MARKER_PATH: &str = "/var/lib/seg/firewalld.active";
+pub const CAP_NET_ADMIN: u32 = 12; // capability numbers from linux/capability.h
+pub const CAP_NET_RAW: u32 = 13;
+pub const MIN_NFT_VERSION: (u32, u32, u32) = (0, 9, 3); // first release with a stable JSON API
@@ -0,0 +1,723 @@
+use crate::error::{Context, Result, SegError};
+use crate::parse::SourceNetwork;
+
+use arrow_array::{
+    ArrayRef, RecordBatch, StringArray, TimestampMicrosecondArray, UInt16Array, UInt64Array,
+    UInt8Array,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use data::{PacketInfo, PacketMetadata};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Rows per Parquet row group, and per batch held in memory while writing.
+const PARQUET_BATCH_ROWS: usize = 65536;
+
+pub struct Network {
+    pub id: u64,
+    /// `source` for scanner networks, `destination` for listener network tags.
+    pub kind: &'static str,
+    pub name: String,
+}
+
+pub struct Host {
+    pub id: u64,
+    pub ip: Ipv4Addr,
+    /// `scanner` or `listener`.
+    pub role: &'static str,
+    pub network_id: u64,
+}
+
+/// Every packet between one scanner and one listener port.
+pub struct Session {
+    pub id: u64,
+    pub scanner_id: u64,
+    pub listener_id: u64,
+    pub protocol: String,
+    pub port: u16,
+    pub packets: u64,
+    pub records: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+pub struct Record<'a> {
+    pub id: u64,
+    pub session_id: u64,
+    pub packet: &'a PacketInfo,
+}
+
+/// Packets split into networks, hosts, sessions and the records themselves, the
+/// layout both the SQLite and the Parquet export use.
+pub struct Normalized<'a> {
+    pub networks: Vec<Network>,
+    pub hosts: Vec<Host>,
+    pub sessions: Vec<Session>,
+    pub records: Vec<Record<'a>>,
+}
+
+/// Normalizes packets, ordered by time so range queries on the records stay cheap.
+/// Ids start at 1 and follow first appearance.
+pub fn normalize(packets: &[PacketInfo], source_prefix: u8) -> Normalized<'_> {
+    let mut sorted: Vec<&PacketInfo> = packets.iter().collect();
+    sorted.sort_by_key(|packet| packet.timestamp);
+
+    let mut normalized = Normalized {
+        networks: vec![],
+        hosts: vec![],
+        sessions: vec![],
+        records: vec![],
+    };
+    let mut network_ids: HashMap<(&'static str, String), u64> = HashMap::new();
+    let mut host_ids: HashMap<(Ipv4Addr, &'static str, u64), u64> = HashMap::new();
+    let mut session_ids: HashMap<(u64, u64, String, u16), u64> = HashMap::new();
+
+    for packet in sorted {
+        let mut network = |kind: &'static str, name: String| {
+            *network_ids.entry((kind, name.clone())).or_insert_with(|| {
+                let id = normalized.networks.len() as u64 + 1;
+                normalized.networks.push(Network { id, kind, name });
+                id
+            })
+        };
+        let source_network = network(
+            "source",
+            SourceNetwork::of(packet.source_ip, source_prefix).to_string(),
+        );
+        let destination_network = network("destination", packet.network_tag.clone());
+
+        let mut host = |ip: Ipv4Addr, role: &'static str, network_id: u64| {
+            *host_ids.entry((ip, role, network_id)).or_insert_with(|| {
+                let id = normalized.hosts.len() as u64 + 1;
+                normalized.hosts.push(Host {
+                    id,
+                    ip,
+                    role,
+                    network_id,
+                });
+                id
+            })
+        };
+        let scanner_id = host(packet.source_ip, "scanner", source_network);
+        let listener_id = host(packet.listener_ip, "listener", destination_network);
+
+        let key = (
+            scanner_id,
+            listener_id,
+            packet.protocol.clone(),
+            packet.target_port,
+        );
+        let session_id = match session_ids.get(&key) {
+            Some(id) => {
+                let session = &mut normalized.sessions[*id as usize - 1];
+                session.packets += packet.count();
+                session.records += 1;
+                session.first_seen = session.first_seen.min(packet.timestamp);
+                session.last_seen = session.last_seen.max(packet.last_seen());
+                *id
+            }
+            None => {
+                let id = normalized.sessions.len() as u64 + 1;
+                normalized.sessions.push(Session {
+                    id,
+                    scanner_id,
+                    listener_id,
+                    protocol: packet.protocol.clone(),
+                    port: packet.target_port,
+                    packets: packet.count(),
+                    records: 1,
+                    first_seen: packet.timestamp,
+                    last_seen: packet.last_seen(),
+                });
+                session_ids.insert(key, id);
+                id
+            }
+        };
+
+        normalized.records.push(Record {
+            id: normalized.records.len() as u64 + 1,
+            session_id,
+            packet,
+        });
+    }
+    normalized
+}
+
+/// Fixed width so timestamps stored as text sort chronologically.
+fn timestamp_text(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+const SQLITE_SCHEMA: &str = "
+CREATE TABLE networks (
+    id INTEGER PRIMARY KEY,
+    kind TEXT NOT NULL,
+    name TEXT NOT NULL,
+    UNIQUE (kind, name)
+);
+CREATE TABLE hosts (
+    id INTEGER PRIMARY KEY,
+    ip TEXT NOT NULL,
+    role TEXT NOT NULL,
+    network_id INTEGER NOT NULL REFERENCES networks (id)
+);
+CREATE TABLE sessions (
+    id INTEGER PRIMARY KEY,
+    scanner_id INTEGER NOT NULL REFERENCES hosts (id),
+    listener_id INTEGER NOT NULL REFERENCES hosts (id),
+    protocol TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    packets INTEGER NOT NULL,
+    records INTEGER NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL
+);
+CREATE TABLE records (
+    id INTEGER PRIMARY KEY,
+    session_id INTEGER NOT NULL REFERENCES sessions (id),
+    timestamp TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    source_port INTEGER NOT NULL,
+    flags TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    source_mac TEXT,
+    destination_mac TEXT,
+    ttl INTEGER,
+    ip_id INTEGER,
+    dscp INTEGER,
+    length INTEGER,
+    tcp_window INTEGER,
+    tcp_options TEXT
+);
+CREATE VIEW packets AS
+SELECT r.id, r.timestamp, r.last_seen, listener_net.name AS network_tag,
+       scanner_net.name AS source_network, scanner.ip AS source_ip, r.source_port,
+       listener.ip AS listener_ip, s.port AS target_port, s.protocol, r.flags, r.count
+FROM records r
+JOIN sessions s ON r.session_id = s.id
+JOIN hosts scanner ON s.scanner_id = scanner.id
+JOIN networks scanner_net ON scanner.network_id = scanner_net.id
+JOIN hosts listener ON s.listener_id = listener.id
+JOIN networks listener_net ON listener.network_id = listener_net.id;
+";
+
+/// Indexes are created after the bulk insert, which is a lot faster than keeping them
+/// up to date row by row.
+const SQLITE_INDEXES: &str = "
+CREATE INDEX records_session ON records (session_id);
+CREATE INDEX records_timestamp ON records (timestamp);
+CREATE INDEX sessions_port ON sessions (protocol, port);
+CREATE INDEX hosts_ip ON hosts (ip);
+";
+
+fn sqlite_error(context: impl Into<String>) -> impl FnOnce(rusqlite::Error) -> SegError {
+    let context = context.into();
+    move |e| SegError::Io {
+        context,
+        source: io::Error::other(e),
+    }
+}
+
+/// Writes a SQLite database, replacing `path` if it exists.
+pub fn write_sqlite(path: &str, normalized: &Normalized) -> Result<()> {
+    if Path::new(path).exists() {
+        std::fs::remove_file(path).context(format!("Unable to replace {}", path))?;
+    }
+    let mut connection =
+        Connection::open(path).map_err(sqlite_error(format!("Unable to create {}", path)))?;
+    insert_sqlite(&mut connection, normalized)
+        .map_err(sqlite_error(format!("Failed to write {}", path)))
+}
+
+fn insert_sqlite(connection: &mut Connection, normalized: &Normalized) -> rusqlite::Result<()> {
+    // The file is written from scratch, a crash half way leaves nothing worth keeping
+    connection.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")?;
+    connection.execute_batch(SQLITE_SCHEMA)?;
+
+    let transaction = connection.transaction()?;
+    {
+        let mut insert = transaction.prepare("INSERT INTO networks VALUES (?1, ?2, ?3)")?;
+        for network in &normalized.networks {
+            insert.execute(params![network.id, network.kind, network.name])?;
+        }
+
+        let mut insert = transaction.prepare("INSERT INTO hosts VALUES (?1, ?2, ?3, ?4)")?;
+        for host in &normalized.hosts {
+            insert.execute(params![
+                host.id,
+                host.ip.to_string(),
+                host.role,
+                host.network_id
+            ])?;
+        }
+
+        let mut insert = transaction
+            .prepare("INSERT INTO sessions VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)")?;
+        for session in &normalized.sessions {
+            insert.execute(params![
+                session.id,
+                session.scanner_id,
+                session.listener_id,
+                session.protocol,
+                session.port,
+                session.packets,
+                session.records,
+                timestamp_text(session.first_seen),
+                timestamp_text(session.last_seen),
+            ])?;
+        }
+
+        let mut insert = transaction.prepare(
+            "INSERT INTO records VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+        )?;
+        for record in &normalized.records {
+            let packet = record.packet;
+            let metadata = packet.metadata.as_ref();
+            insert.execute(params![
+                record.id,
+                record.session_id,
+                timestamp_text(packet.timestamp),
+                timestamp_text(packet.last_seen()),
+                packet.source_port,
+                packet.flags.join(" "),
+                packet.count(),
+                metadata.map(|m| m.source_mac.clone()),
+                metadata.map(|m| m.destination_mac.clone()),
+                metadata.map(|m| m.ttl),
+                metadata.map(|m| m.ip_id),
+                metadata.map(|m| m.dscp),
+                metadata.map(|m| m.length),
+                metadata.and_then(|m| m.tcp_window),
+                metadata.map(|m| m.tcp_options.join(" ")),
+            ])?;
+        }
+    }
+    transaction.commit()?;
+
+    connection.execute_batch(SQLITE_INDEXES)
+}
+
+fn parquet_error(context: String) -> impl FnOnce(parquet::errors::ParquetError) -> SegError {
+    move |e| SegError::Io {
+        context,
+        source: io::Error::other(e),
+    }
+}
+
+/// Writes `rows` to `path` in batches built by `batch`.
+fn write_parquet_file<T>(
+    path: &Path,
+    rows: &[T],
+    batch: impl Fn(&[T]) -> std::result::Result<RecordBatch, arrow_schema::ArrowError>,
+) -> Result<()> {
+    let context = format!("Failed to write {}", path.display());
+    let file =
+        std::fs::File::create(path).context(format!("Unable to create {}", path.display()))?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(PARQUET_BATCH_ROWS)
+        .build();
+
+    let empty = batch(&[]).map_err(|e| parquet_error(context.clone())(e.into()))?;
+    let mut writer = ArrowWriter::try_new(file, empty.schema(), Some(properties))
+        .map_err(parquet_error(context.clone()))?;
+    for chunk in rows.chunks(PARQUET_BATCH_ROWS) {
+        let batch = batch(chunk).map_err(|e| parquet_error(context.clone())(e.into()))?;
+        writer
+            .write(&batch)
+            .map_err(parquet_error(context.clone()))?;
+    }
+    writer.close().map_err(parquet_error(context))?;
+    Ok(())
+}
+
+fn column(array: impl arrow_array::Array + 'static) -> ArrayRef {
+    Arc::new(array)
+}
+
+fn timestamps(values: impl Iterator<Item = DateTime<Utc>>) -> ArrayRef {
+    column(
+        TimestampMicrosecondArray::from(
+            values
+                .map(|timestamp| timestamp.timestamp_micros())
+                .collect::<Vec<_>>(),
+        )
+        .with_timezone("UTC"),
+    )
+}
+
+/// Writes networks.parquet, hosts.parquet, sessions.parquet and records.parquet into
+/// `dir`, with the same columns as the SQLite tables.
+pub fn write_parquet(dir: &str, normalized: &Normalized) -> Result<()> {
+    let dir = Path::new(dir);
+    std::fs::create_dir_all(dir).context(format!("Unable to create {}", dir.display()))?;
+
+    write_parquet_file(
+        &dir.join("networks.parquet"),
+        &normalized.networks,
+        |rows| {
+            RecordBatch::try_from_iter([
+                (
+                    "id",
+                    column(UInt64Array::from_iter_values(rows.iter().map(|n| n.id))),
+                ),
+                (
+                    "kind",
+                    column(StringArray::from_iter_values(rows.iter().map(|n| n.kind))),
+                ),
+                (
+                    "name",
+                    column(StringArray::from_iter_values(rows.iter().map(|n| &n.name))),
+                ),
+            ])
+        },
+    )?;
+
+    write_parquet_file(&dir.join("hosts.parquet"), &normalized.hosts, |rows| {
+        RecordBatch::try_from_iter([
+            (
+                "id",
+                column(UInt64Array::from_iter_values(rows.iter().map(|h| h.id))),
+            ),
+            (
+                "ip",
+                column(StringArray::from_iter_values(
+                    rows.iter().map(|h| h.ip.to_string()),
+                )),
+            ),
+            (
+                "role",
+                column(StringArray::from_iter_values(rows.iter().map(|h| h.role))),
+            ),
+            (
+                "network_id",
+                column(UInt64Array::from_iter_values(
+                    rows.iter().map(|h| h.network_id),
+                )),
+            ),
+        ])
+    })?;
+
+    write_parquet_file(
+        &dir.join("sessions.parquet"),
+        &normalized.sessions,
+        |rows| {
+            RecordBatch::try_from_iter([
+                (
+                    "id",
+                    column(UInt64Array::from_iter_values(rows.iter().map(|s| s.id))),
+                ),
+                (
+                    "scanner_id",
+                    column(UInt64Array::from_iter_values(
+                        rows.iter().map(|s| s.scanner_id),
+                    )),
+                ),
+                (
+                    "listener_id",
+                    column(UInt64Array::from_iter_values(
+                        rows.iter().map(|s| s.listener_id),
+                    )),
+                ),
+                (
+                    "protocol",
+                    column(StringArray::from_iter_values(
+                        rows.iter().map(|s| &s.protocol),
+                    )),
+                ),
+                (
+                    "port",
+                    column(UInt16Array::from_iter_values(rows.iter().map(|s| s.port))),
+                ),
+                (
+                    "packets",
+                    column(UInt64Array::from_iter_values(
+                        rows.iter().map(|s| s.packets),
+                    )),
+                ),
+                (
+                    "records",
+                    column(UInt64Array::from_iter_values(
+                        rows.iter().map(|s| s.records),
+                    )),
+                ),
+                ("first_seen", timestamps(rows.iter().map(|s| s.first_seen))),
+                ("last_seen", timestamps(rows.iter().map(|s| s.last_seen))),
+            ])
+        },
+    )?;
+
+    write_parquet_file(&dir.join("records.parquet"), &normalized.records, |rows| {
+        fn metadata<'a>(record: &Record<'a>) -> Option<&'a PacketMetadata> {
+            record.packet.metadata.as_ref()
+        }
+        RecordBatch::try_from_iter([
+            (
+                "id",
+                column(UInt64Array::from_iter_values(rows.iter().map(|r| r.id))),
+            ),
+            (
+                "session_id",
+                column(UInt64Array::from_iter_values(
+                    rows.iter().map(|r| r.session_id),
+                )),
+            ),
+            (
+                "timestamp",
+                timestamps(rows.iter().map(|r| r.packet.timestamp)),
+            ),
+            (
+                "last_seen",
+                timestamps(rows.iter().map(|r| r.packet.last_seen())),
+            ),
+            (
+                "source_port",
+                column(UInt16Array::from_iter_values(
+                    rows.iter().map(|r| r.packet.source_port),
+                )),
+            ),
+            (
+                "flags",
+                column(StringArray::from_iter_values(
+                    rows.iter().map(|r| r.packet.flags.join(" ")),
+                )),
+            ),
+            (
+                "count",
+                column(UInt64Array::from_iter_values(
+                    rows.iter().map(|r| r.packet.count()),
+                )),
+            ),
+            (
+                "source_mac",
+                column(StringArray::from_iter(
+                    rows.iter()
+                        .map(|r| metadata(r).map(|m| m.source_mac.clone())),
+                )),
+            ),
+            (
+                "destination_mac",
+                column(StringArray::from_iter(
+                    rows.iter()
+                        .map(|r| metadata(r).map(|m| m.destination_mac.clone())),
+                )),
+            ),
+            (
+                "ttl",
+                column(UInt8Array::from_iter(
+                    rows.iter().map(|r| metadata(r).map(|m| m.ttl)),
+                )),
+            ),
+            (
+                "ip_id",
+                column(UInt16Array::from_iter(
+                    rows.iter().map(|r| metadata(r).map(|m| m.ip_id)),
+                )),
+            ),
+            (
+                "dscp",
+                column(UInt8Array::from_iter(
+                    rows.iter().map(|r| metadata(r).map(|m| m.dscp)),
+                )),
+            ),
+            (
+                "length",
+                column(UInt16Array::from_iter(
+                    rows.iter().map(|r| metadata(r).map(|m| m.length)),
+                )),
+            ),
+            (
+                "tcp_window",
+                column(UInt16Array::from_iter(
+                    rows.iter().map(|r| metadata(r).and_then(|m| m.tcp_window)),
+                )),
+            ),
+            (
+                "tcp_options",
+                column(StringArray::from_iter(
+                    rows.iter()
+                        .map(|r| metadata(r).map(|m| m.tcp_options.join(" "))),
+                )),
+            ),
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_800_000_000 + seconds, 0).unwrap()
+    }
+
+    fn packet(source: [u8; 4], tag: &str, port: u16, seconds: i64) -> PacketInfo {
+        PacketInfo {
+            listener_ip: Ipv4Addr::new(192, 0, 2, 2),
+            network_tag: tag.to_string(),
+            source_ip: source.into(),
+            source_port: 40000,
+            target_port: port,
+            protocol: "tcp".to_string(),
+            flags: vec!["SYN".to_string()],
+            timestamp: time(seconds),
+            metadata: None,
+            last_seen: None,
+            count: None,
+        }
+    }
+
+    fn packets() -> Vec<PacketInfo> {
+        let mut aggregated = packet([10, 0, 1, 5], "pci", 22, 4);
+        aggregated.count = Some(10);
+        aggregated.last_seen = Some(time(9));
+        vec![
+            packet([10, 0, 1, 6], "pci", 22, 3),
+            packet([10, 0, 1, 5], "pci", 22, 1),
+            aggregated,
+            // The same listener address in another network is another host
+            packet([10, 0, 1, 5], "dmz", 22, 2),
+            packet([10, 0, 2, 5], "pci", 443, 5),
+        ]
+    }
+
+    #[test]
+    fn normalizes_in_time_order() {
+        let packets = packets();
+        let normalized = normalize(&packets, 24);
+
+        let networks: Vec<(u64, &str, &str)> = normalized
+            .networks
+            .iter()
+            .map(|network| (network.id, network.kind, network.name.as_str()))
+            .collect();
+        assert_eq!(
+            networks,
+            vec![
+                (1, "source", "10.0.1.0/24"),
+                (2, "destination", "pci"),
+                (3, "destination", "dmz"),
+                (4, "source", "10.0.2.0/24"),
+            ]
+        );
+
+        let hosts: Vec<(u64, String, &str, u64)> = normalized
+            .hosts
+            .iter()
+            .map(|host| (host.id, host.ip.to_string(), host.role, host.network_id))
+            .collect();
+        assert_eq!(
+            hosts,
+            vec![
+                (1, "10.0.1.5".to_string(), "scanner", 1),
+                (2, "192.0.2.2".to_string(), "listener", 2),
+                (3, "192.0.2.2".to_string(), "listener", 3),
+                (4, "10.0.1.6".to_string(), "scanner", 1),
+                (5, "10.0.2.5".to_string(), "scanner", 4),
+            ]
+        );
+
+        // id: scanner -> listener port, packets in records, first and last second
+        let sessions: Vec<String> = normalized
+            .sessions
+            .iter()
+            .map(|session| {
+                format!(
+                    "{}: {} -> {} {} {}, {} in {}, {}-{}",
+                    session.id,
+                    session.scanner_id,
+                    session.listener_id,
+                    session.protocol,
+                    session.port,
+                    session.packets,
+                    session.records,
+                    session.first_seen.timestamp() - 1_800_000_000,
+                    session.last_seen.timestamp() - 1_800_000_000,
+                )
+            })
+            .collect();
+        assert_eq!(
+            sessions,
+            vec![
+                "1: 1 -> 2 tcp 22, 11 in 2, 1-9",
+                "2: 1 -> 3 tcp 22, 1 in 1, 2-2",
+                "3: 4 -> 2 tcp 22, 1 in 1, 3-3",
+                "4: 5 -> 2 tcp 443, 1 in 1, 5-5",
+            ]
+        );
+
+        let records: Vec<(u64, u64, i64)> = normalized
+            .records
+            .iter()
+            .map(|record| {
+                (
+                    record.id,
+                    record.session_id,
+                    record.packet.timestamp.timestamp() - 1_800_000_000,
+                )
+            })
+            .collect();
+        assert_eq!(
+            records,
+            vec![(1, 1, 1), (2, 2, 2), (3, 3, 3), (4, 1, 4), (5, 4, 5)]
+        );
+    }
+
+    #[test]
+    fn sqlite_view_joins_back_to_packets() {
+        let packets = packets();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seg.db");
+        let path = path.to_str().unwrap();
+        write_sqlite(path, &normalize(&packets, 24)).unwrap();
+        // Replacing an existing database
+        write_sqlite(path, &normalize(&packets, 24)).unwrap();
+
+        let connection = Connection::open(path).unwrap();
+        let mut query = connection
+            .prepare(
+                "SELECT network_tag, source_network, source_ip, target_port, count \
+                 FROM packets ORDER BY id",
+            )
+            .unwrap();
+        let rows: Vec<(String, String, String, u16, u64)> = query
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let row = |tag: &str, network: &str, ip: &str, port, count| {
+            (
+                tag.to_string(),
+                network.to_string(),
+                ip.to_string(),
+                port,
+                count,
+            )
+        };
+        assert_eq!(
+            rows,
+            vec![
+                row("pci", "10.0.1.0/24", "10.0.1.5", 22, 1),
+                row("dmz", "10.0.1.0/24", "10.0.1.5", 22, 1),
+                row("pci", "10.0.1.0/24", "10.0.1.6", 22, 1),
+                row("pci", "10.0.1.0/24", "10.0.1.5", 22, 10),
+                row("pci", "10.0.2.0/24", "10.0.2.5", 443, 1),
+            ]
+        );
+    }
+}
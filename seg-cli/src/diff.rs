@@ -0,0 +1,504 @@
+use crate::error::{Context, Result};
+use crate::parse::*;
+use crate::table::*;
+use crate::util::escape_html;
+
+use chrono::Utc;
+use data::{Filter, PacketInfo};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DiffFormat {
+    Text,
+    Json,
+    Html,
+}
+
+pub struct DiffOptions {
+    pub old: String,
+    pub new: String,
+    pub filter: Option<Filter>,
+    pub format: DiffFormat,
+    pub out: Option<String>,
+    pub source_prefix: u8,
+}
+
+/// A path through the segmentation: traffic from a source network reached a port
+/// in a destination network.
+type PathKey = (SourceNetwork, String, String, u16);
+
+/// One side of the diff, a listener log or a directory of them.
+struct Engagement {
+    input: String,
+    packets: usize,
+    paths: BTreeMap<PathKey, Row>,
+    /// The network tags a listener ran for, whether or not anything reached it.
+    listened: BTreeSet<String>,
+}
+
+impl Engagement {
+    fn load(input: &str, filter: Option<&Filter>, source_prefix: u8) -> Result<Self> {
+        let input_path = Path::new(input);
+        let logs = if input_path.is_dir() {
+            load_logs(&[], &[input.to_string()], None)?
+        } else {
+            load_logs(&[input.to_string()], &[], None)?
+        };
+        Ok(Self::from_logs(input, logs, filter, source_prefix))
+    }
+
+    fn from_logs(input: &str, logs: Logs, filter: Option<&Filter>, source_prefix: u8) -> Self {
+        // A listener that was fully locked out still logged its counter summaries, so
+        // its network counts as tested even when no packet (or none the filter matches)
+        // reached it
+        let listened = logs
+            .packets
+            .iter()
+            .map(|packet| packet.network_tag.clone())
+            .chain(
+                logs.counters
+                    .iter()
+                    .map(|summary| summary.network_tag.clone()),
+            )
+            .collect();
+        let packets: Vec<PacketInfo> = logs
+            .packets
+            .into_iter()
+            .filter(|packet| filter.is_none_or(|filter| filter.matches(packet)))
+            .collect();
+
+        let rows = build_rows(
+            &packets,
+            &TableOptions {
+                columns: vec![],
+                aggregate: true,
+                collapse_ports: false,
+                source_prefix,
+            },
+        );
+        let paths = rows
+            .into_iter()
+            .filter_map(|row| {
+                let port = *row.ports.first()?;
+                let key = (
+                    row.source_network,
+                    row.destination_network.clone(),
+                    row.protocol.clone(),
+                    port,
+                );
+                Some((key, row))
+            })
+            .collect();
+
+        Self {
+            input: input.to_string(),
+            packets: packets.len(),
+            paths,
+            listened,
+        }
+    }
+
+    fn describe(&self) -> String {
+        let window = self
+            .paths
+            .values()
+            .map(|row| row.first_seen)
+            .min()
+            .zip(self.paths.values().map(|row| row.last_seen).max());
+        match window {
+            Some((first, last)) => format!(
+                "{} ({} packets, {} to {})",
+                self.input,
+                self.packets,
+                first.to_rfc3339(),
+                last.to_rfc3339()
+            ),
+            None => format!("{} (no packets)", self.input),
+        }
+    }
+}
+
+struct Drift<'a> {
+    /// Paths only the new engagement reached, with their rows from the new logs.
+    opened: Vec<&'a Row>,
+    /// Paths only the old engagement reached, with their rows from the old logs.
+    closed: Vec<&'a Row>,
+    unchanged: usize,
+    /// Destination networks only the old engagement had listeners in, with the number
+    /// of paths to them. Nothing was listening there the second time, so their paths
+    /// aren't counted as closed.
+    only_old: Vec<(String, usize)>,
+    /// Destination networks only the new engagement had listeners in. Their paths
+    /// aren't counted as opened, there was no listener to see them before.
+    only_new: Vec<(String, usize)>,
+}
+
+/// Destination networks `engagement` had a listener in and `other` didn't, with the
+/// number of paths to each.
+fn untested(engagement: &Engagement, other: &Engagement) -> Vec<(String, usize)> {
+    engagement
+        .listened
+        .difference(&other.listened)
+        .map(|network| {
+            let paths = engagement
+                .paths
+                .keys()
+                .filter(|(_, destination, _, _)| destination == network)
+                .count();
+            (network.clone(), paths)
+        })
+        .collect()
+}
+
+impl<'a> Drift<'a> {
+    fn new(old: &'a Engagement, new: &'a Engagement) -> Self {
+        // Only destinations both engagements listened in can show drift
+        let compared = |(_, destination, _, _): &PathKey| {
+            old.listened.contains(destination) && new.listened.contains(destination)
+        };
+
+        let opened = new
+            .paths
+            .iter()
+            .filter(|(key, _)| compared(key) && !old.paths.contains_key(*key))
+            .map(|(_, row)| row)
+            .collect();
+        let closed = old
+            .paths
+            .iter()
+            .filter(|(key, _)| compared(key) && !new.paths.contains_key(*key))
+            .map(|(_, row)| row)
+            .collect();
+        let unchanged = new
+            .paths
+            .keys()
+            .filter(|key| old.paths.contains_key(*key))
+            .count();
+        Self {
+            opened,
+            closed,
+            unchanged,
+            only_old: untested(old, new),
+            only_new: untested(new, old),
+        }
+    }
+}
+
+/// Ports per (source network, destination network, protocol), so a newly opened
+/// port range reads as one line.
+fn group_ports(rows: &[&Row]) -> BTreeMap<(SourceNetwork, String, String), BTreeSet<u16>> {
+    let mut groups: BTreeMap<_, BTreeSet<u16>> = BTreeMap::new();
+    for row in rows {
+        groups
+            .entry((
+                row.source_network,
+                row.destination_network.clone(),
+                row.protocol.clone(),
+            ))
+            .or_default()
+            .extend(row.ports.iter().copied());
+    }
+    groups
+}
+
+fn write_text(
+    output: &mut dyn Write,
+    old: &Engagement,
+    new: &Engagement,
+    drift: &Drift,
+) -> Result<()> {
+    let mut text = String::new();
+    let _ = writeln!(
+        text,
+        "{} paths opened, {} closed, {} unchanged",
+        drift.opened.len(),
+        drift.closed.len(),
+        drift.unchanged
+    );
+    let _ = writeln!(text, "old: {}", old.describe());
+    let _ = writeln!(text, "new: {}", new.describe());
+
+    for (title, networks) in [
+        ("Only in old, not compared", &drift.only_old),
+        ("Only in new, not compared", &drift.only_new),
+    ] {
+        if networks.is_empty() {
+            continue;
+        }
+        let _ = writeln!(text, "\n{}:", title);
+        for (network, paths) in networks {
+            let _ = writeln!(text, "  {} ({} paths)", network, paths);
+        }
+    }
+
+    for (title, marker, rows) in [
+        ("Opened", '+', &drift.opened),
+        ("Closed", '-', &drift.closed),
+    ] {
+        if rows.is_empty() {
+            continue;
+        }
+        let _ = writeln!(text, "\n{}:", title);
+        for ((source, destination, protocol), ports) in group_ports(rows) {
+            let _ = writeln!(
+                text,
+                "  {} {} -> {} {} {}",
+                marker,
+                source,
+                destination,
+                protocol,
+                format_port_ranges(&ports)
+            );
+        }
+    }
+
+    output
+        .write_all(text.as_bytes())
+        .context("Failed to write the diff")
+}
+
+fn write_json(
+    output: &mut dyn Write,
+    old: &Engagement,
+    new: &Engagement,
+    drift: &Drift,
+) -> Result<()> {
+    let path = |row: &&Row| {
+        json!({
+            "source_network": row.source_network.to_string(),
+            "destination_network": row.destination_network,
+            "protocol": row.protocol,
+            "port": row.ports.first(),
+            "count": row.count,
+            "first_seen": row.first_seen,
+            "last_seen": row.last_seen,
+        })
+    };
+    let engagement = |engagement: &Engagement| {
+        json!({
+            "input": engagement.input,
+            "packets": engagement.packets,
+            "paths": engagement.paths.len(),
+        })
+    };
+    let untested = |networks: &Vec<(String, usize)>| {
+        networks
+            .iter()
+            .map(|(network, paths)| json!({"destination_network": network, "paths": paths}))
+            .collect::<Vec<_>>()
+    };
+    let diff = json!({
+        "old": engagement(old),
+        "new": engagement(new),
+        "opened": drift.opened.iter().map(path).collect::<Vec<_>>(),
+        "closed": drift.closed.iter().map(path).collect::<Vec<_>>(),
+        "unchanged": drift.unchanged,
+        "only_in_old": untested(&drift.only_old),
+        "only_in_new": untested(&drift.only_new),
+    });
+
+    serde_json::to_writer_pretty(&mut *output, &diff)
+        .map_err(io::Error::from)
+        .and_then(|_| writeln!(output))
+        .context("Failed to write the diff")
+}
+
+const DIFF_CSS: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+table.data { border-collapse: collapse; margin-bottom: 1em; }
+table.data th, table.data td { border: 1px solid #999; padding: 4px 8px; text-align: left; }
+table.data th { background: #eee; }
+tr.opened td { background: #f8d7da; }
+tr.closed td { background: #d4edda; }
+.muted { color: #666; }
+";
+
+fn write_html(
+    output: &mut dyn Write,
+    old: &Engagement,
+    new: &Engagement,
+    drift: &Drift,
+) -> Result<()> {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Segmentation drift</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>Segmentation drift</h1>\n<p class=\"muted\">Generated {} by seg {}</p>\n",
+        DIFF_CSS,
+        Utc::now().to_rfc3339(),
+        env!("CARGO_PKG_VERSION"),
+    );
+    let _ = write!(
+        html,
+        "<p><strong>{}</strong> paths opened, <strong>{}</strong> closed, {} unchanged.</p>\n<table class=\"data\">\n<tr><th>Old</th><td>{}</td></tr>\n<tr><th>New</th><td>{}</td></tr>\n</table>\n",
+        drift.opened.len(),
+        drift.closed.len(),
+        drift.unchanged,
+        escape_html(&old.describe()),
+        escape_html(&new.describe())
+    );
+
+    for (title, networks) in [
+        ("Only in old", &drift.only_old),
+        ("Only in new", &drift.only_new),
+    ] {
+        if networks.is_empty() {
+            continue;
+        }
+        let networks: Vec<String> = networks
+            .iter()
+            .map(|(network, paths)| format!("{} ({} paths)", escape_html(network), paths))
+            .collect();
+        let _ = writeln!(
+            html,
+            "<p class=\"muted\">{}, not compared: {}</p>",
+            title,
+            networks.join(", ")
+        );
+    }
+
+    html.push_str("<table class=\"data\">\n<tr><th>Change</th><th>Source network</th><th>Destination network</th><th>Protocol</th><th>Ports</th></tr>\n");
+    for (class, rows) in [("opened", &drift.opened), ("closed", &drift.closed)] {
+        for ((source, destination, protocol), ports) in group_ports(rows) {
+            let _ = writeln!(
+                html,
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                class,
+                class,
+                source,
+                escape_html(&destination),
+                escape_html(&protocol),
+                format_port_ranges(&ports)
+            );
+        }
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    output
+        .write_all(html.as_bytes())
+        .context("Failed to write the diff")
+}
+
+/// Compares the paths two engagements reached and lists the ones that opened or
+/// closed in between.
+pub fn run_diff(options: DiffOptions) -> Result<()> {
+    let old = Engagement::load(&options.old, options.filter.as_ref(), options.source_prefix)?;
+    let new = Engagement::load(&options.new, options.filter.as_ref(), options.source_prefix)?;
+    let drift = Drift::new(&old, &new);
+
+    let mut output = open_output(options.out.as_deref())?;
+    match options.format {
+        DiffFormat::Text => write_text(&mut output, &old, &new, &drift)?,
+        DiffFormat::Json => write_json(&mut output, &old, &new, &drift)?,
+        DiffFormat::Html => write_html(&mut output, &old, &new, &drift)?,
+    }
+    output.flush().context("Failed to write the diff")?;
+
+    if let Some(out) = &options.out {
+        eprintln!(
+            "Wrote {} opened and {} closed paths to {}",
+            drift.opened.len(),
+            drift.closed.len(),
+            out
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::CounterSummary;
+    use std::net::Ipv4Addr;
+
+    fn packet(tag: &str, port: u16) -> PacketInfo {
+        PacketInfo {
+            listener_ip: Ipv4Addr::new(192, 0, 2, 2),
+            network_tag: tag.to_string(),
+            source_ip: Ipv4Addr::new(10, 0, 1, 5),
+            source_port: 40000,
+            target_port: port,
+            protocol: "tcp".to_string(),
+            flags: vec!["SYN".to_string()],
+            timestamp: "2026-10-18T10:00:00Z".parse().unwrap(),
+            metadata: None,
+            last_seen: None,
+            count: None,
+        }
+    }
+
+    /// An engagement with `packets`, plus listeners that only logged counter summaries.
+    fn engagement(packets: Vec<PacketInfo>, quiet: &[&str], filter: Option<&str>) -> Engagement {
+        let counters = quiet
+            .iter()
+            .map(|tag| CounterSummary {
+                listener_ip: Ipv4Addr::new(192, 0, 3, 2),
+                network_tag: tag.to_string(),
+                timestamp: "2026-10-18T10:00:09Z".parse().unwrap(),
+                is_final: true,
+                captured: 0,
+                rules: vec![],
+            })
+            .collect();
+        let logs = Logs {
+            packets,
+            counters,
+            files: vec![],
+        };
+        let filter = filter.map(|filter| Filter::parse(filter).unwrap());
+        Engagement::from_logs("test", logs, filter.as_ref(), 24)
+    }
+
+    fn ports(rows: &[&Row]) -> Vec<(String, u16)> {
+        rows.iter()
+            .map(|row| (row.destination_network.clone(), *row.ports.first().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn opened_and_closed() {
+        let old = engagement(vec![packet("pci", 22), packet("pci", 23)], &[], None);
+        let new = engagement(vec![packet("pci", 22), packet("pci", 443)], &[], None);
+        let drift = Drift::new(&old, &new);
+        assert_eq!(ports(&drift.opened), [("pci".to_string(), 443)]);
+        assert_eq!(ports(&drift.closed), [("pci".to_string(), 23)]);
+        assert_eq!(drift.unchanged, 1);
+        assert!(drift.only_old.is_empty() && drift.only_new.is_empty());
+    }
+
+    #[test]
+    fn locked_down_network_is_closed() {
+        let old = engagement(vec![packet("pci", 22), packet("dmz", 53)], &[], None);
+        // The dmz listener ran but nothing reached it
+        let new = engagement(vec![packet("pci", 22)], &["dmz"], None);
+        let drift = Drift::new(&old, &new);
+        assert_eq!(ports(&drift.closed), [("dmz".to_string(), 53)]);
+        assert!(drift.only_old.is_empty());
+
+        // A filter that matches nothing in a network doesn't make it untested either
+        let old = engagement(
+            vec![packet("pci", 22), packet("dmz", 53)],
+            &[],
+            Some("port < 60"),
+        );
+        let new = engagement(
+            vec![packet("pci", 22), packet("dmz", 80)],
+            &[],
+            Some("port < 60"),
+        );
+        let drift = Drift::new(&old, &new);
+        assert_eq!(ports(&drift.closed), [("dmz".to_string(), 53)]);
+    }
+
+    #[test]
+    fn network_without_listener_is_not_compared() {
+        let old = engagement(vec![packet("pci", 22), packet("dmz", 53)], &[], None);
+        let new = engagement(vec![packet("pci", 22), packet("lab", 80)], &[], None);
+        let drift = Drift::new(&old, &new);
+        assert!(drift.opened.is_empty() && drift.closed.is_empty());
+        assert_eq!(drift.only_old, [("dmz".to_string(), 1)]);
+        assert_eq!(drift.only_new, [("lab".to_string(), 1)]);
+    }
+}
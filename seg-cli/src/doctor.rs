@@ -0,0 +1,340 @@
+use crate::capture::find_interface;
+use crate::consts::{CAP_NET_ADMIN, CAP_NET_RAW, MIN_NFT_VERSION, RULESET_BACKUP_PATH};
+use crate::error::{Result, SegError};
+use crate::firewall::*;
+use crate::util::*;
+
+use pnet::datalink;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Outcome of a single preflight check.
+enum Status {
+    Ok,
+    Warn,
+    /// Carries the error a real run would have failed with.
+    Fail(SegError),
+}
+
+struct Report {
+    failure: Option<SegError>,
+    warnings: usize,
+}
+
+impl Report {
+    fn record(&mut self, status: Status, message: &str) {
+        let label = match status {
+            Status::Ok => "  ok",
+            Status::Warn => {
+                self.warnings += 1;
+                "warn"
+            }
+            Status::Fail(e) => {
+                // The first failure decides the exit code
+                self.failure.get_or_insert(e);
+                "FAIL"
+            }
+        };
+        println!("[{}] {}", label, message);
+    }
+}
+
+/// First line of `<tool> --version`, or `None` when the tool isn't installed.
+fn tool_version(tool: &str) -> Option<String> {
+    let output = std::process::Command::new(tool)
+        .arg("--version")
+        .output()
+        .ok()?;
+    let text = if output.stdout.is_empty() {
+        output.stderr
+    } else {
+        output.stdout
+    };
+    Some(
+        String::from_utf8_lossy(&text)
+            .lines()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+    )
+}
+
+/// Pulls the first `x.y.z` out of a version string.
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    version
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .find_map(|word| {
+            let mut parts = word.split('.').map(|part| part.parse::<u32>().ok());
+            Some((
+                parts.next()??,
+                parts.next()??,
+                parts.next().flatten().unwrap_or(0),
+            ))
+        })
+}
+
+fn check_privileges(report: &mut Report) {
+    let root = unsafe { libc::geteuid() } == 0;
+    report.record(
+        Status::Ok,
+        &format!(
+            "running as {}",
+            if root { "root" } else { "an unprivileged user" }
+        ),
+    );
+
+    for (cap, name, needed_for) in [
+        (CAP_NET_RAW, "CAP_NET_RAW", "capturing packets"),
+        (CAP_NET_ADMIN, "CAP_NET_ADMIN", "changing firewall rules"),
+    ] {
+        if has_capability(cap) {
+            report.record(Status::Ok, &format!("{} is available", name));
+        } else {
+            report.record(
+                Status::Fail(SegError::Permission(format!(
+                    "{} is required for {}",
+                    name, needed_for
+                ))),
+                &format!("{} is missing, needed for {}", name, needed_for),
+            );
+        }
+    }
+
+    // Backups of the host ruleset live here. The listener creates the directory when
+    // it saves one, so it's enough for the closest existing directory to be writable.
+    let state_dir = Path::new(RULESET_BACKUP_PATH)
+        .parent()
+        .unwrap_or(Path::new("/"));
+    let existing = state_dir
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("/"));
+    let what = if existing == state_dir {
+        format!("state directory {}", state_dir.display())
+    } else {
+        format!(
+            "{}, where state directory {} would be created,",
+            existing.display(),
+            state_dir.display()
+        )
+    };
+    match writable(existing) {
+        Ok(()) => report.record(Status::Ok, &format!("{} is writable", what)),
+        Err(e) => {
+            let message = format!("{} is not writable: {}", what, e);
+            report.record(
+                Status::Fail(SegError::io(
+                    format!("Failed to create {}", state_dir.display()),
+                    e,
+                )),
+                &message,
+            );
+        }
+    }
+}
+
+/// Whether we could create files in `dir`, without creating anything.
+fn writable(dir: &Path) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            "not a directory",
+        ));
+    }
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    if unsafe { libc::access(path.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn check_tools(report: &mut Report, backend: &dyn FirewallBackend) {
+    for tool in backend.tools() {
+        match tool_version(tool) {
+            Some(version) => report.record(Status::Ok, &format!("{}: {}", tool, version)),
+            None => report.record(
+                Status::Fail(SegError::MissingTool(format!(
+                    "{} is not installed or not in PATH",
+                    tool
+                ))),
+                &format!(
+                    "{} is not installed, the {} backend needs it",
+                    tool,
+                    backend.name()
+                ),
+            ),
+        }
+    }
+
+    if backend.tools().contains(&"nft") {
+        let version = tool_version("nft").as_deref().and_then(parse_version);
+        if let Some(version) = version.filter(|version| *version < MIN_NFT_VERSION) {
+            report.record(
+                Status::Fail(SegError::MissingTool(format!(
+                    "nft {}.{}.{} is too old",
+                    version.0, version.1, version.2
+                ))),
+                &format!(
+                    "nft {}.{}.{} is older than {}.{}.{}, which seg needs for JSON rulesets",
+                    version.0,
+                    version.1,
+                    version.2,
+                    MIN_NFT_VERSION.0,
+                    MIN_NFT_VERSION.1,
+                    MIN_NFT_VERSION.2
+                ),
+            );
+        }
+    }
+
+    for (tool, needed_for) in [
+        ("nmap", "seg scan"),
+        ("tcpdump", "compiling --bpf-filter expressions"),
+        ("unshare", "diffing rulesets in --dry-run"),
+    ] {
+        match tool_version(tool) {
+            Some(version) => report.record(Status::Ok, &format!("{}: {}", tool, version)),
+            None => report.record(
+                Status::Warn,
+                &format!("{} is not installed, needed for {}", tool, needed_for),
+            ),
+        }
+    }
+}
+
+fn check_interface(report: &mut Report, interface_name: Option<&str>) {
+    let Some(interface_name) = interface_name else {
+        let candidates: Vec<String> = datalink::interfaces()
+            .into_iter()
+            .filter(|iface| iface.is_up() && !iface.is_loopback())
+            .filter_map(|iface| {
+                let ip = iface.ips.iter().find(|ip| ip.is_ipv4())?;
+                Some(format!("{} ({})", iface.name, ip))
+            })
+            .collect();
+
+        if candidates.is_empty() {
+            report.record(
+                Status::Fail(SegError::Interface(
+                    "no interface is up with an IPv4 address".to_string(),
+                )),
+                "no interface is up with an IPv4 address",
+            );
+        } else {
+            report.record(
+                Status::Ok,
+                &format!("interfaces a listener can use: {}", candidates.join(", ")),
+            );
+        }
+        return;
+    };
+
+    match find_interface(interface_name) {
+        Ok((_, ip)) => report.record(
+            Status::Ok,
+            &format!("interface {} is up with address {}", interface_name, ip),
+        ),
+        Err(e) => {
+            let message = e.to_string();
+            report.record(Status::Fail(e), &message);
+        }
+    }
+}
+
+fn check_firewall(report: &mut Report, backend: &dyn FirewallBackend, kind: FirewallKind) {
+    report.record(
+        Status::Ok,
+        &format!("listener rules will be applied with {}", backend.name()),
+    );
+
+    let firewalld = run_command_blocking("firewall-cmd", &["--state"], None).is_ok();
+    if firewalld && backend.name() != "firewalld" {
+        report.record(
+            Status::Warn,
+            "firewalld is running and will overwrite seg's rules when it reloads, consider --firewall firewalld",
+        );
+    }
+
+    let ufw = run_command_blocking("ufw", &["status"], None)
+        .is_ok_and(|status| status.contains("Status: active"));
+    if ufw {
+        report.record(
+            Status::Warn,
+            "ufw is active and may reapply its rules while the listener runs",
+        );
+    }
+
+    let legacy = run_command_blocking("iptables-legacy-save", &[], None)
+        .is_ok_and(|rules| rules.lines().any(|line| line.starts_with("-A")));
+    if legacy && backend.name() == "nftables" {
+        report.record(
+            Status::Warn,
+            "legacy iptables rules are loaded and still filter traffic nftables accepts, consider --firewall iptables",
+        );
+    }
+
+    if backend.name() == "nftables" {
+        let tables: Vec<String> = run_command_blocking("nft", &["list", "tables"], None)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.strip_prefix("table "))
+            .map(str::to_string)
+            .collect();
+        if !tables.is_empty() {
+            report.record(
+                Status::Warn,
+                &format!(
+                    "the default nftables ruleset flushes the existing tables ({}) until the listener exits",
+                    tables.join(", ")
+                ),
+            );
+        }
+    }
+
+    let leftovers = leftover_rules(kind);
+    if leftovers.is_empty() {
+        report.record(Status::Ok, "no leftover seg rules");
+    } else {
+        report.record(
+            Status::Warn,
+            &format!(
+                "leftovers from a previous listener: {}, run seg listen --cleanup",
+                leftovers.join(", ")
+            ),
+        );
+    }
+}
+
+/// Checks everything a listener or scanner needs before it's run for real and prints a
+/// readiness report. Fails with the error of the first failed check.
+pub fn run_doctor(interface_name: Option<String>, kind: FirewallKind) -> Result<()> {
+    let mut report = Report {
+        failure: None,
+        warnings: 0,
+    };
+    let backend = select_backend(kind, interface_name.as_deref());
+
+    println!("Privileges");
+    check_privileges(&mut report);
+    println!("\nTools");
+    check_tools(&mut report, backend.as_ref());
+    println!("\nInterface");
+    check_interface(&mut report, interface_name.as_deref());
+    println!("\nFirewall");
+    check_firewall(&mut report, backend.as_ref(), kind);
+
+    println!();
+    match report.failure {
+        Some(e) => {
+            println!("Not ready");
+            Err(e)
+        }
+        None => {
+            println!("Ready ({} warnings)", report.warnings);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can make a seg command fail. Each variant maps to its own exit code
+/// so scripts driving seg can tell a missing tool from a typo in the arguments.
+#[derive(Debug)]
+pub enum SegError {
+    /// Bad arguments or unusable input files, rules or filters.
+    Input(String),
+    /// Not running with the privileges the operation needs.
+    Permission(String),
+    /// The interface doesn't exist, is down or has no IPv4 address.
+    Interface(String),
+    /// An external tool seg relies on (nft, nmap, tcpdump, ...) isn't installed.
+    MissingTool(String),
+    /// The firewall refused the rules or couldn't be restored.
+    Firewall(String),
+    /// Any other I/O failure, with what we were doing at the time.
+    Io { context: String, source: io::Error },
+}
+
+pub type Result<T> = std::result::Result<T, SegError>;
+
+impl SegError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SegError::Io { .. } => 1,
+            SegError::Input(_) => 2,
+            SegError::Permission(_) => 3,
+            SegError::Interface(_) => 4,
+            SegError::MissingTool(_) => 5,
+            SegError::Firewall(_) => 6,
+        }
+    }
+
+    /// Classifies an I/O error, so permission problems and missing files get their own
+    /// exit codes.
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        let context = context.into();
+        match source.kind() {
+            io::ErrorKind::PermissionDenied => {
+                SegError::Permission(format!("{}: {} (are you root?)", context, source))
+            }
+            io::ErrorKind::NotFound => SegError::Input(format!("{}: {}", context, source)),
+            _ => SegError::Io { context, source },
+        }
+    }
+
+    /// Like `io`, but a command that can't be found is reported as a missing tool.
+    pub fn command(tool: &str, context: impl Into<String>, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::NotFound => {
+                SegError::MissingTool(format!("{} is not installed or not in PATH", tool))
+            }
+            _ => SegError::io(context, source),
+        }
+    }
+}
+
+impl fmt::Display for SegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SegError::Input(message)
+            | SegError::Permission(message)
+            | SegError::Interface(message)
+            | SegError::MissingTool(message)
+            | SegError::Firewall(message) => write!(f, "{}", message),
+            SegError::Io { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+impl std::error::Error for SegError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SegError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Attaches context to I/O results, see `SegError::io`.
+pub trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| SegError::io(context, e))
+    }
+}
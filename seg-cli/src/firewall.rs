@@ -1,48 +1,615 @@
+mod access;
+mod firewalld;
+mod iptables;
+mod nftables;
+
+use crate::consts::CAP_NET_ADMIN;
+use crate::network::ScanProtocol;
+use crate::responder::{ResponderOptions, UdpReply};
+use data::RuleCounter;
+use std::io;
+
+/// Which firewall the listener manages its rules through.
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum FirewallKind {
+    /// firewalld if it's running, iptables on legacy hosts, nftables otherwise
+    Auto,
+    Nftables,
+    Iptables,
+    Firewalld,
+}
+
+/// A firewall seg can apply its listener rules through. Everything blocks so teardown
+/// can run from a panic hook.
+pub trait FirewallBackend: Send {
+    fn name(&self) -> &'static str;
+
+    /// The commands the backend runs, all of which must be installed.
+    fn tools(&self) -> &'static [&'static str];
+
+    /// The capabilities those commands need, kept after the listener drops privileges
+    /// so it can still tear its rules down.
+    fn capabilities(&self) -> &'static [u32] {
+        &[CAP_NET_ADMIN]
+    }
+
+    /// The default ruleset as a text template, printed by `--emit-rules`.
+    fn rules_template(&self) -> String;
+
+    /// The ruleset applied when no `--rules` file is given.
+    fn default_rules(
+        &self,
+        variables: &RuleVariables,
+        responder: Option<&ResponderOptions>,
+    ) -> String {
+        render_template(self, &self.rules_template(), variables, responder)
+    }
+
+    /// Fills in a custom `--rules` file, appending the responder redirects when
+    /// responding. Errors if the file can't be rendered.
+    fn custom_rules(
+        &self,
+        rules: &str,
+        variables: &RuleVariables,
+        responder: Option<&ResponderOptions>,
+    ) -> Result<String, String> {
+        Ok(render_template(self, rules, variables, responder))
+    }
+
+    /// Template variables only this backend knows about, such as firewalld's `{zone}`.
+    fn variables(&self) -> Vec<(&'static str, String)> {
+        vec![]
+    }
+
+    /// Renders `{excluded_ports}`, an nft set such as `{ 22, 53 }` by default.
+    fn format_ports(&self, ports: &[u16]) -> String {
+        let ports: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+        format!("{{ {} }}", ports.join(", "))
+    }
+
+    /// Rules redirecting probes to the responder, appended to the ruleset. Probes to
+    /// `excluded_ports`, which include the access port, are left alone.
+    fn responder_rules(&self, excluded_ports: &[u16], responder: &ResponderOptions) -> String;
+
+    /// Checks a ruleset without applying it.
+    fn check(&self, rules: &str) -> io::Result<()>;
+
+    /// Makes sure new TCP connections to the access port still get through once `rules`
+    /// are applied, so a custom ruleset can't lock us out of the host.
+    fn check_access_port(&self, rules: &str, access_port: u16) -> Result<(), String>;
+
+    fn live_ruleset(&self) -> io::Result<String>;
+
+    /// The live ruleset as it would look with `rules` applied, if that can be worked
+    /// out without touching the host.
+    fn simulate(&self, _rules: &str) -> Option<String> {
+        None
+    }
+
+    /// Saves whatever `restore` needs to put the host back.
+    fn backup(&self) -> io::Result<()>;
+
+    fn apply(&self, rules: &str) -> io::Result<()>;
+
+    /// Describes seg rules or backups left behind by a listener that didn't clean up.
+    fn leftovers(&self) -> Vec<String>;
+
+    /// Removes seg's rules and restores the ruleset saved by `backup`.
+    fn restore(&self);
+
+    /// Packet and byte counts of seg's rules.
+    fn counters(&self) -> io::Result<Vec<RuleCounter>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} doesn't report rule counters", self.name()),
+        ))
+    }
+}
+
+/// Fills in a text ruleset, appending the backend's responder redirects when responding.
+fn render_template<B: FirewallBackend + ?Sized>(
+    backend: &B,
+    rules: &str,
+    variables: &RuleVariables,
+    responder: Option<&ResponderOptions>,
+) -> String {
+    let mut ruleset = rules.to_string();
+    if let Some(responder) = responder {
+        ruleset.push_str(&backend.responder_rules(&variables.excluded_ports, responder));
+    }
+    variables.apply(&ruleset, backend)
+}
+
+/// The protocols probes are redirected to the responder for.
+fn redirect_protocols(responder: &ResponderOptions) -> Vec<&'static str> {
+    let mut protocols = vec![];
+    if !matches!(responder.protocol, ScanProtocol::UDP) {
+        protocols.push("tcp");
+    }
+    if !matches!(responder.protocol, ScanProtocol::TCP)
+        && !matches!(responder.udp_reply, UdpReply::None)
+    {
+        protocols.push("udp");
+    }
+    protocols
+}
+
 //#[cfg(target_os = "linux")]
 mod platform {
-    use crate::consts::NFT_RULES_TEMPLATE;
+    use super::firewalld::Firewalld;
+    use super::iptables::Iptables;
+    use super::nftables::Nftables;
+    use super::{FirewallBackend, FirewallKind};
+    use crate::error::SegError;
+    use crate::responder::ResponderOptions;
     use crate::util::*;
-    use std::io::Write;
+    use data::RuleCounter;
+    use std::collections::HashSet;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Holds the backend while seg's rules are applied, so teardown runs exactly once no
+    /// matter which exit path gets there first.
+    static ACTIVE_BACKEND: Mutex<Option<Box<dyn FirewallBackend>>> = Mutex::new(None);
+
+    /// The privileged process that tears the rules down for a listener that switched
+    /// users: its pid and our end of the pipe it waits on.
+    static TEARDOWN_HELPER: Mutex<Option<(libc::pid_t, libc::c_int)>> = Mutex::new(None);
+
+    /// Picks the firewall to manage, detecting it for `FirewallKind::Auto`.
+    /// `interface` is used to find the firewalld zone.
+    pub fn select_backend(kind: FirewallKind, interface: Option<&str>) -> Box<dyn FirewallBackend> {
+        match kind {
+            FirewallKind::Auto => select_backend(detect_firewall(), interface),
+            FirewallKind::Nftables => Box::new(Nftables),
+            FirewallKind::Iptables => Box::new(Iptables),
+            FirewallKind::Firewalld => Box::new(Firewalld::new(interface)),
+        }
+    }
+
+    /// firewalld overwrites raw edits and legacy iptables rules apply on top of nftables,
+    /// so both win over nft when present.
+    fn detect_firewall() -> FirewallKind {
+        if run_command_blocking("firewall-cmd", &["--state"], None).is_ok() {
+            return FirewallKind::Firewalld;
+        }
+
+        let iptables = run_command_blocking("iptables", &["--version"], None);
+        if iptables
+            .as_ref()
+            .is_ok_and(|version| version.contains("legacy"))
+        {
+            return FirewallKind::Iptables;
+        }
+        if run_command_blocking("nft", &["--version"], None).is_err() && iptables.is_ok() {
+            return FirewallKind::Iptables;
+        }
+        FirewallKind::Nftables
+    }
+
+    /// Values substituted into the rules template or a custom ruleset.
+    pub struct RuleVariables {
+        pub access_port: u16,
+        pub interface: String,
+        pub listener_ip: Ipv4Addr,
+        /// Includes the access port.
+        pub excluded_ports: Vec<u16>,
+    }
+
+    impl RuleVariables {
+        /// Replaces `{access_port}`, `{interface}`, `{listener_ip}`, `{excluded_ports}` and
+        /// the backend's own variables. Excluded ports are formatted by the backend.
+        pub fn apply<B: FirewallBackend + ?Sized>(&self, rules: &str, backend: &B) -> String {
+            let mut rules = rules
+                .replace("{access_port}", &self.access_port.to_string())
+                .replace("{interface}", &self.interface)
+                .replace("{listener_ip}", &self.listener_ip.to_string())
+                .replace(
+                    "{excluded_ports}",
+                    &backend.format_ports(&self.excluded_ports),
+                );
+            for (name, value) in backend.variables() {
+                rules = rules.replace(name, &value);
+            }
+            rules
+        }
+    }
+
+    /// Renders the final ruleset: the custom rules with the variables filled in and the
+    /// responder redirects appended, or the backend's default ruleset.
+    pub fn render_rules(
+        backend: &dyn FirewallBackend,
+        rules: Option<String>,
+        variables: &RuleVariables,
+        responder: Option<&ResponderOptions>,
+    ) -> crate::error::Result<String> {
+        match rules {
+            Some(rules) => backend
+                .custom_rules(&rules, variables, responder)
+                .map_err(|e| SegError::Input(format!("Invalid rules file: {}", e))),
+            None => Ok(backend.default_rules(variables, responder)),
+        }
+    }
+
+    /// Maps a backend failure to an error, telling missing tools and missing privileges
+    /// apart from rules the firewall refused.
+    fn firewall_error(backend: &dyn FirewallBackend, action: &str, e: io::Error) -> SegError {
+        match e.kind() {
+            io::ErrorKind::NotFound => SegError::MissingTool(format!(
+                "{} is not installed or not in PATH: {}",
+                backend.name(),
+                e
+            )),
+            io::ErrorKind::PermissionDenied => SegError::Permission(format!(
+                "{} with {} requires root: {}",
+                action,
+                backend.name(),
+                e
+            )),
+            _ => SegError::Firewall(format!("{} with {}: {}", action, backend.name(), e)),
+        }
+    }
+
+    pub fn setup_firewall_rules(
+        backend: Box<dyn FirewallBackend>,
+        rules: Option<String>,
+        variables: &RuleVariables,
+        responder: Option<&ResponderOptions>,
+    ) -> crate::error::Result<()> {
+        let ruleset = render_rules(backend.as_ref(), rules, variables, responder)?;
+        backend
+            .check_access_port(&ruleset, variables.access_port)
+            .map_err(|e| SegError::Input(format!("Refusing to apply firewall rules: {}", e)))?;
+
+        backend.backup().map_err(|e| {
+            firewall_error(backend.as_ref(), "Failed to back up the current rules", e)
+        })?;
+
+        println!(
+            "Setting up firewall rules with {}:\n{}",
+            backend.name(),
+            ruleset
+        );
+
+        if let Err(e) = backend.apply(&ruleset) {
+            // Put back whatever was applied and drop the backup we just took
+            backend.restore();
+            return Err(firewall_error(backend.as_ref(), "Failed to set rules", e));
+        }
+        *ACTIVE_BACKEND.lock().unwrap_or_else(|e| e.into_inner()) = Some(backend);
+        Ok(())
+    }
+
+    /// Shows what `setup_firewall_rules` would do without touching the host: prints the
+    /// final ruleset, checks it with the backend and diffs it against the live ruleset.
+    /// Returns whether the ruleset is valid.
+    pub fn preview_firewall_rules(
+        backend: &dyn FirewallBackend,
+        rules: Option<String>,
+        variables: &RuleVariables,
+        responder: Option<&ResponderOptions>,
+    ) -> bool {
+        let ruleset = match render_rules(backend, rules, variables, responder) {
+            Ok(ruleset) => ruleset,
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        };
+        println!(
+            "Rules that would be applied with {}:\n{}",
+            backend.name(),
+            ruleset
+        );
+
+        let mut valid = true;
+        if let Err(e) = backend.check_access_port(&ruleset, variables.access_port) {
+            eprintln!("Access port check failed: {}", e);
+            valid = false;
+        }
+
+        match backend.check(&ruleset) {
+            Ok(()) => println!("{} check: ruleset is valid", backend.name()),
+            Err(e) => {
+                eprintln!("{} check: ruleset is NOT valid: {}", backend.name(), e);
+                valid = false;
+            }
+        }
+
+        let live = backend.live_ruleset().unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to read the live ruleset, diffing against an empty one: {}",
+                e
+            );
+            String::new()
+        });
+
+        let Some(proposed) = backend.simulate(&ruleset) else {
+            println!("\nCurrent {} configuration:\n{}", backend.name(), live);
+            return valid;
+        };
+
+        println!("\nChanges to the live ruleset (- removed, + added):");
+        let changes = diff_lines(&live, &proposed);
+        if changes.iter().all(|line| line.starts_with(' ')) {
+            println!("  (none)");
+        }
+        for line in changes {
+            println!("{}", line);
+        }
 
-    pub async fn setup_firewall_rules(rules: Option<String>, access_port: &str) {
-        let nft_rules = match rules {
-            Some(rules) => rules,
-            None => NFT_RULES_TEMPLATE.replace("{access_port}", access_port),
+        valid
+    }
+
+    /// Commit-confirm for freshly applied rules. The operator has `timeout` to type
+    /// `yes`, or with `check` set another host must open a new session to that port. If
+    /// neither happens, or we're told to shut down, the previous ruleset is restored.
+    /// Returns whether the rules were kept.
+    pub async fn confirm_firewall_rules(
+        timeout: Duration,
+        check: Option<u16>,
+        local_ip: Ipv4Addr,
+    ) -> bool {
+        println!(
+            "Firewall rules applied. Type 'yes' within {}s to keep them or they will be rolled back.",
+            timeout.as_secs()
+        );
+        if let Some(port) = check {
+            println!(
+                "Rules are kept automatically once a new connection to port {} comes in from another host.",
+                port
+            );
+        }
+
+        let confirmed = tokio::time::timeout(timeout, async {
+            tokio::select! {
+                _ = wait_for_operator() => true,
+                _ = wait_for_connectivity(check, local_ip) => true,
+                signal = shutdown_signal() => {
+                    println!("Received {} before the rules were confirmed", signal);
+                    false
+                }
+            }
+        })
+        .await
+        .unwrap_or(false);
+
+        if confirmed {
+            println!("Firewall rules confirmed");
+        } else {
+            eprintln!("Firewall rules were not confirmed, rolling back");
+            teardown_firewall_rules().await;
+        }
+        confirmed
+    }
+
+    /// Reads stdin on a plain thread: tokio's stdin is a blocking read the runtime
+    /// would wait for on shutdown, so an unconfirmed listener couldn't exit.
+    async fn wait_for_operator() {
+        let (confirmed_tx, confirmed_rx) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            for line in io::stdin().lines() {
+                match line {
+                    Ok(line) if matches!(line.trim(), "y" | "yes") => {
+                        let _ = confirmed_tx.send(());
+                        return;
+                    }
+                    Ok(_) => println!("Type 'yes' to keep the rules"),
+                    Err(_) => return,
+                }
+            }
+        });
+
+        // No terminal to confirm from, leave it to the timeout or the check
+        if confirmed_rx.await.is_err() {
+            std::future::pending().await
+        }
+    }
+
+    /// Parses an address from `/proc/net/tcp` or `/proc/net/tcp6`, e.g. `0100007F:0016`.
+    /// The kernel prints the address as 32-bit words in host byte order.
+    fn parse_proc_address(address: &str) -> Option<(IpAddr, u16)> {
+        let (ip, port) = address.split_once(':')?;
+        let port = u16::from_str_radix(port, 16).ok()?;
+        let mut bytes = vec![];
+        for word in 0..ip.len() / 8 {
+            let word = u32::from_str_radix(ip.get(word * 8..word * 8 + 8)?, 16).ok()?;
+            bytes.extend(word.to_ne_bytes());
+        }
+        let ip = match bytes.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?),
+            16 => IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?).to_canonical(),
+            _ => return None,
         };
+        Some((ip, port))
+    }
 
-        println!("Setting up firewall rules with nft:\n{}", nft_rules);
+    /// The peers with an established TCP session to `port` on this host, other than
+    /// this host itself.
+    fn inbound_sessions(port: u16, local_ip: Ipv4Addr) -> HashSet<(IpAddr, u16)> {
+        const ESTABLISHED: &str = "01";
+        let mut peers = HashSet::new();
+        for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+            let Ok(sessions) = std::fs::read_to_string(table) else {
+                continue;
+            };
+            for session in sessions.lines().skip(1) {
+                let fields: Vec<&str> = session.split_whitespace().collect();
+                let (Some(local), Some(remote), Some(&ESTABLISHED)) = (
+                    fields.get(1).and_then(|a| parse_proc_address(a)),
+                    fields.get(2).and_then(|a| parse_proc_address(a)),
+                    fields.get(3),
+                ) else {
+                    continue;
+                };
+                if local.1 == port && !remote.0.is_loopback() && remote.0 != local_ip {
+                    peers.insert(remote);
+                }
+            }
+        }
+        peers
+    }
 
-        // Create a temp file to use with nft
-        let mut temp_file = tempfile::Builder::new()
-            .prefix("seg_nft_rules")
-            .suffix(".txt")
-            .tempfile()
-            .expect("Failed to open temp file for nft rules");
+    /// Waits for a session to `port` from another host that wasn't there when the rules
+    /// were applied. Connecting from the listener itself would go over loopback and
+    /// never cross the rules being tested, and sessions that already existed may only
+    /// survive through a conntrack rule.
+    async fn wait_for_connectivity(port: Option<u16>, local_ip: Ipv4Addr) {
+        let Some(port) = port else {
+            return std::future::pending().await;
+        };
 
-        write!(temp_file, "{}", nft_rules).expect("Failed to write nft rules to temp file");
+        let existing = inbound_sessions(port, local_ip);
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if let Some(peer) = inbound_sessions(port, local_ip)
+                .into_iter()
+                .find(|peer| !existing.contains(peer))
+            {
+                println!(
+                    "New connection to port {} from {}:{}, the access port is reachable",
+                    port, peer.0, peer.1
+                );
+                return;
+            }
+        }
+    }
 
-        let temp_file_path = temp_file.path();
-        let temp_file_path = temp_file_path.to_owned();
-        let temp_file_path = temp_file_path
-            .to_str()
-            .expect("Failed to get temp file path");
+    /// Counters of the rules currently applied, empty when none are.
+    pub fn rule_counters() -> io::Result<Vec<RuleCounter>> {
+        match ACTIVE_BACKEND
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+        {
+            Some(backend) => backend.counters(),
+            None => Ok(vec![]),
+        }
+    }
 
-        println!("Rules written to {:?}", temp_file_path);
+    /// Forks a helper that keeps our privileges and tears the rules down once the pipe
+    /// to it closes, when the listener asks for it or dies without getting to. Must be
+    /// called before any other thread is started.
+    pub fn fork_teardown_helper() -> io::Result<()> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let [read_fd, write_fd] = fds;
 
-        run_command("nft", &vec!["-f", temp_file_path], None)
-            .await
-            .expect("Failed to set rules with nft");
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                // Ctrl-C reaches the whole process group, the listener tells us when to go
+                unsafe {
+                    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+                        libc::signal(signal, libc::SIG_IGN);
+                    }
+                    libc::close(write_fd);
+                    let mut byte = 0u8;
+                    while libc::read(read_fd, (&mut byte as *mut u8).cast(), 1) < 0
+                        && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+                    {
+                    }
+                }
+                teardown_firewall_rules_blocking();
+                unsafe { libc::_exit(0) }
+            }
+            pid => {
+                unsafe { libc::close(read_fd) };
+                *TEARDOWN_HELPER.lock().unwrap_or_else(|e| e.into_inner()) = Some((pid, write_fd));
+                Ok(())
+            }
+        }
     }
 
     pub async fn teardown_firewall_rules() {
-        let cleanup_commands = ["delete table ip filter", "flush ruleset"];
+        if let Err(e) = tokio::task::spawn_blocking(teardown_firewall_rules_blocking).await {
+            eprintln!("Failed to clean up firewall rules: {}", e);
+        }
+    }
+
+    /// Removes seg's rules and restores the ruleset that was in place before the
+    /// listener started. Synchronous so it can run from a panic hook. Does nothing if
+    /// the rules were already torn down.
+    pub fn teardown_firewall_rules_blocking() {
+        let backend = ACTIVE_BACKEND
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        let Some(backend) = backend else {
+            return;
+        };
+
+        let helper = TEARDOWN_HELPER
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some((pid, write_fd)) = helper {
+            unsafe {
+                libc::close(write_fd);
+                libc::waitpid(pid, std::ptr::null_mut(), 0);
+            }
+            return;
+        }
+
+        println!("Cleaning up {} rules...", backend.name());
+        backend.restore();
+    }
+
+    /// The backends a previous listener may have used.
+    fn leftover_backends(kind: FirewallKind) -> Vec<Box<dyn FirewallBackend>> {
+        match kind {
+            FirewallKind::Auto => vec![
+                select_backend(FirewallKind::Nftables, None),
+                select_backend(FirewallKind::Iptables, None),
+                select_backend(FirewallKind::Firewalld, None),
+            ],
+            kind => vec![select_backend(kind, None)],
+        }
+    }
+
+    /// Describes the rules and backups `cleanup_leftover_rules` would remove.
+    pub fn leftover_rules(kind: FirewallKind) -> Vec<String> {
+        leftover_backends(kind)
+            .iter()
+            .flat_map(|backend| backend.leftovers())
+            .collect()
+    }
+
+    /// Looks for rules left behind by a listener that never got to clean up (killed,
+    /// power loss, ...) and removes them. With `FirewallKind::Auto` every backend is
+    /// checked, since the listener may have used another one. Fails if anything is
+    /// still left once every backend has been restored.
+    pub fn cleanup_leftover_rules(kind: FirewallKind) -> crate::error::Result<()> {
+        let mut found_any = false;
+        let mut remaining = vec![];
+        for backend in leftover_backends(kind) {
+            let found = backend.leftovers();
+            if found.is_empty() {
+                continue;
+            }
 
-        println!("Cleaning up nft rules...");
-        for cmd in &cleanup_commands {
-            if let Err(e) = run_command("nft", &[cmd], None).await {
-                eprintln!("Failed to remove nft rules: {}, {}", cmd, e);
+            for leftover in found {
+                println!("Found leftover {}", leftover);
             }
+            backend.restore();
+            found_any = true;
+            remaining.extend(backend.leftovers());
+        }
+
+        if !found_any {
+            println!("No leftover seg rules found");
+        }
+        if !remaining.is_empty() {
+            return Err(SegError::Firewall(format!(
+                "failed to clean up {}",
+                remaining.join(", ")
+            )));
         }
+        Ok(())
     }
 }
 
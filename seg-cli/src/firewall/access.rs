@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+/// Whether a rule's conditions hold for a new TCP connection to the access port.
+/// Conditions on anything but the protocol and the port (addresses, interfaces,
+/// conntrack state, ...) are `Maybe`, since we don't know where the operator connects
+/// from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matches {
+    Always,
+    Never,
+    Maybe,
+}
+
+impl Matches {
+    pub fn from_bool(matches: bool) -> Self {
+        if matches {
+            Matches::Always
+        } else {
+            Matches::Never
+        }
+    }
+
+    /// `Maybe` for conditions that can't be worked out.
+    pub fn from_option(matches: Option<bool>) -> Self {
+        matches.map_or(Matches::Maybe, Matches::from_bool)
+    }
+
+    pub fn and(self, other: Matches) -> Self {
+        match (self, other) {
+            (Matches::Never, _) | (_, Matches::Never) => Matches::Never,
+            (Matches::Always, Matches::Always) => Matches::Always,
+            _ => Matches::Maybe,
+        }
+    }
+
+    pub fn not(self) -> Self {
+        match self {
+            Matches::Always => Matches::Never,
+            Matches::Never => Matches::Always,
+            Matches::Maybe => Matches::Maybe,
+        }
+    }
+}
+
+/// What a rule does with the packets it matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Accept,
+    /// Drop or reject.
+    Drop,
+    Jump(String),
+    Goto(String),
+    Return,
+    /// Counters, logging and anything else that carries on with the next rule.
+    Continue,
+}
+
+pub struct AccessRule {
+    pub matches: Matches,
+    pub action: Action,
+}
+
+#[derive(Default)]
+pub struct AccessChain {
+    pub policy_drops: bool,
+    pub rules: Vec<AccessRule>,
+}
+
+/// A ruleset reduced to what decides whether the access port stays reachable.
+#[derive(Default)]
+pub struct AccessRuleset {
+    pub chains: HashMap<String, AccessChain>,
+    /// The chains hooked where packets to the host are filtered, in any order.
+    pub base_chains: Vec<String>,
+}
+
+enum Verdict {
+    Accept,
+    Drop,
+    Continue,
+}
+
+/// How deep jumps are followed. Firewalls refuse jump loops, this only guards against
+/// a ruleset we misread.
+const MAX_JUMPS: usize = 16;
+
+/// Walks `name` like the kernel would for a new TCP connection to the access port.
+/// Rules that only might match are assumed not to, so an accept that depends on the
+/// source address doesn't count, and neither does a drop of invalid packets.
+fn walk(ruleset: &AccessRuleset, name: &str, depth: usize) -> Verdict {
+    let Some(chain) = ruleset.chains.get(name) else {
+        return Verdict::Continue;
+    };
+    if depth > MAX_JUMPS {
+        return Verdict::Continue;
+    }
+
+    for rule in &chain.rules {
+        if rule.matches != Matches::Always {
+            continue;
+        }
+        match &rule.action {
+            Action::Accept => return Verdict::Accept,
+            Action::Drop => return Verdict::Drop,
+            Action::Return => return Verdict::Continue,
+            Action::Jump(target) => match walk(ruleset, target, depth + 1) {
+                Verdict::Continue => {}
+                verdict => return verdict,
+            },
+            Action::Goto(target) => return walk(ruleset, target, depth + 1),
+            Action::Continue => {}
+        }
+    }
+    Verdict::Continue
+}
+
+impl AccessRuleset {
+    /// Makes sure no base chain drops a new TCP connection to the access port. Every
+    /// base chain has to let it through, an accept in one doesn't skip the others.
+    pub fn check(&self, access_port: u16) -> Result<(), String> {
+        for name in &self.base_chains {
+            let drops = match walk(self, name, 0) {
+                Verdict::Accept => false,
+                Verdict::Drop => true,
+                Verdict::Continue => self.chains.get(name).is_some_and(|c| c.policy_drops),
+            };
+            if drops {
+                return Err(format!(
+                    "chain {} drops new connections to tcp port {}, applying the ruleset would lock us out",
+                    name, access_port
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `port` is in a list such as `22`, `1-1024` or `22,80,8000:8080`. `None`
+/// when part of the list isn't a number, e.g. a service name.
+pub fn port_in_list(list: &str, port: u16) -> Option<bool> {
+    let mut found = false;
+    for item in list.split(',').map(str::trim) {
+        let (first, last) = item.split_once([':', '-']).unwrap_or((item, item));
+        let first: u16 = first.trim().parse().ok()?;
+        let last: u16 = last.trim().parse().ok()?;
+        found |= (first..=last).contains(&port);
+    }
+    Some(found)
+}
@@ -0,0 +1,247 @@
+use super::access::port_in_list;
+use super::{redirect_protocols, FirewallBackend};
+use crate::consts::{FIREWALLD_MARKER_PATH, FIREWALLD_RULES_TEMPLATE};
+use crate::responder::ResponderOptions;
+use crate::util::*;
+use std::io;
+use std::path::Path;
+
+/// Changes the runtime configuration of the zone the listener interface is in through
+/// `firewall-cmd`, so firewalld doesn't overwrite the rules. Teardown is a
+/// `firewall-cmd --reload`, which also drops runtime changes made by anyone else
+/// while the listener was running.
+pub struct Firewalld {
+    zone: String,
+}
+
+impl Firewalld {
+    /// Uses the zone `interface` is bound to, or the default zone.
+    pub fn new(interface: Option<&str>) -> Self {
+        let zone = interface
+            .and_then(|interface| {
+                let query = format!("--get-zone-of-interface={}", interface);
+                run_command_blocking("firewall-cmd", &[&query], None).ok()
+            })
+            .or_else(|| run_command_blocking("firewall-cmd", &["--get-default-zone"], None).ok())
+            .map(|zone| zone.trim().to_string())
+            .filter(|zone| !zone.is_empty())
+            .unwrap_or_else(|| "public".to_string());
+
+        Self { zone }
+    }
+
+    /// Every non-comment line of a ruleset, split into `firewall-cmd` arguments.
+    fn commands(rules: &str) -> Vec<Vec<&str>> {
+        rules
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| line.split_whitespace().collect())
+            .collect()
+    }
+}
+
+/// The port ranges covering everything but `ports`, in firewalld's `from-to` notation.
+fn ranges_excluding(ports: &[u16]) -> Vec<String> {
+    let mut ports: Vec<u32> = ports.iter().map(|port| u32::from(*port)).collect();
+    ports.sort();
+    ports.dedup();
+
+    let mut ranges = vec![];
+    let mut start = 1;
+    for port in ports.into_iter().chain([u32::from(u16::MAX) + 1]) {
+        if port > start + 1 {
+            ranges.push(format!("{}-{}", start, port - 1));
+        } else if port == start + 1 {
+            ranges.push(start.to_string());
+        }
+        start = start.max(port + 1);
+    }
+    ranges
+}
+
+/// The value of `name="..."` in a rich rule.
+fn rich_rule_value<'a>(rule: &'a str, name: &str) -> Option<&'a str> {
+    let start = rule.find(&format!("{}=\"", name))? + name.len() + 2;
+    rule[start..].split('"').next()
+}
+
+/// Whether a rich rule drops or rejects new TCP connections to `port` from anywhere.
+fn rich_rule_drops(rule: &str, port: u16) -> bool {
+    let rule = rule.trim_matches(|c| c == '\'' || c == '"');
+    let drops = rule
+        .split_whitespace()
+        .any(|word| matches!(word, "drop" | "reject"));
+    // Rules limited to some sources might not cover the operator
+    if !drops || rule.contains("source ") || rule.contains(" log") {
+        return false;
+    }
+    if rule.contains("port ") {
+        let protocol = rich_rule_value(rule, "protocol").unwrap_or("tcp");
+        return protocol == "tcp"
+            && rich_rule_value(rule, "port")
+                .and_then(|ports| port_in_list(ports, port))
+                .unwrap_or(false);
+    }
+    if rule.contains("service ") {
+        return port == 22 && rich_rule_value(rule, "name") == Some("ssh");
+    }
+    !rule.contains("protocol ") && !rule.contains("icmp-")
+}
+
+impl FirewallBackend for Firewalld {
+    fn name(&self) -> &'static str {
+        "firewalld"
+    }
+
+    fn tools(&self) -> &'static [&'static str] {
+        &["firewall-cmd"]
+    }
+
+    fn rules_template(&self) -> String {
+        FIREWALLD_RULES_TEMPLATE.to_string()
+    }
+
+    fn variables(&self) -> Vec<(&'static str, String)> {
+        vec![("{zone}", self.zone.clone())]
+    }
+
+    fn format_ports(&self, ports: &[u16]) -> String {
+        let ports: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+        ports.join(",")
+    }
+
+    fn responder_rules(&self, excluded_ports: &[u16], responder: &ResponderOptions) -> String {
+        let mut rules = String::from("# Redirect probes to the responder\n");
+        for protocol in redirect_protocols(responder) {
+            for range in ranges_excluding(excluded_ports) {
+                rules.push_str(&format!(
+                    "--zone={} --add-forward-port=port={}:proto={}:toport={}\n",
+                    self.zone, range, protocol, responder.port
+                ));
+            }
+        }
+        rules
+    }
+
+    /// firewalld has no check mode, so this only makes sure teardown can undo the rules.
+    fn check(&self, rules: &str) -> io::Result<()> {
+        for command in Self::commands(rules) {
+            if !command.first().is_some_and(|arg| arg.starts_with("--")) {
+                return Err(io::Error::other(format!(
+                    "'{}' is not a list of firewall-cmd options",
+                    command.join(" ")
+                )));
+            }
+            if command.contains(&"--permanent") {
+                return Err(io::Error::other(format!(
+                    "'{}' changes the permanent configuration, which teardown can't undo",
+                    command.join(" ")
+                )));
+            }
+        }
+        run_command_blocking("firewall-cmd", &["--state"], None).map(|_| ())
+    }
+
+    /// firewall-cmd can't load changes anywhere but the live daemon, so this looks for
+    /// the options that would close the access port instead.
+    fn check_access_port(&self, rules: &str, access_port: u16) -> Result<(), String> {
+        for line in rules
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+        {
+            let option = |name: &str| {
+                line.split_whitespace()
+                    .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+            };
+            let closes = line.split_whitespace().any(|arg| arg == "--panic-on")
+                || option("--set-target")
+                    .is_some_and(|target| !matches!(target, "ACCEPT" | "default"))
+                || option("--remove-port").is_some_and(|ports| {
+                    ports
+                        .strip_suffix("/tcp")
+                        .and_then(|ports| port_in_list(ports, access_port))
+                        == Some(true)
+                })
+                || (access_port == 22 && option("--remove-service") == Some("ssh"))
+                || line
+                    .split_once("--add-rich-rule=")
+                    .is_some_and(|(_, rule)| rich_rule_drops(rule, access_port))
+                || ((option("--add-interface").is_some()
+                    || option("--change-interface").is_some())
+                    && matches!(option("--zone"), Some("drop" | "block")));
+            if closes {
+                return Err(format!(
+                    "'{}' closes tcp port {}, applying the ruleset would lock us out",
+                    line, access_port
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn live_ruleset(&self) -> io::Result<String> {
+        let zone = format!("--zone={}", self.zone);
+        run_command_blocking("firewall-cmd", &[&zone, "--list-all"], None)
+    }
+
+    fn backup(&self) -> io::Result<()> {
+        // The permanent configuration is the backup, only leave a note that it's needed
+        if let Some(parent) = Path::new(FIREWALLD_MARKER_PATH).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(FIREWALLD_MARKER_PATH, &self.zone)
+    }
+
+    fn apply(&self, rules: &str) -> io::Result<()> {
+        for command in Self::commands(rules) {
+            if let Err(e) = run_command_blocking("firewall-cmd", &command, None) {
+                // Don't leave half a ruleset behind
+                self.restore();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn leftovers(&self) -> Vec<String> {
+        if Path::new(FIREWALLD_MARKER_PATH).exists() {
+            vec![format!(
+                "firewalld runtime changes ({})",
+                FIREWALLD_MARKER_PATH
+            )]
+        } else {
+            vec![]
+        }
+    }
+
+    fn restore(&self) {
+        match run_command_blocking("firewall-cmd", &["--reload"], None) {
+            Ok(_) => {
+                println!("Reloaded the permanent firewalld configuration");
+                if let Err(e) = std::fs::remove_file(FIREWALLD_MARKER_PATH) {
+                    eprintln!("Failed to remove {}: {}", FIREWALLD_MARKER_PATH, e);
+                }
+            }
+            Err(e) => eprintln!("Failed to reload firewalld: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_skip_excluded_ports() {
+        assert_eq!(ranges_excluding(&[22]), vec!["1-21", "23-65535"]);
+        assert_eq!(
+            ranges_excluding(&[443, 22, 24, 22]),
+            vec!["1-21", "23", "25-442", "444-65535"]
+        );
+        assert_eq!(ranges_excluding(&[1, 2, 65535]), vec!["3-65534"]);
+        assert_eq!(ranges_excluding(&[65534]), vec!["1-65533", "65535"]);
+        assert_eq!(ranges_excluding(&[]), vec!["1-65535"]);
+    }
+}
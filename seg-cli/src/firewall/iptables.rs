@@ -0,0 +1,371 @@
+use super::access::{port_in_list, AccessRule, AccessRuleset, Action, Matches};
+use super::{redirect_protocols, FirewallBackend};
+use crate::consts::{
+    CAP_NET_ADMIN, CAP_NET_RAW, IPTABLES_BACKUP_PATH, IPTABLES_RESPONDER_TEMPLATE,
+    IPTABLES_RULES_TEMPLATE, SEG_INPUT_CHAIN, SEG_PREROUTING_CHAIN,
+};
+use crate::responder::ResponderOptions;
+use crate::util::*;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Puts seg's rules in the `SEG_INPUT` and `SEG_PREROUTING` chains, jumped to from the
+/// top of `INPUT` and `PREROUTING`. Works with both legacy and nft-backed iptables.
+pub struct Iptables;
+
+/// Drops the comments and packet counters `iptables-save` adds, which change on
+/// every call and would otherwise show up in diffs.
+fn normalize_save(ruleset: &str) -> String {
+    ruleset
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| match line.strip_prefix(':') {
+            Some(chain) => format!(":{}", chain.split(" [").next().unwrap_or(chain)),
+            None => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Applies `rules` on top of a copy of the live ruleset in a throwaway network
+/// namespace and returns what `iptables-save` makes of the result there.
+fn in_namespace(rules: &str) -> io::Result<String> {
+    let mut live = tempfile::NamedTempFile::new()?;
+    live.write_all(run_command_blocking("iptables-save", &[], None)?.as_bytes())?;
+    let script = format!(
+        "iptables-restore '{}' && iptables-restore --noflush && iptables-save",
+        live.path().display()
+    );
+
+    run_command_blocking(
+        "unshare",
+        &["--net", "sh", "-c", &script],
+        Some(rules.to_string()),
+    )
+}
+
+/// Splits a rule into its arguments, keeping quoted comments in one piece.
+fn split_args(rule: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut quoted = false;
+    for c in rule.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+    args
+}
+
+/// Reads the rules appended to a chain, `-A CHAIN ...` without the `-A CHAIN`.
+fn access_rule(args: &[String], chains: &AccessRuleset, port: u16) -> AccessRule {
+    let mut rule = AccessRule {
+        matches: Matches::Always,
+        action: Action::Continue,
+    };
+    let mut negated = false;
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        let matches = match arg.as_str() {
+            "!" => {
+                negated = true;
+                continue;
+            }
+            "-p" | "--protocol" => {
+                let protocol = args.next().map(String::as_str).unwrap_or_default();
+                Matches::from_bool(matches!(protocol, "tcp" | "6" | "all"))
+            }
+            "--dport" | "--destination-port" | "--dports" | "--destination-ports" => {
+                Matches::from_option(port_in_list(args.next().map_or("", String::as_str), port))
+            }
+            // Match modules and comments don't decide anything by themselves
+            "-m" | "--match" | "--comment" => {
+                args.next();
+                continue;
+            }
+            "-j" | "--jump" | "-g" | "--goto" => {
+                let target = args.next().cloned().unwrap_or_default();
+                rule.action = match target.as_str() {
+                    "ACCEPT" => Action::Accept,
+                    "DROP" | "REJECT" => Action::Drop,
+                    "RETURN" => Action::Return,
+                    _ if !chains.chains.contains_key(&target) => Action::Continue,
+                    _ if arg == "-g" || arg == "--goto" => Action::Goto(target),
+                    _ => Action::Jump(target),
+                };
+                // Whatever follows are the target's own options
+                break;
+            }
+            _ => {
+                // Addresses, interfaces, states, ...: skip the option's values
+                while args
+                    .next_if(|arg| !arg.starts_with('-') && *arg != "!")
+                    .is_some()
+                {}
+                Matches::Maybe
+            }
+        };
+        rule.matches = rule
+            .matches
+            .and(if negated { matches.not() } else { matches });
+        negated = false;
+    }
+    rule
+}
+
+/// Reads the filter table of `iptables-save` output into what the access port check
+/// needs.
+fn access_ruleset(save: &str, port: u16) -> AccessRuleset {
+    let mut access = AccessRuleset {
+        base_chains: vec!["INPUT".to_string()],
+        ..Default::default()
+    };
+    let mut in_filter = false;
+    for line in save.lines().map(str::trim) {
+        if let Some(table) = line.strip_prefix('*') {
+            in_filter = table == "filter";
+        } else if !in_filter {
+            continue;
+        } else if let Some(chain) = line.strip_prefix(':') {
+            let mut fields = chain.split_whitespace();
+            let name = fields.next().unwrap_or_default().to_string();
+            let policy_drops = fields.next() == Some("DROP");
+            access.chains.entry(name).or_default().policy_drops = policy_drops;
+        } else if let Some(rule) = line.strip_prefix("-A ") {
+            let args = split_args(rule);
+            let Some((chain, args)) = args.split_first() else {
+                continue;
+            };
+            let rule = access_rule(args, &access, port);
+            access
+                .chains
+                .entry(chain.clone())
+                .or_default()
+                .rules
+                .push(rule);
+        }
+    }
+    access
+}
+
+/// Removes seg's chains and the jumps to them, ignoring whatever is already gone.
+fn remove_seg_chains() {
+    for (table, hook, chain) in [
+        ("filter", "INPUT", SEG_INPUT_CHAIN),
+        ("nat", "PREROUTING", SEG_PREROUTING_CHAIN),
+    ] {
+        let jump = ["-t", table, "-D", hook, "-j", chain];
+        while run_command_blocking("iptables", &jump, None).is_ok() {}
+        let _ = run_command_blocking("iptables", &["-t", table, "-F", chain], None);
+        let _ = run_command_blocking("iptables", &["-t", table, "-X", chain], None);
+    }
+}
+
+impl FirewallBackend for Iptables {
+    fn name(&self) -> &'static str {
+        "iptables"
+    }
+
+    fn tools(&self) -> &'static [&'static str] {
+        &["iptables", "iptables-save", "iptables-restore"]
+    }
+
+    /// Legacy iptables talks to the kernel through a raw socket.
+    fn capabilities(&self) -> &'static [u32] {
+        &[CAP_NET_ADMIN, CAP_NET_RAW]
+    }
+
+    fn rules_template(&self) -> String {
+        IPTABLES_RULES_TEMPLATE.to_string()
+    }
+
+    fn format_ports(&self, ports: &[u16]) -> String {
+        let ports: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+        ports.join(",")
+    }
+
+    /// Excluded ports return first, `! --dport` only takes one port and multiport at
+    /// most 15.
+    fn responder_rules(&self, excluded_ports: &[u16], responder: &ResponderOptions) -> String {
+        let mut redirects = vec![];
+        for protocol in redirect_protocols(responder) {
+            for port in excluded_ports {
+                redirects.push(format!(
+                    "-A {} -p {} --dport {} -j RETURN",
+                    SEG_PREROUTING_CHAIN, protocol, port
+                ));
+            }
+            redirects.push(format!(
+                "-A {} -p {} -j REDIRECT --to-ports {}",
+                SEG_PREROUTING_CHAIN, protocol, responder.port
+            ));
+        }
+
+        IPTABLES_RESPONDER_TEMPLATE.replace("{redirects}", &redirects.join("\n"))
+    }
+
+    fn check(&self, rules: &str) -> io::Result<()> {
+        run_command_blocking(
+            "iptables-restore",
+            &["--test", "--noflush"],
+            Some(rules.to_string()),
+        )
+        .map(|_| ())
+    }
+
+    fn live_ruleset(&self) -> io::Result<String> {
+        run_command_blocking("iptables-save", &[], None).map(|ruleset| normalize_save(&ruleset))
+    }
+
+    fn check_access_port(&self, rules: &str, access_port: u16) -> Result<(), String> {
+        let save = in_namespace(rules).map_err(|e| {
+            format!(
+                "couldn't load the ruleset in a network namespace to check it: {}",
+                e
+            )
+        })?;
+        access_ruleset(&save, access_port).check(access_port)
+    }
+
+    fn simulate(&self, rules: &str) -> Option<String> {
+        in_namespace(rules)
+            .ok()
+            .map(|ruleset| normalize_save(&ruleset))
+    }
+
+    fn backup(&self) -> io::Result<()> {
+        if Path::new(IPTABLES_BACKUP_PATH).exists() {
+            println!(
+                "Keeping existing ruleset backup {} from a previous run",
+                IPTABLES_BACKUP_PATH
+            );
+            return Ok(());
+        }
+
+        let ruleset = run_command_blocking("iptables-save", &[], None)?;
+        if let Some(parent) = Path::new(IPTABLES_BACKUP_PATH).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(IPTABLES_BACKUP_PATH, ruleset)
+    }
+
+    fn apply(&self, rules: &str) -> io::Result<()> {
+        run_command_blocking("iptables-restore", &["--noflush"], Some(rules.to_string()))
+            .map(|_| ())
+    }
+
+    fn leftovers(&self) -> Vec<String> {
+        let mut found = vec![];
+        for (table, chain) in [("filter", SEG_INPUT_CHAIN), ("nat", SEG_PREROUTING_CHAIN)] {
+            if run_command_blocking("iptables", &["-t", table, "-S", chain], None).is_ok() {
+                found.push(format!("iptables chain {} in table {}", chain, table));
+            }
+        }
+        if Path::new(IPTABLES_BACKUP_PATH).exists() {
+            found.push(format!("saved ruleset {}", IPTABLES_BACKUP_PATH));
+        }
+        found
+    }
+
+    fn restore(&self) {
+        if let Ok(previous) = std::fs::read_to_string(IPTABLES_BACKUP_PATH) {
+            match run_command_blocking("iptables-restore", &[], Some(previous)) {
+                Ok(_) => {
+                    println!("Restored previous ruleset from {}", IPTABLES_BACKUP_PATH);
+                    if let Err(e) = std::fs::remove_file(IPTABLES_BACKUP_PATH) {
+                        eprintln!("Failed to remove {}: {}", IPTABLES_BACKUP_PATH, e);
+                    }
+                }
+                Err(e) => eprintln!(
+                    "Failed to restore previous ruleset, it is kept in {}: {}",
+                    IPTABLES_BACKUP_PATH, e
+                ),
+            }
+        }
+
+        // The backup only covers tables that existed when it was taken, nat may not have
+        remove_seg_chains();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(save: &str, port: u16) -> Result<(), String> {
+        access_ruleset(save, port).check(port)
+    }
+
+    #[test]
+    fn port_ranges_and_multiport() {
+        let save = "*filter\n:INPUT DROP [0:0]\n:FORWARD ACCEPT [0:0]\n\
+            -A INPUT -p tcp -m tcp --dport 1:1024 -j ACCEPT\n\
+            -A INPUT -p tcp -m multiport --dports 80,2000:3000 -j ACCEPT\nCOMMIT";
+        assert_eq!(check(save, 22), Ok(()));
+        assert_eq!(check(save, 2222), Ok(()));
+        assert!(check(save, 8080).is_err());
+    }
+
+    #[test]
+    fn drops_in_jumped_chains() {
+        let save = "*filter\n:INPUT ACCEPT [0:0]\n:BLOCKED - [0:0]\n\
+            -A INPUT -m comment --comment \"drop everything\" -j BLOCKED\n\
+            -A BLOCKED -j REJECT --reject-with icmp-port-unreachable\nCOMMIT";
+        assert!(check(save, 22).unwrap_err().contains("chain INPUT"));
+
+        let save = "*filter\n:INPUT ACCEPT [0:0]\n\
+            -A INPUT -p udp -j DROP\n-A INPUT -p tcp ! --dport 22 -j DROP\nCOMMIT";
+        assert_eq!(check(save, 22), Ok(()));
+        assert!(check(save, 2222).is_err());
+    }
+
+    #[test]
+    fn conditional_accept_does_not_count() {
+        let save = "*filter\n:INPUT DROP [0:0]\n\
+            -A INPUT -s 10.0.0.1/32 -p tcp --dport 22 -j ACCEPT\n\
+            -A INPUT -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT\nCOMMIT";
+        assert!(check(save, 22).is_err());
+    }
+
+    #[test]
+    fn only_filter_table() {
+        let save = "*nat\n:PREROUTING ACCEPT [0:0]\n-A PREROUTING -j DROP\nCOMMIT\n\
+            *filter\n:INPUT ACCEPT [0:0]\nCOMMIT";
+        assert_eq!(check(save, 22), Ok(()));
+    }
+
+    #[test]
+    fn responder_skips_excluded_ports() {
+        let responder = ResponderOptions {
+            port: 9999,
+            protocol: crate::network::ScanProtocol::BOTH,
+            udp_reply: crate::responder::UdpReply::Echo,
+            udp_payload: None,
+        };
+        let rules = Iptables.responder_rules(&[22, 53], &responder);
+        let redirects: Vec<&str> = rules
+            .lines()
+            .filter(|line| line.starts_with("-A SEG_PREROUTING"))
+            .collect();
+        assert_eq!(
+            redirects,
+            vec![
+                "-A SEG_PREROUTING -p tcp --dport 22 -j RETURN",
+                "-A SEG_PREROUTING -p tcp --dport 53 -j RETURN",
+                "-A SEG_PREROUTING -p tcp -j REDIRECT --to-ports 9999",
+                "-A SEG_PREROUTING -p udp --dport 22 -j RETURN",
+                "-A SEG_PREROUTING -p udp --dport 53 -j RETURN",
+                "-A SEG_PREROUTING -p udp -j REDIRECT --to-ports 9999",
+            ]
+        );
+    }
+}
@@ -0,0 +1,923 @@
+use super::access::{AccessRule, AccessRuleset, Action, Matches};
+use super::{redirect_protocols, render_template, FirewallBackend, RuleVariables};
+use crate::consts::{RULESET_BACKUP_PATH, SEG_TABLE};
+use crate::responder::ResponderOptions;
+use crate::util::*;
+use data::RuleCounter;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Keeps all of seg's rules in the `ip seg` table. The default ruleset is built as nft
+/// JSON and flushes everything else, so the whole ruleset is saved beforehand and
+/// restored on teardown. Custom rulesets can be nft JSON or the text syntax.
+pub struct Nftables;
+
+/// A failed nft invocation.
+#[derive(Debug)]
+pub enum NftError {
+    /// nft couldn't be run.
+    Io(io::Error),
+    /// nft rejected the input, one message per error it reported.
+    Rejected(Vec<String>),
+    /// nft's JSON output wasn't what we expected.
+    Output(String),
+}
+
+impl fmt::Display for NftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NftError::Io(e) => write!(f, "failed to run nft: {}", e),
+            NftError::Rejected(errors) => {
+                write!(f, "nft rejected the ruleset: {}", errors.join("; "))
+            }
+            NftError::Output(message) => write!(f, "unexpected nft output: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for NftError {}
+
+impl From<NftError> for io::Error {
+    fn from(e: NftError) -> Self {
+        match e {
+            NftError::Io(e) => io::Error::new(e.kind(), NftError::Io(e)),
+            e => io::Error::other(e),
+        }
+    }
+}
+
+/// Runs nft with `input` on stdin and returns its stdout. JSON input is detected and
+/// passed with `-j`.
+fn run_nft(args: &[&str], input: Option<&str>) -> Result<String, NftError> {
+    let mut command = Command::new("nft");
+    if input.is_some_and(|input| input.trim_start().starts_with('{')) {
+        command.arg("-j");
+    }
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(NftError::Io)?;
+
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        stdin.write_all(input.as_bytes()).map_err(NftError::Io)?;
+    }
+
+    let output = child.wait_with_output().map_err(NftError::Io)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut errors: Vec<String> = stderr
+            .lines()
+            .filter_map(|line| line.strip_prefix("Error:"))
+            .map(|line| line.trim().to_string())
+            .collect();
+        if errors.is_empty() {
+            errors.push(format!("{} ({})", stderr.trim(), output.status));
+        }
+        return Err(NftError::Rejected(errors));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `<protocol> dport <op> <right>`
+fn match_dport(protocol: &str, op: &str, right: Value) -> Value {
+    json!({"match": {
+        "op": op,
+        "left": {"payload": {"protocol": protocol, "field": "dport"}},
+        "right": right,
+    }})
+}
+
+fn rule(chain: &str, comment: &str, expr: Vec<Value>) -> Value {
+    json!({"add": {"rule": {
+        "family": "ip",
+        "table": SEG_TABLE,
+        "chain": chain,
+        "comment": comment,
+        "expr": expr,
+    }}})
+}
+
+fn counter() -> Value {
+    json!({"counter": {"packets": 0, "bytes": 0}})
+}
+
+/// seg's table and its input chain, accepting everything so the capture sees it.
+/// `access_port` is a number, or the `"{access_port}"` placeholder for the template.
+fn base_commands(access_port: &Value) -> Vec<Value> {
+    vec![
+        json!({"flush": {"ruleset": null}}),
+        json!({"add": {"table": {"family": "ip", "name": SEG_TABLE}}}),
+        json!({"add": {"chain": {
+            "family": "ip", "table": SEG_TABLE, "name": "input",
+            "type": "filter", "hook": "input", "prio": 0, "policy": "accept",
+        }}}),
+        // Always accept listener port traffic
+        rule(
+            "input",
+            "access port",
+            vec![
+                match_dport("tcp", "==", access_port.clone()),
+                counter(),
+                json!({"accept": null}),
+            ],
+        ),
+        rule(
+            "input",
+            "tcp",
+            vec![
+                match_dport("tcp", "!=", access_port.clone()),
+                counter(),
+                json!({"accept": null}),
+            ],
+        ),
+        rule(
+            "input",
+            "udp",
+            vec![
+                match_dport("udp", "==", json!({"range": [1, 65535]})),
+                counter(),
+                json!({"accept": null}),
+            ],
+        ),
+    ]
+}
+
+/// Redirects probes to the responder, the kernel answers them for us. Probes to
+/// `excluded_ports` go through untouched.
+fn responder_commands(excluded_ports: &[u16], responder: &ResponderOptions) -> Vec<Value> {
+    let excluded = match excluded_ports {
+        [port] => json!(port),
+        ports => json!({ "set": ports }),
+    };
+    let mut commands = vec![
+        json!({"add": {"table": {"family": "ip", "name": SEG_TABLE}}}),
+        json!({"add": {"chain": {
+            "family": "ip", "table": SEG_TABLE, "name": "prerouting",
+            "type": "nat", "hook": "prerouting", "prio": -100, "policy": "accept",
+        }}}),
+    ];
+    for protocol in redirect_protocols(responder) {
+        commands.push(rule(
+            "prerouting",
+            &format!("responder {}", protocol),
+            vec![
+                match_dport(protocol, "!=", excluded.clone()),
+                counter(),
+                json!({"redirect": {"port": responder.port}}),
+            ],
+        ));
+    }
+    commands
+}
+
+/// The built-in ruleset as nft JSON commands, what's applied without `--rules`.
+fn default_ruleset(variables: &RuleVariables, responder: Option<&ResponderOptions>) -> Vec<Value> {
+    let mut commands = vec![json!({"metainfo": {"json_schema_version": 1}})];
+    commands.extend(base_commands(&json!(variables.access_port)));
+    if let Some(responder) = responder {
+        commands.extend(responder_commands(&variables.excluded_ports, responder));
+    }
+    commands
+}
+
+/// Fills in a custom ruleset in nft JSON. `{excluded_ports}` becomes a JSON set and the
+/// responder redirects are added to the `nftables` array as commands.
+fn render_json(
+    rules: &str,
+    variables: &RuleVariables,
+    responder: Option<&ResponderOptions>,
+) -> Result<String, String> {
+    let ports = json!({ "set": variables.excluded_ports });
+    let rules = variables.apply(
+        &rules.replace("{excluded_ports}", &ports.to_string()),
+        &Nftables,
+    );
+    let Some(responder) = responder else {
+        return Ok(rules);
+    };
+    let mut ruleset: Value =
+        serde_json::from_str(&rules).map_err(|e| format!("not valid nft JSON: {}", e))?;
+    let commands = ruleset
+        .get_mut("nftables")
+        .and_then(Value::as_array_mut)
+        .ok_or("nft JSON must be an object with an \"nftables\" array")?;
+    commands.extend(responder_commands(&variables.excluded_ports, responder));
+    Ok(ruleset_json(commands))
+}
+
+/// One command per line, which keeps the ruleset readable in `--dry-run` output.
+fn ruleset_json(commands: &[Value]) -> String {
+    let commands: Vec<String> = commands.iter().map(Value::to_string).collect();
+    format!("{{\"nftables\": [\n  {}\n]}}\n", commands.join(",\n  "))
+}
+
+/// The right-hand side of a match in nft syntax.
+fn text_value(value: &Value) -> String {
+    if let Some([first, last]) = value
+        .get("range")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+    {
+        return format!("{}-{}", text_value(first), text_value(last));
+    }
+    if let Some(items) = value.get("set").and_then(Value::as_array) {
+        let items: Vec<String> = items.iter().map(text_value).collect();
+        return format!("{{ {} }}", items.join(", "));
+    }
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// One statement of a rule in nft syntax. Covers what seg's own rules use.
+fn text_statement(name: &str, value: &Value) -> String {
+    match name {
+        "match" => {
+            let left = &value["left"];
+            let left = match (left.get("payload"), left.get("meta")) {
+                (Some(payload), _) => format!(
+                    "{} {}",
+                    text_value(&payload["protocol"]),
+                    text_value(&payload["field"])
+                ),
+                (None, Some(meta)) => format!("meta {}", text_value(&meta["key"])),
+                (None, None) => text_value(left),
+            };
+            match value["op"].as_str().unwrap_or("==") {
+                "==" => format!("{} {}", left, text_value(&value["right"])),
+                op => format!("{} {} {}", left, op, text_value(&value["right"])),
+            }
+        }
+        "redirect" => format!("redirect to :{}", value["port"]),
+        "jump" | "goto" => format!("{} {}", name, text_value(&value["target"])),
+        name => name.to_string(),
+    }
+}
+
+/// A chain in nft syntax: its name, type declaration and rules.
+type TextChain = (String, String, Vec<String>);
+
+/// Renders nft JSON commands as an `nft -f` ruleset, with the chains and rules of
+/// each table in one block.
+fn ruleset_text(commands: &[Value]) -> String {
+    let mut text = String::new();
+    let mut tables: Vec<(String, Vec<TextChain>)> = vec![];
+    for command in commands {
+        if command["flush"].get("ruleset").is_some() {
+            text.push_str("flush ruleset\n\n");
+            continue;
+        }
+        let Some(add) = command.get("add") else {
+            continue;
+        };
+
+        if let Some(table) = add.get("table") {
+            let name = format!(
+                "{} {}",
+                text_value(&table["family"]),
+                text_value(&table["name"])
+            );
+            if !tables.iter().any(|(existing, _)| *existing == name) {
+                tables.push((name, vec![]));
+            }
+            continue;
+        }
+        let (Some(object), is_chain) = (
+            add.get("chain").or(add.get("rule")),
+            add.get("chain").is_some(),
+        ) else {
+            continue;
+        };
+        let table = format!(
+            "{} {}",
+            text_value(&object["family"]),
+            text_value(&object["table"])
+        );
+        let Some((_, chains)) = tables.iter_mut().find(|(name, _)| *name == table) else {
+            continue;
+        };
+
+        if is_chain {
+            let declaration = match object["hook"].as_str() {
+                Some(hook) => format!(
+                    "type {} hook {} priority {}; policy {};",
+                    text_value(&object["type"]),
+                    hook,
+                    object["prio"],
+                    object["policy"].as_str().unwrap_or("accept")
+                ),
+                None => String::new(),
+            };
+            chains.push((text_value(&object["name"]), declaration, vec![]));
+        } else if let Some((_, _, rules)) = chains
+            .iter_mut()
+            .find(|(name, _, _)| *name == text_value(&object["chain"]))
+        {
+            let mut statements: Vec<String> = object["expr"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|expr| expr.as_object()?.iter().next())
+                .map(|(name, value)| text_statement(name, value))
+                .collect();
+            if let Some(comment) = object["comment"].as_str() {
+                statements.push(format!("comment \"{}\"", comment));
+            }
+            rules.push(statements.join(" "));
+        }
+    }
+
+    for (table, chains) in tables {
+        text.push_str(&format!("table {} {{\n", table));
+        for (chain, declaration, rules) in chains {
+            text.push_str(&format!("    chain {} {{\n", chain));
+            for line in std::iter::once(declaration).chain(rules) {
+                if !line.is_empty() {
+                    text.push_str(&format!("        {}\n", line));
+                }
+            }
+            text.push_str("    }\n");
+        }
+        text.push_str("}\n");
+    }
+    text
+}
+
+/// Reads the counters out of `nft -j list table` output.
+fn parse_counters(listing: &str) -> Result<Vec<RuleCounter>, NftError> {
+    let listing: Value =
+        serde_json::from_str(listing).map_err(|e| NftError::Output(e.to_string()))?;
+    let objects = listing["nftables"]
+        .as_array()
+        .ok_or_else(|| NftError::Output("no nftables array".to_string()))?;
+
+    Ok(objects
+        .iter()
+        .filter_map(|object| object.get("rule"))
+        .filter_map(|rule| {
+            let counter = rule["expr"]
+                .as_array()?
+                .iter()
+                .find_map(|expr| expr.get("counter"))?;
+            Some(RuleCounter {
+                chain: rule["chain"].as_str().unwrap_or_default().to_string(),
+                rule: match rule["comment"].as_str() {
+                    Some(comment) => comment.to_string(),
+                    None => format!("handle {}", rule["handle"]),
+                },
+                packets: counter["packets"].as_u64().unwrap_or(0),
+                bytes: counter["bytes"].as_u64().unwrap_or(0),
+            })
+        })
+        .collect())
+}
+
+/// Whether `right` holds the access port: a number, a range or a set of them.
+fn holds_port(right: &Value, port: u16) -> Option<bool> {
+    if let Some(items) = right.get("set").and_then(Value::as_array) {
+        let found: Vec<Option<bool>> = items.iter().map(|item| holds_port(item, port)).collect();
+        if found.contains(&Some(true)) {
+            return Some(true);
+        }
+        return found
+            .iter()
+            .all(|found| *found == Some(false))
+            .then_some(false);
+    }
+    if let Some([first, last]) = right
+        .get("range")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+    {
+        return Some((first.as_u64()?..=last.as_u64()?).contains(&u64::from(port)));
+    }
+    match right {
+        Value::Number(number) => Some(number.as_u64()? == u64::from(port)),
+        Value::String(text) => Some(text.parse::<u16>().ok()? == port),
+        _ => None,
+    }
+}
+
+/// Whether `right` holds TCP, as a protocol name or number, or a set of them.
+fn holds_tcp(right: &Value) -> Option<bool> {
+    if let Some(items) = right.get("set").and_then(Value::as_array) {
+        let found: Vec<Option<bool>> = items.iter().map(holds_tcp).collect();
+        if found.contains(&Some(true)) {
+            return Some(true);
+        }
+        return found
+            .iter()
+            .all(|found| *found == Some(false))
+            .then_some(false);
+    }
+    match right {
+        Value::Number(number) => Some(number.as_u64()? == 6),
+        Value::String(text) => Some(text == "tcp"),
+        _ => None,
+    }
+}
+
+/// Evaluates a `match` expression for a new TCP connection to the access port.
+fn match_access_port(expr: &Value, port: u16) -> Matches {
+    let left = &expr["left"];
+    let right = &expr["right"];
+    let holds = if let Some(payload) = left.get("payload") {
+        match (payload["protocol"].as_str(), payload["field"].as_str()) {
+            (Some("tcp"), Some("dport")) => holds_port(right, port),
+            (Some("tcp"), _) => None,
+            (Some("ip"), Some("protocol")) => holds_tcp(right),
+            (Some("ip"), _) | (None, _) => None,
+            // Matching another protocol's header only matches packets of that protocol
+            (Some(_), _) => return Matches::Never,
+        }
+    } else if left["meta"]["key"] == "l4proto" {
+        holds_tcp(right)
+    } else {
+        None
+    };
+
+    match expr["op"].as_str().unwrap_or("==") {
+        "==" | "in" => Matches::from_option(holds),
+        "!=" => Matches::from_option(holds).not(),
+        _ => Matches::Maybe,
+    }
+}
+
+/// Reads `nft -j list ruleset` output, or nft JSON commands such as seg's default
+/// ruleset, into what the access port check needs. Only IPv4 input chains count.
+fn access_ruleset(ruleset: &str, port: u16) -> Result<AccessRuleset, String> {
+    let ruleset: Value =
+        serde_json::from_str(ruleset).map_err(|e| format!("unreadable nft JSON: {}", e))?;
+    let objects = ruleset["nftables"]
+        .as_array()
+        .ok_or_else(|| "no nftables array in the nft JSON".to_string())?;
+
+    let mut access = AccessRuleset::default();
+    for object in objects {
+        let Some((command, object)) = object.as_object().and_then(|object| object.iter().next())
+        else {
+            continue;
+        };
+        // Commands wrap the objects a listing has, `{"add": {"rule": ...}}`
+        let (command, object) = match command.as_str() {
+            "add" | "create" | "insert" | "flush" => {
+                match object.as_object().and_then(|object| object.iter().next()) {
+                    Some((kind, object)) => (command.as_str(), (kind.as_str(), object)),
+                    None => continue,
+                }
+            }
+            kind => ("list", (kind, object)),
+        };
+        let (kind, object) = object;
+        if command == "flush" {
+            if kind == "ruleset" {
+                access = AccessRuleset::default();
+            }
+            continue;
+        }
+        if !matches!(object["family"].as_str(), Some("ip" | "inet")) {
+            continue;
+        }
+        let table = object["table"].as_str().unwrap_or_default();
+
+        match kind {
+            "chain" => {
+                let name = format!(
+                    "{} {} {}",
+                    object["family"].as_str().unwrap_or_default(),
+                    table,
+                    object["name"].as_str().unwrap_or_default()
+                );
+                if object["hook"] == "input"
+                    && object["type"] == "filter"
+                    && !access.base_chains.contains(&name)
+                {
+                    access.base_chains.push(name.clone());
+                }
+                let chain = access.chains.entry(name).or_default();
+                if let Some(policy) = object["policy"].as_str() {
+                    chain.policy_drops = policy == "drop";
+                }
+            }
+            "rule" => {
+                let prefix = format!(
+                    "{} {}",
+                    object["family"].as_str().unwrap_or_default(),
+                    table
+                );
+                let mut rule = AccessRule {
+                    matches: Matches::Always,
+                    action: Action::Continue,
+                };
+                for expr in object["expr"].as_array().into_iter().flatten() {
+                    let Some((name, value)) = expr.as_object().and_then(|e| e.iter().next()) else {
+                        continue;
+                    };
+                    let target = || {
+                        format!(
+                            "{} {}",
+                            prefix,
+                            value["target"].as_str().unwrap_or_default()
+                        )
+                    };
+                    match name.as_str() {
+                        "match" => rule.matches = rule.matches.and(match_access_port(value, port)),
+                        // Rate limits and quotas stop matching at some point
+                        "limit" | "quota" => rule.matches = rule.matches.and(Matches::Maybe),
+                        "accept" => rule.action = Action::Accept,
+                        "drop" | "reject" => rule.action = Action::Drop,
+                        "return" => rule.action = Action::Return,
+                        "jump" => rule.action = Action::Jump(target()),
+                        "goto" => rule.action = Action::Goto(target()),
+                        _ => {}
+                    }
+                }
+
+                let chain = format!(
+                    "{} {}",
+                    prefix,
+                    object["chain"].as_str().unwrap_or_default()
+                );
+                let rules = &mut access.chains.entry(chain).or_default().rules;
+                if command == "insert" {
+                    rules.insert(0, rule);
+                } else {
+                    rules.push(rule);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(access)
+}
+
+/// Applies `rules` on top of a copy of the live ruleset in a throwaway network
+/// namespace and returns the output of `list`, e.g. `nft list ruleset`. This gives
+/// nft's own rendering of the result, which diffs cleanly against the live ruleset.
+fn in_namespace(rules: &str, list: &str) -> io::Result<String> {
+    let mut live = tempfile::NamedTempFile::new()?;
+    live.write_all(run_nft(&["list", "ruleset"], None)?.as_bytes())?;
+    let json = if rules.trim_start().starts_with('{') {
+        "-j "
+    } else {
+        ""
+    };
+    let script = format!(
+        "nft -f '{}' && nft {}-f - && {}",
+        live.path().display(),
+        json,
+        list
+    );
+
+    run_command_blocking(
+        "unshare",
+        &["--net", "sh", "-c", &script],
+        Some(rules.to_string()),
+    )
+}
+
+impl FirewallBackend for Nftables {
+    fn name(&self) -> &'static str {
+        "nftables"
+    }
+
+    fn tools(&self) -> &'static [&'static str] {
+        &["nft"]
+    }
+
+    /// The default ruleset in nft syntax, rendered from the JSON that's applied.
+    fn rules_template(&self) -> String {
+        ruleset_text(&base_commands(&json!("{access_port}")))
+    }
+
+    fn default_rules(
+        &self,
+        variables: &RuleVariables,
+        responder: Option<&ResponderOptions>,
+    ) -> String {
+        ruleset_json(&default_ruleset(variables, responder))
+    }
+
+    /// JSON rules get their responder redirects as JSON, text rules as text.
+    fn custom_rules(
+        &self,
+        rules: &str,
+        variables: &RuleVariables,
+        responder: Option<&ResponderOptions>,
+    ) -> Result<String, String> {
+        if rules.trim_start().starts_with('{') {
+            render_json(rules, variables, responder)
+        } else {
+            Ok(render_template(self, rules, variables, responder))
+        }
+    }
+
+    fn responder_rules(&self, excluded_ports: &[u16], responder: &ResponderOptions) -> String {
+        ruleset_text(&responder_commands(excluded_ports, responder))
+    }
+
+    fn check(&self, rules: &str) -> io::Result<()> {
+        run_nft(&["-c", "-f", "-"], Some(rules))?;
+        Ok(())
+    }
+
+    fn live_ruleset(&self) -> io::Result<String> {
+        Ok(run_nft(&["list", "ruleset"], None)?)
+    }
+
+    fn check_access_port(&self, rules: &str, access_port: u16) -> Result<(), String> {
+        // What the host would end up with, including tables seg doesn't own. Without a
+        // namespace, nft JSON such as the default ruleset can still be read as is.
+        let ruleset = match in_namespace(rules, "nft -j list ruleset") {
+            Ok(listing) => listing,
+            Err(_) if rules.trim_start().starts_with('{') => rules.to_string(),
+            Err(e) => {
+                return Err(format!(
+                    "couldn't load the ruleset in a network namespace to check it: {}",
+                    e
+                ))
+            }
+        };
+        access_ruleset(&ruleset, access_port)?.check(access_port)
+    }
+
+    fn simulate(&self, rules: &str) -> Option<String> {
+        in_namespace(rules, "nft list ruleset").ok()
+    }
+
+    fn backup(&self) -> io::Result<()> {
+        if Path::new(RULESET_BACKUP_PATH).exists() {
+            println!(
+                "Keeping existing ruleset backup {} from a previous run",
+                RULESET_BACKUP_PATH
+            );
+            return Ok(());
+        }
+
+        let ruleset = self.live_ruleset()?;
+        if let Some(parent) = Path::new(RULESET_BACKUP_PATH).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(RULESET_BACKUP_PATH, ruleset)
+    }
+
+    fn apply(&self, rules: &str) -> io::Result<()> {
+        run_nft(&["-f", "-"], Some(rules))?;
+        Ok(())
+    }
+
+    fn leftovers(&self) -> Vec<String> {
+        let mut found = vec![];
+        if run_nft(&["list", "table", "ip", SEG_TABLE], None).is_ok() {
+            found.push(format!("nft table ip {}", SEG_TABLE));
+        }
+        if Path::new(RULESET_BACKUP_PATH).exists() {
+            found.push(format!("saved ruleset {}", RULESET_BACKUP_PATH));
+        }
+        found
+    }
+
+    fn restore(&self) {
+        match std::fs::read_to_string(RULESET_BACKUP_PATH) {
+            Ok(previous) => {
+                // The saved ruleset doesn't flush on its own, and ours flushed everything else
+                let restore = format!("flush ruleset\n{}", previous);
+                match run_nft(&["-f", "-"], Some(&restore)) {
+                    Ok(_) => {
+                        println!("Restored previous ruleset from {}", RULESET_BACKUP_PATH);
+                        if let Err(e) = std::fs::remove_file(RULESET_BACKUP_PATH) {
+                            eprintln!("Failed to remove {}: {}", RULESET_BACKUP_PATH, e);
+                        }
+                    }
+                    Err(e) => eprintln!(
+                        "Failed to restore previous ruleset, it is kept in {}: {}",
+                        RULESET_BACKUP_PATH, e
+                    ),
+                }
+            }
+            Err(_) => {
+                let table = format!("delete table ip {}", SEG_TABLE);
+                if let Err(e) = run_nft(&[&table], None) {
+                    eprintln!("Failed to remove nft rules: {}, {}", table, e);
+                }
+            }
+        }
+    }
+
+    fn counters(&self) -> io::Result<Vec<RuleCounter>> {
+        let listing = run_nft(&["-j", "list", "table", "ip", SEG_TABLE], None)?;
+        Ok(parse_counters(&listing)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An `nft -j list ruleset` listing with one input chain.
+    fn listing(family: &str, policy: &str, rules: Vec<Vec<Value>>) -> String {
+        let mut objects = vec![
+            json!({"table": {"family": family, "name": "filter"}}),
+            json!({"chain": {"family": family, "table": "filter", "name": "input",
+                "type": "filter", "hook": "input", "prio": 0, "policy": policy}}),
+            json!({"chain": {"family": family, "table": "filter", "name": "blocked"}}),
+            json!({"rule": {"family": family, "table": "filter", "chain": "blocked",
+                "expr": [{"drop": null}]}}),
+        ];
+        for expr in rules {
+            objects.push(json!({"rule": {"family": family, "table": "filter",
+                "chain": "input", "expr": expr}}));
+        }
+        json!({ "nftables": objects }).to_string()
+    }
+
+    fn check(ruleset: &str, port: u16) -> Result<(), String> {
+        access_ruleset(ruleset, port).unwrap().check(port)
+    }
+
+    fn variables(access_port: u16, excluded_ports: Vec<u16>) -> RuleVariables {
+        RuleVariables {
+            access_port,
+            interface: "eth0".to_string(),
+            listener_ip: std::net::Ipv4Addr::new(192, 0, 2, 2),
+            excluded_ports,
+        }
+    }
+
+    fn responder() -> ResponderOptions {
+        ResponderOptions {
+            port: 9999,
+            protocol: crate::network::ScanProtocol::BOTH,
+            udp_reply: crate::responder::UdpReply::Echo,
+            udp_payload: None,
+        }
+    }
+
+    #[test]
+    fn default_ruleset_keeps_access_port() {
+        let ruleset =
+            json!({ "nftables": default_ruleset(&variables(2222, vec![2222]), None) }).to_string();
+        assert_eq!(check(&ruleset, 2222), Ok(()));
+    }
+
+    #[test]
+    fn template_is_the_applied_ruleset() {
+        let template = Nftables.rules_template();
+        assert!(template.starts_with("flush ruleset\n\ntable ip seg {\n    chain input {\n"));
+        assert!(template
+            .contains("        tcp dport {access_port} counter accept comment \"access port\"\n"));
+
+        // Every applied rule shows up in the template, in order
+        let rules: Vec<String> = ruleset_text(&default_ruleset(&variables(22, vec![22]), None))
+            .lines()
+            .map(str::to_string)
+            .collect();
+        let template: Vec<String> = template
+            .replace("{access_port}", "22")
+            .lines()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(rules, template);
+    }
+
+    #[test]
+    fn port_ranges_and_sets() {
+        let range = listing(
+            "inet",
+            "drop",
+            vec![vec![
+                match_dport("tcp", "==", json!({"range": [1, 1024]})),
+                json!({"accept": null}),
+            ]],
+        );
+        assert_eq!(check(&range, 22), Ok(()));
+        assert!(check(&range, 2222).is_err());
+
+        let set = listing(
+            "ip",
+            "drop",
+            vec![vec![
+                match_dport("tcp", "==", json!({"set": [80, {"range": [2000, 3000]}]})),
+                json!({"accept": null}),
+            ]],
+        );
+        assert_eq!(check(&set, 2222), Ok(()));
+        assert!(check(&set, 22).is_err());
+    }
+
+    #[test]
+    fn drops_before_accept() {
+        let ruleset = listing(
+            "ip",
+            "accept",
+            vec![
+                vec![json!({"jump": {"target": "blocked"}})],
+                vec![match_dport("tcp", "==", json!(22)), json!({"accept": null})],
+            ],
+        );
+        let error = check(&ruleset, 22).unwrap_err();
+        assert!(error.contains("ip filter input"), "{}", error);
+
+        // A drop of other protocols or other ports doesn't reach the access port
+        let ruleset = listing(
+            "ip",
+            "accept",
+            vec![
+                vec![match_dport("udp", "==", json!(22)), json!({"drop": null})],
+                vec![match_dport("tcp", "!=", json!(22)), json!({"drop": null})],
+            ],
+        );
+        assert_eq!(check(&ruleset, 22), Ok(()));
+    }
+
+    #[test]
+    fn conditional_accept_does_not_count() {
+        let ruleset = listing(
+            "ip",
+            "drop",
+            vec![vec![
+                json!({"match": {"op": "==",
+                    "left": {"payload": {"protocol": "ip", "field": "saddr"}},
+                    "right": "10.0.0.1"}}),
+                match_dport("tcp", "==", json!(22)),
+                json!({"accept": null}),
+            ]],
+        );
+        assert!(check(&ruleset, 22).is_err());
+    }
+
+    #[test]
+    fn ignores_ipv6_and_flushed_tables() {
+        assert_eq!(check(&listing("ip6", "drop", vec![]), 22), Ok(()));
+
+        let mut commands: Value = serde_json::from_str(&listing("ip", "drop", vec![])).unwrap();
+        let objects = commands["nftables"].as_array_mut().unwrap();
+        objects.push(json!({"flush": {"ruleset": null}}));
+        assert_eq!(check(&commands.to_string(), 22), Ok(()));
+    }
+
+    #[test]
+    fn json_rules_get_json_responder_commands() {
+        let variables = variables(22, vec![22, 53]);
+        let responder = responder();
+        let rules = r#"{"nftables": [{"add": {"rule": {"family": "ip", "table": "filter",
+            "chain": "input", "expr": [{"match": {"op": "!=",
+            "left": {"payload": {"protocol": "tcp", "field": "dport"}},
+            "right": {excluded_ports}}}, {"accept": null}]}}}]}"#;
+
+        let rendered = Nftables
+            .custom_rules(rules, &variables, Some(&responder))
+            .unwrap();
+        let rendered: Value = serde_json::from_str(&rendered).unwrap();
+        let commands = rendered["nftables"].as_array().unwrap();
+        assert_eq!(
+            commands[0]["add"]["rule"]["expr"][0]["match"]["right"],
+            json!({"set": [22, 53]})
+        );
+        assert_eq!(
+            commands[1..].to_vec(),
+            responder_commands(&[22, 53], &responder)
+        );
+
+        // Text rules still get text redirects
+        let rendered = Nftables
+            .custom_rules("table ip filter {}\n", &variables, Some(&responder))
+            .unwrap();
+        assert!(rendered.contains("redirect to :9999"));
+
+        assert!(Nftables
+            .custom_rules("{\"nftables\": {}}", &variables, Some(&responder))
+            .is_err());
+    }
+
+    #[test]
+    fn responder_skips_excluded_ports() {
+        let rules = Nftables.responder_rules(&[22], &responder());
+        assert!(rules.contains("tcp dport != 22 counter redirect to :9999"));
+        assert!(rules.contains("udp dport != 22 counter redirect to :9999"));
+
+        let rules = Nftables.responder_rules(&[22, 53, 443], &responder());
+        assert!(rules.contains("tcp dport != { 22, 53, 443 } counter redirect to :9999"));
+        assert!(rules.contains("udp dport != { 22, 53, 443 } counter redirect to :9999"));
+
+        let commands = default_ruleset(&variables(22, vec![22, 53]), Some(&responder()));
+        let redirects: Vec<&Value> = commands
+            .iter()
+            .filter(|command| command["add"]["rule"]["chain"] == "prerouting")
+            .collect();
+        assert_eq!(redirects.len(), 2);
+        for redirect in redirects {
+            assert_eq!(
+                redirect["add"]["rule"]["expr"][0]["match"]["right"],
+                json!({"set": [22, 53]})
+            );
+        }
+    }
+}
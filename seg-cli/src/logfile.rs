@@ -0,0 +1,371 @@
+use crate::aggregate::Aggregator;
+use crate::capture::CaptureStats;
+use crate::consts::LOG_BATCH_SIZE;
+
+use data::*;
+
+use chrono::{NaiveDateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::Receiver;
+
+/// When to roll the active log over into a compressed archive.
+#[derive(Clone, Default)]
+pub struct RotationPolicy {
+    /// Rotate once the active file reaches this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Rotate once the active file has been open this long.
+    pub max_age: Option<Duration>,
+    /// Number of archives to keep, oldest are deleted first. Keeps everything when unset.
+    pub keep: Option<usize>,
+}
+
+/// Listener log file that rotates itself according to a `RotationPolicy`.
+/// Rotated files are renamed to `<stem>.<timestamp>.<ext>` and gzipped in the background.
+pub struct RotatingLog {
+    path: PathBuf,
+    policy: RotationPolicy,
+    writer: BufWriter<File>,
+    written: u64,
+    opened_at: Instant,
+}
+
+/// Expands `{tag}` and `{host}` in a log path template.
+pub fn log_path(template: &str, network_tag: &str) -> PathBuf {
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|host| host.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    PathBuf::from(
+        template
+            .replace("{tag}", &sanitize(network_tag))
+            .replace("{host}", &sanitize(&host)),
+    )
+}
+
+// Tags are free form, keep them from escaping the log directory
+fn sanitize(part: &str) -> String {
+    part.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+impl RotatingLog {
+    pub async fn open(path: PathBuf, policy: RotationPolicy) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await?;
+        let written = file.metadata().await?.len();
+
+        Ok(Self {
+            path,
+            policy,
+            writer: BufWriter::new(file),
+            written,
+            opened_at: Instant::now(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.should_rotate() {
+            self.rotate().await?;
+        }
+
+        self.writer.write_all(buf).await?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush().await
+    }
+
+    fn should_rotate(&self) -> bool {
+        // Never rotate out an empty file
+        if self.written == 0 {
+            return false;
+        }
+
+        self.policy
+            .max_bytes
+            .is_some_and(|max_bytes| self.written >= max_bytes)
+            || self
+                .policy
+                .max_age
+                .is_some_and(|max_age| self.opened_at.elapsed() >= max_age)
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush().await?;
+
+        let (stem, extension) = stem_and_extension(&self.path);
+        let rotated = self.path.with_file_name(format!(
+            "{}.{}{}",
+            stem,
+            Utc::now().format(ROTATED_TIMESTAMP_FORMAT),
+            extension
+        ));
+
+        tokio::fs::rename(&self.path, &rotated).await?;
+        let fresh = Self::open(self.path.clone(), self.policy.clone()).await?;
+        *self = fresh;
+
+        let path = self.path.clone();
+        let keep = self.policy.keep;
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = compress(&rotated) {
+                eprintln!("Failed to compress rotated log {:?}: {}", rotated, e);
+                return;
+            }
+            if let Some(keep) = keep {
+                if let Err(e) = prune_archives(&path, keep) {
+                    eprintln!("Failed to prune old logs for {:?}: {}", path, e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Timestamp in the names of rotated logs, they sort chronologically.
+const ROTATED_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3f";
+
+/// `conn.pci.log` as (`conn.pci`, `.log`).
+fn stem_and_extension(path: &Path) -> (String, String) {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (stem, extension)
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+    let mut archive_name = path.as_os_str().to_owned();
+    archive_name.push(".gz");
+
+    let mut input = std::fs::File::open(path)?;
+    let output = std::fs::File::create(&archive_name)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+
+    std::fs::remove_file(path)
+}
+
+/// Deletes the oldest `<stem>.<timestamp><extension>.gz` archives next to `path` until
+/// `keep` remain. Only exact matches count, so `conn.log` never prunes the archives of
+/// `conn.pci.log`.
+fn prune_archives(path: &Path, keep: usize) -> io::Result<()> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let (stem, extension) = stem_and_extension(path);
+    let suffix = format!("{}.gz", extension);
+    let is_archive = |name: &str| {
+        name.strip_prefix(&stem)
+            .and_then(|rest| rest.strip_prefix('.'))
+            .and_then(|rest| rest.strip_suffix(&suffix))
+            .is_some_and(|timestamp| {
+                NaiveDateTime::parse_from_str(timestamp, ROTATED_TIMESTAMP_FORMAT).is_ok()
+            })
+    };
+
+    let mut archives: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|archive| {
+            archive
+                .file_name()
+                .is_some_and(|name| is_archive(&name.to_string_lossy()))
+        })
+        .collect();
+
+    // Timestamps in the names sort chronologically
+    archives.sort();
+    let excess = archives.len().saturating_sub(keep);
+    for archive in &archives[..excess] {
+        std::fs::remove_file(archive)?;
+    }
+
+    Ok(())
+}
+
+/// Drains the capture queue into the log in batches, flushing once per batch rather
+/// than once per packet, and writes rule counter summaries as they come in. Returns
+/// once both queues are closed and everything is written.
+pub async fn run_log_writer(
+    mut log: RotatingLog,
+    mut queue: Receiver<PacketInfo>,
+    mut summaries: Receiver<CounterSummary>,
+    mut aggregator: Option<Aggregator>,
+    stats: Arc<CaptureStats>,
+) {
+    let mut batch = Vec::with_capacity(LOG_BATCH_SIZE);
+    // Closes aggregation windows even when no packets are arriving
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        let records = tokio::select! {
+            received = queue.recv_many(&mut batch, LOG_BATCH_SIZE) => {
+                if received == 0 {
+                    break;
+                }
+                match &mut aggregator {
+                    Some(aggregator) => batch
+                        .drain(..)
+                        .filter_map(|packet| aggregator.observe(packet))
+                        .collect(),
+                    None => std::mem::take(&mut batch),
+                }
+            }
+            Some(summary) = summaries.recv() => {
+                write_summary(&mut log, summary).await;
+                continue;
+            }
+            _ = tick.tick() => match &mut aggregator {
+                Some(aggregator) => aggregator.drain_expired(Utc::now()),
+                None => continue,
+            },
+        };
+
+        write_records(&mut log, &records, &stats).await;
+    }
+
+    // Write out whatever is still being aggregated
+    if let Some(aggregator) = &mut aggregator {
+        let records = aggregator.drain_all();
+        write_records(&mut log, &records, &stats).await;
+    }
+
+    // The final summary is sent once the capture has stopped
+    while let Some(summary) = summaries.recv().await {
+        write_summary(&mut log, summary).await;
+    }
+}
+
+/// Serializes a record as one log line. Returns whether it was written.
+async fn write_record(log: &mut RotatingLog, record: LogRecord) -> bool {
+    let mut line = match serde_json::to_vec(&record) {
+        Ok(line) => line,
+        Err(e) => {
+            eprintln!("Failed to serialize record: {}", e);
+            return false;
+        }
+    };
+    line.push(b'\n');
+
+    if let Err(e) = log.write_all(&line).await {
+        eprintln!("Failed to write to log: {}", e);
+        return false;
+    }
+    true
+}
+
+async fn write_summary(log: &mut RotatingLog, summary: CounterSummary) {
+    write_record(log, LogRecord::new(summary)).await;
+    if let Err(e) = log.flush().await {
+        eprintln!("Failed to flush log writer: {}", e);
+    }
+}
+
+async fn write_records(log: &mut RotatingLog, records: &[PacketInfo], stats: &CaptureStats) {
+    if records.is_empty() {
+        return;
+    }
+
+    for record in records {
+        if write_record(log, LogRecord::new(record.clone())).await {
+            stats.written.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    if let Err(e) = log.flush().await {
+        eprintln!("Failed to flush log writer: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn prunes_only_its_own_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "conn.log",
+            "conn.20261018T100000.000.log.gz",
+            "conn.20261018T110000.000.log.gz",
+            "conn.20261018T120000.000.log.gz",
+            "conn.pci.log",
+            "conn.pci.20261018T090000.000.log.gz",
+            "conn.pci.20261018T130000.000.log.gz",
+            "conn.notes.log.gz",
+            "conn.20261018T100000.000.log",
+        ] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        prune_archives(&dir.path().join("conn.log"), 1).unwrap();
+        assert_eq!(
+            files(dir.path()),
+            vec![
+                "conn.20261018T100000.000.log",
+                "conn.20261018T120000.000.log.gz",
+                "conn.log",
+                "conn.notes.log.gz",
+                "conn.pci.20261018T090000.000.log.gz",
+                "conn.pci.20261018T130000.000.log.gz",
+                "conn.pci.log",
+            ]
+        );
+
+        prune_archives(&dir.path().join("conn.pci.log"), 0).unwrap();
+        assert_eq!(
+            files(dir.path()),
+            vec![
+                "conn.20261018T100000.000.log",
+                "conn.20261018T120000.000.log.gz",
+                "conn.log",
+                "conn.notes.log.gz",
+                "conn.pci.log",
+            ]
+        );
+    }
+}
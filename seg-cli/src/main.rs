@@ -1,13 +1,42 @@
 use clap::{Parser, Subcommand};
 
+mod aggregate;
+mod bpf;
+mod capture;
 mod consts;
-mod data;
+mod database;
+mod diff;
+mod doctor;
+mod error;
 mod firewall;
+mod logfile;
+mod matrix;
+mod merge;
+mod migrate;
 mod network;
+mod parse;
+mod privileges;
+mod report;
+mod responder;
+mod table;
 mod util;
 
-use consts::NFT_RULES_TEMPLATE;
+use data::Filter;
+use diff::{run_diff, DiffFormat, DiffOptions};
+use doctor::run_doctor;
+use error::{Context, Result, SegError};
+use firewall::{cleanup_leftover_rules, select_backend, FirewallKind};
+use logfile::RotationPolicy;
+use matrix::MatrixFormat;
+use merge::{run_merge, MergeOptions, ScanMarker};
+use migrate::run_migrate;
 use network::*;
+use parse::{run_parse, GraphFormat, ParseOptions};
+use report::{run_report, ReportOptions};
+use responder::{ResponderOptions, UdpReply};
+use std::time::Duration;
+use table::{Column, TableOptions};
+use util::{parse_duration, parse_size};
 
 #[derive(Parser)]
 #[command(name = "Seg network segmentation scanner")]
@@ -19,6 +48,8 @@ struct Cli {
     command: Commands,
 }
 
+// Parsed once at startup, the size difference between variants doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Run in scanner mode
@@ -32,62 +63,411 @@ enum Commands {
     /// Run in listener mode
     Listen {
         /// The name / tag of the network
-        #[arg(long, short)]
-        network_tag: String,
+        #[arg(long, short, required_unless_present_any = ["cleanup", "emit_rules"])]
+        network_tag: Option<String>,
         /// The interface to listen on
-        #[arg(long, short)]
-        interface_name: String,
+        #[arg(long, short, required_unless_present_any = ["cleanup", "emit_rules"])]
+        interface_name: Option<String>,
         /// The protocol to listen for connection over.
         #[arg(long, value_enum, default_value = "both")]
         protocol: ScanProtocol,
         /// Port used to access the host (typically 22 for ssh)
         #[arg(short, long, default_value = "22")]
         access_port: String,
+        /// Also record MAC addresses, TTL, IP ID, DSCP, length and TCP window/options.
+        #[arg(long)]
+        capture_metadata: bool,
+        /// Answer every SYN and UDP probe so scanners see reachable ports as open.
+        #[arg(long)]
+        respond: bool,
+        /// Local port probes are redirected to when responding.
+        #[arg(long, default_value = "5555")]
+        respond_port: u16,
+        /// How to answer UDP probes when responding.
+        #[arg(long, value_enum, default_value = "echo")]
+        udp_reply: UdpReply,
+        /// Fixed payload to answer UDP probes with instead of echoing them.
+        #[arg(long)]
+        udp_payload: Option<String>,
+        /// Log file path. {tag} and {host} are replaced with the network tag and hostname.
+        #[arg(long, default_value = "connections_{tag}_{host}.log")]
+        log: String,
+        /// Rotate the log once it reaches this size (e.g. 512K, 100M, 2G).
+        #[arg(long, value_parser = parse_size)]
+        rotate_size: Option<u64>,
+        /// Rotate the log after this much time (e.g. 30m, 12h, 1d).
+        #[arg(long, value_parser = parse_duration)]
+        rotate_every: Option<Duration>,
+        /// Number of compressed archives to keep. Keeps all of them when omitted.
+        #[arg(long)]
+        rotate_keep: Option<usize>,
+        /// Collapse repeated packets per (source, listener, port, protocol) over this window (e.g. 60s, 5m).
+        #[arg(long, value_parser = parse_duration)]
+        aggregate: Option<Duration>,
+        /// Don't print every captured packet to the console.
+        #[arg(long, short)]
+        quiet: bool,
+        /// Ports to ignore in addition to the access port, comma separated.
+        #[arg(long, value_delimiter = ',')]
+        exclude_ports: Vec<u16>,
+        /// Extra capture filter, either a pcap expression (compiled with tcpdump) or `tcpdump -ddd` output.
+        #[arg(long)]
+        bpf_filter: Option<String>,
+        /// An optional rules file for the firewall backend to use instead of the default rules. Supports the
+        /// {access_port}, {interface}, {listener_ip} and {excluded_ports} variables, and {zone} with firewalld.
+        #[arg(long)]
+        rules: Option<String>,
+        /// Emits the base rules template of the firewall backend for customization.
+        #[arg(long, conflicts_with_all = ["rules", "dry_run", "cleanup"])]
+        emit_rules: bool,
+        /// Firewall to manage the listener rules through.
+        #[arg(long, value_enum, default_value = "auto")]
+        firewall: FirewallKind,
+        /// Print the ruleset that would be applied, check it with the firewall backend, diff it against the live ruleset and exit.
+        #[arg(long)]
+        dry_run: bool,
+        /// Roll the rules back unless they are confirmed within this long (e.g. 60s).
+        #[arg(long, value_parser = parse_duration)]
+        confirm_timeout: Option<Duration>,
+        /// With --confirm-timeout, also confirm once a new connection to the access port comes in from another host.
+        #[arg(long, requires = "confirm_timeout")]
+        confirm_check: bool,
+        /// Remove rules left behind by a listener that didn't exit cleanly, then exit.
+        #[arg(long, conflicts_with_all = ["rules", "dry_run"])]
+        cleanup: bool,
+        /// Switch to this user once the capture is open and the rules are applied. The log is written as this user.
+        #[arg(long)]
+        user: Option<String>,
     },
     /// Parse seg JSONL scan data into various useful formats.
     Parse {
-        /// The JSONL file of scan data to parse.
-        #[arg(short, long)]
-        input_file: String,
-        /// A dir of JSONL files to parse.
-        #[arg(short, long)]
-        input_dir: String,
+        /// The JSONL file of scan data to parse. Can be repeated.
+        #[arg(short, long, required_unless_present = "input_dir")]
+        input_file: Vec<String>,
+        /// A dir of JSONL files to parse, including gzipped rotation archives. Can be repeated.
+        #[arg(short = 'd', long)]
+        input_dir: Vec<String>,
+        /// Only use packets matching this expression, e.g. 'proto == tcp and port < 1024'.
+        #[arg(long)]
+        filter: Option<Filter>,
         /// Output as CSV.
         #[arg(long)]
         csv: bool,
         /// Output as Netflow.
         #[arg(long)]
         netflow: bool,
-        /// Output file name (can be a path).
+        /// Output a source network by destination network matrix of the ports that got through.
+        #[arg(long, value_enum, conflicts_with_all = ["csv", "netflow"])]
+        matrix: Option<MatrixFormat>,
+        /// Output the scanner to listener graph the viewer draws, for Graphviz, Gephi or Markdown.
+        #[arg(long, value_enum, conflicts_with_all = ["csv", "netflow", "matrix"])]
+        graph: Option<GraphFormat>,
+        /// Output a normalized SQLite database (networks, hosts, sessions, records and a packets view) to --out.
+        #[arg(long, requires = "out", conflicts_with_all = ["csv", "netflow", "matrix", "graph"])]
+        sqlite: bool,
+        /// Output the same tables as Parquet files into the --out directory.
+        #[arg(long, requires = "out", conflicts_with_all = ["csv", "netflow", "matrix", "graph", "sqlite"])]
+        parquet: bool,
+        /// Output file name (can be a path). Defaults to stdout.
         #[arg(short, long)]
+        out: Option<String>,
+        /// CSV columns to write, comma separated. Defaults depend on --aggregate.
+        #[arg(long, value_enum, value_delimiter = ',')]
+        columns: Vec<Column>,
+        /// One row per source network, destination network, port and protocol.
+        #[arg(long)]
+        aggregate: bool,
+        /// With --aggregate, one row per network pair and protocol with its ports as ranges (e.g. 1-1024).
+        #[arg(long, requires = "aggregate")]
+        collapse_ports: bool,
+        /// Prefix length that groups scanner addresses into source networks.
+        #[arg(long, default_value = "24", value_parser = clap::value_parser!(u8).range(0..=32))]
+        source_prefix: u8,
+    },
+    /// Write a self-contained HTML report on the listener logs of an engagement.
+    Report {
+        /// A JSONL listener log to report on. Can be repeated.
+        #[arg(short, long, required_unless_present = "input_dir")]
+        input_file: Vec<String>,
+        /// A dir of listener logs to report on, including gzipped rotation archives. Can be repeated.
+        #[arg(short = 'd', long)]
+        input_dir: Vec<String>,
+        /// Only use packets matching this expression, e.g. 'proto == tcp and port < 1024'.
+        #[arg(long)]
+        filter: Option<Filter>,
+        /// Nmap output written by seg scan, to show what the scanners saw. Can be repeated.
+        #[arg(long)]
+        scan_log: Vec<String>,
+        /// Allowed traffic as source,destination,protocol,ports lines (* matches anything). Findings outside it are flagged.
+        #[arg(long)]
+        policy: Option<String>,
+        /// Output file name (can be a path).
+        #[arg(short, long, default_value = "seg_report.html")]
         out: String,
+        /// Title of the report.
+        #[arg(long, default_value = "Network segmentation report")]
+        title: String,
+        /// Prefix length that groups scanner addresses into source networks.
+        #[arg(long, default_value = "24", value_parser = clap::value_parser!(u8).range(0..=32))]
+        source_prefix: u8,
+    },
+    /// Compare two engagements and list the paths that opened or closed in between.
+    Diff {
+        /// The earlier engagement, a listener log or a dir of them.
+        old: String,
+        /// The later engagement, a listener log or a dir of them.
+        new: String,
+        /// Only compare packets matching this expression, e.g. 'proto == tcp and port < 1024'.
+        #[arg(long)]
+        filter: Option<Filter>,
+        /// Output format.
+        #[arg(long, value_enum, default_value = "text")]
+        format: DiffFormat,
+        /// Output file name (can be a path). Defaults to stdout.
+        #[arg(short, long)]
+        out: Option<String>,
+        /// Prefix length that groups scanner addresses into source networks.
+        #[arg(long, default_value = "24", value_parser = clap::value_parser!(u8).range(0..=32))]
+        source_prefix: u8,
+    },
+    /// Merge many listeners' logs into one log ordered by time, optionally correcting clock skew.
+    Merge {
+        /// A JSONL listener log to merge. Can be repeated.
+        #[arg(short, long, required_unless_present = "input_dir")]
+        input_file: Vec<String>,
+        /// A dir of listener logs to merge, including gzipped rotation archives. Can be repeated.
+        #[arg(short = 'd', long)]
+        input_dir: Vec<String>,
+        /// When a scanner sent its first probe, by its own clock, as SOURCE_IP@TIME. Each listener's clock is shifted so its first packet from that scanner lines up, so the shift includes the probe's transit time, and any probes before it that were blocked. Mark a probe you know gets through. Once per scanner, can be repeated.
+        #[arg(long)]
+        marker: Vec<ScanMarker>,
+        /// How far a listener's clock may be off. Only packets this close to a marker are paired with it.
+        #[arg(long, value_parser = parse_duration, default_value = "5m")]
+        marker_window: Duration,
+        /// Output file name (can be a path). Defaults to stdout.
+        #[arg(short, long)]
+        out: Option<String>,
+    },
+    /// Check privileges, tools, interfaces and firewall managers before an engagement.
+    Doctor {
+        /// The interface the listener will run on.
+        #[arg(short, long)]
+        interface_name: Option<String>,
+        /// Firewall the listener will manage its rules through.
+        #[arg(long, value_enum, default_value = "auto")]
+        firewall: FirewallKind,
+    },
+    /// Upgrade a listener log written by an older version of seg to the current schema.
+    Migrate {
+        /// The JSONL log file to migrate.
+        #[arg(short, long)]
+        input_file: String,
+        /// Output file name (can be a path). Defaults to rewriting the input file.
+        #[arg(short, long)]
+        out: Option<String>,
     },
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let cli = Cli::parse();
 
+    if let Err(e) = run(cli) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
     match &cli.command {
         Commands::Scan {
             input_file,
             scan_type,
         } => {
-            run_scan(input_file.to_string(), scan_type.clone()).await;
+            // The listener starts its own runtime once it has dropped its privileges
+            tokio::runtime::Runtime::new()
+                .context("Failed to start the async runtime")?
+                .block_on(run_scan(input_file.to_string(), scan_type.clone()))?;
         }
         Commands::Listen {
             network_tag,
             interface_name,
             protocol,
             access_port,
+            capture_metadata,
+            respond,
+            respond_port,
+            udp_reply,
+            udp_payload,
+            log,
+            rotate_size,
+            rotate_every,
+            rotate_keep,
+            aggregate,
+            quiet,
+            exclude_ports,
+            bpf_filter,
+            rules,
+            emit_rules,
+            firewall,
+            dry_run,
+            confirm_timeout,
+            confirm_check,
+            cleanup,
+            user,
+        } => {
+            if *cleanup {
+                return cleanup_leftover_rules(*firewall);
+            }
+
+            if *emit_rules {
+                print!("{}", select_backend(*firewall, None).rules_template());
+                return Ok(());
+            }
+
+            let rules = match rules {
+                Some(path) => Some(
+                    std::fs::read_to_string(path)
+                        .context(format!("Unable to read rules file {}", path))?,
+                ),
+                None => None,
+            };
+
+            // Both are required by clap unless --cleanup or --emit-rules is passed
+            let (Some(network_tag), Some(interface_name)) = (network_tag, interface_name) else {
+                return Err(SegError::Input(
+                    "--network-tag and --interface-name are required".to_string(),
+                ));
+            };
+
+            let responder = respond.then(|| ResponderOptions {
+                port: *respond_port,
+                protocol: protocol.clone(),
+                udp_reply: udp_reply.clone(),
+                udp_payload: udp_payload.clone(),
+            });
+
+            run_listener(ListenerOptions {
+                access_port: access_port.clone(),
+                interface_name: interface_name.clone(),
+                network_tag: network_tag.clone(),
+                protocol: protocol.clone(),
+                capture_metadata: *capture_metadata,
+                responder,
+                log: log.clone(),
+                rotation: RotationPolicy {
+                    max_bytes: *rotate_size,
+                    max_age: *rotate_every,
+                    keep: *rotate_keep,
+                },
+                aggregate: *aggregate,
+                quiet: *quiet,
+                excluded_ports: exclude_ports.clone(),
+                bpf_filter: bpf_filter.clone(),
+                rules,
+                firewall: *firewall,
+                dry_run: *dry_run,
+                confirm_timeout: *confirm_timeout,
+                confirm_check: *confirm_check,
+                user: user.clone(),
+            })?;
+        }
+        Commands::Parse {
+            input_file,
+            input_dir,
+            filter,
+            csv,
+            netflow,
+            matrix,
+            graph,
+            sqlite,
+            parquet,
+            out,
+            columns,
+            aggregate,
+            collapse_ports,
+            source_prefix,
+        } => {
+            run_parse(ParseOptions {
+                input_files: input_file.clone(),
+                input_dirs: input_dir.clone(),
+                filter: filter.clone(),
+                out: out.clone(),
+                csv: *csv,
+                netflow: *netflow,
+                matrix: *matrix,
+                graph: *graph,
+                sqlite: *sqlite,
+                parquet: *parquet,
+                table: TableOptions {
+                    columns: columns.clone(),
+                    aggregate: *aggregate,
+                    collapse_ports: *collapse_ports,
+                    source_prefix: *source_prefix,
+                },
+            })?;
+        }
+        Commands::Report {
+            input_file,
+            input_dir,
+            filter,
+            scan_log,
+            policy,
+            out,
+            title,
+            source_prefix,
+        } => {
+            run_report(ReportOptions {
+                input_files: input_file.clone(),
+                input_dirs: input_dir.clone(),
+                filter: filter.clone(),
+                scan_logs: scan_log.clone(),
+                policy: policy.clone(),
+                out: out.clone(),
+                title: title.clone(),
+                source_prefix: *source_prefix,
+            })?;
+        }
+        Commands::Diff {
+            old,
+            new,
+            filter,
+            format,
+            out,
+            source_prefix,
         } => {
-            run_listener(
-                access_port.clone(),
-                interface_name.clone(),
-                network_tag.clone(),
-                protocol.clone(),
-            )
-            .await;
+            run_diff(DiffOptions {
+                old: old.clone(),
+                new: new.clone(),
+                filter: filter.clone(),
+                format: *format,
+                out: out.clone(),
+                source_prefix: *source_prefix,
+            })?;
+        }
+        Commands::Merge {
+            input_file,
+            input_dir,
+            marker,
+            marker_window,
+            out,
+        } => {
+            run_merge(MergeOptions {
+                input_files: input_file.clone(),
+                input_dirs: input_dir.clone(),
+                markers: marker.clone(),
+                marker_window: *marker_window,
+                out: out.clone(),
+            })?;
+        }
+        Commands::Doctor {
+            interface_name,
+            firewall,
+        } => {
+            run_doctor(interface_name.clone(), *firewall)?;
+        }
+        Commands::Migrate { input_file, out } => {
+            run_migrate(input_file.to_string(), out.clone())?;
         }
     }
+    Ok(())
 }
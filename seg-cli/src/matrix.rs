@@ -0,0 +1,241 @@
+use crate::parse::*;
+use crate::table::*;
+use crate::util::escape_html;
+
+use data::PacketInfo;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum MatrixFormat {
+    Csv,
+    Markdown,
+    Html,
+}
+
+/// Which ports each source network reached in each destination network.
+pub struct Matrix {
+    pub sources: Vec<SourceNetwork>,
+    pub destinations: Vec<String>,
+    /// Reached ports per protocol, only for pairs that reached anything.
+    cells: BTreeMap<(SourceNetwork, String), BTreeMap<String, BTreeSet<u16>>>,
+}
+
+impl Matrix {
+    /// Builds the matrix from packets. Scanner addresses are grouped into source
+    /// networks of `source_prefix` bits, destinations are the listeners' network tags.
+    pub fn new(packets: &[PacketInfo], source_prefix: u8) -> Self {
+        let rows = build_rows(
+            packets,
+            &TableOptions {
+                columns: vec![],
+                aggregate: true,
+                collapse_ports: true,
+                source_prefix,
+            },
+        );
+
+        let mut sources = BTreeSet::new();
+        let mut destinations = BTreeSet::new();
+        let mut cells: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+        for row in rows {
+            sources.insert(row.source_network);
+            destinations.insert(row.destination_network.clone());
+            cells
+                .entry((row.source_network, row.destination_network))
+                .or_default()
+                .insert(row.protocol, row.ports);
+        }
+
+        Self {
+            sources: sources.into_iter().collect(),
+            destinations: destinations.into_iter().collect(),
+            cells,
+        }
+    }
+
+    /// Ports reached from `source` into `destination`, e.g. `tcp 22-24; udp 53`.
+    /// Empty when nothing got through.
+    pub fn cell(&self, source: SourceNetwork, destination: &str) -> String {
+        let Some(protocols) = self.cells.get(&(source, destination.to_string())) else {
+            return String::new();
+        };
+        protocols
+            .iter()
+            .map(|(protocol, ports)| format!("{} {}", protocol, format_port_ranges(ports)))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// The matrix as an HTML `<table>`, for embedding in a page.
+    pub fn html_table(&self) -> String {
+        let mut html = String::from("<table class=\"matrix\">\n<tr><th>from \\ to</th>");
+        for destination in &self.destinations {
+            html.push_str(&format!("<th>{}</th>", escape_html(destination)));
+        }
+        html.push_str("</tr>\n");
+
+        for source in &self.sources {
+            html.push_str(&format!("<tr><th>{}</th>", source));
+            for destination in &self.destinations {
+                let cell = self.cell(*source, destination);
+                let class = if cell.is_empty() { "closed" } else { "open" };
+                html.push_str(&format!(
+                    "<td class=\"{}\">{}</td>",
+                    class,
+                    escape_html(&cell)
+                ));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n");
+        html
+    }
+}
+
+/// Styles for `Matrix::html_table`, reachable pairs stand out as findings.
+pub const MATRIX_CSS: &str =
+    "table.matrix { border-collapse: collapse; font-family: sans-serif; font-size: 0.9em; }
+table.matrix th, table.matrix td { border: 1px solid #999; padding: 4px 8px; text-align: left; }
+table.matrix th { background: #eee; }
+table.matrix td.open { background: #f8d7da; }
+table.matrix td.closed { background: #d4edda; }
+";
+
+fn escape_markdown(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+pub fn write_matrix(
+    mut output: impl Write,
+    matrix: &Matrix,
+    format: MatrixFormat,
+) -> io::Result<()> {
+    match format {
+        MatrixFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(output);
+            let mut header = vec!["source_network".to_string()];
+            header.extend(matrix.destinations.iter().cloned());
+            writer.write_record(&header)?;
+            for source in &matrix.sources {
+                let mut record = vec![source.to_string()];
+                record.extend(
+                    matrix
+                        .destinations
+                        .iter()
+                        .map(|destination| matrix.cell(*source, destination)),
+                );
+                writer.write_record(&record)?;
+            }
+            writer.flush()
+        }
+        MatrixFormat::Markdown => {
+            write!(output, "| from \\ to |")?;
+            for destination in &matrix.destinations {
+                write!(output, " {} |", escape_markdown(destination))?;
+            }
+            write!(output, "\n|---|")?;
+            for _ in &matrix.destinations {
+                write!(output, "---|")?;
+            }
+            writeln!(output)?;
+
+            for source in &matrix.sources {
+                write!(output, "| {} |", source)?;
+                for destination in &matrix.destinations {
+                    let cell = matrix.cell(*source, destination);
+                    let cell = if cell.is_empty() {
+                        "-".to_string()
+                    } else {
+                        cell
+                    };
+                    write!(output, " {} |", escape_markdown(&cell))?;
+                }
+                writeln!(output)?;
+            }
+            output.flush()
+        }
+        MatrixFormat::Html => {
+            write!(
+                output,
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Reachability matrix</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>Reachability matrix</h1>\n{}</body>\n</html>\n",
+                MATRIX_CSS,
+                matrix.html_table()
+            )?;
+            output.flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use std::net::Ipv4Addr;
+
+    fn packet(source: [u8; 4], tag: &str, port: u16, protocol: &str) -> PacketInfo {
+        PacketInfo {
+            listener_ip: Ipv4Addr::new(192, 0, 2, 2),
+            network_tag: tag.to_string(),
+            source_ip: source.into(),
+            source_port: 40000,
+            target_port: port,
+            protocol: protocol.to_string(),
+            flags: vec![],
+            timestamp: DateTime::from_timestamp(1_800_000_000, 0).unwrap(),
+            metadata: None,
+            last_seen: None,
+            count: None,
+        }
+    }
+
+    fn matrix() -> Matrix {
+        let packets = [
+            packet([10, 0, 1, 5], "pci", 24, "tcp"),
+            packet([10, 0, 1, 5], "pci", 22, "tcp"),
+            packet([10, 0, 1, 6], "pci", 23, "tcp"),
+            packet([10, 0, 1, 5], "pci", 53, "udp"),
+            packet([10, 0, 1, 5], "pci", 443, "tcp"),
+            packet([10, 0, 2, 5], "dmz", 80, "tcp"),
+        ];
+        Matrix::new(&packets, 24)
+    }
+
+    #[test]
+    fn cells() {
+        let matrix = matrix();
+        let lan = SourceNetwork::of(Ipv4Addr::new(10, 0, 1, 0), 24);
+        let dmz_scanner = SourceNetwork::of(Ipv4Addr::new(10, 0, 2, 0), 24);
+        assert_eq!(matrix.sources, vec![lan, dmz_scanner]);
+        assert_eq!(matrix.destinations, vec!["dmz", "pci"]);
+
+        assert_eq!(matrix.cell(lan, "pci"), "tcp 22-24,443; udp 53");
+        assert_eq!(matrix.cell(lan, "dmz"), "");
+        assert_eq!(matrix.cell(dmz_scanner, "dmz"), "tcp 80");
+        assert_eq!(matrix.cell(dmz_scanner, "pci"), "");
+        assert_eq!(matrix.cell(lan, "corp"), "");
+    }
+
+    #[test]
+    fn markdown_and_csv() {
+        let matrix = matrix();
+        let mut markdown = vec![];
+        write_matrix(&mut markdown, &matrix, MatrixFormat::Markdown).unwrap();
+        assert_eq!(
+            String::from_utf8(markdown).unwrap(),
+            "| from \\ to | dmz | pci |\n\
+             |---|---|---|\n\
+             | 10.0.1.0/24 | - | tcp 22-24,443; udp 53 |\n\
+             | 10.0.2.0/24 | tcp 80 | - |\n"
+        );
+
+        let mut csv = vec![];
+        write_matrix(&mut csv, &matrix, MatrixFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "source_network,dmz,pci\n\
+             10.0.1.0/24,,\"tcp 22-24,443; udp 53\"\n\
+             10.0.2.0/24,tcp 80,\n"
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use crate::error::{Context, Result, SegError};
+use crate::parse::*;
+
+use chrono::{DateTime, Duration, Utc};
+use data::*;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+pub struct MergeOptions {
+    pub input_files: Vec<String>,
+    pub input_dirs: Vec<String>,
+    pub markers: Vec<ScanMarker>,
+    /// How far a listener's clock may be off, packets further from a marker aren't
+    /// paired with it.
+    pub marker_window: std::time::Duration,
+    pub out: Option<String>,
+}
+
+/// A scanner's first probe at a time known from the scanner's own clock, written
+/// `SOURCE_IP@TIME`, e.g. `10.0.1.5@2026-10-18T10:00:00Z`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanMarker {
+    pub source_ip: Ipv4Addr,
+    pub started: DateTime<Utc>,
+}
+
+impl FromStr for ScanMarker {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let (source_ip, started) = value
+            .split_once('@')
+            .ok_or_else(|| "expected SOURCE_IP@TIME".to_string())?;
+        let source_ip = source_ip
+            .parse()
+            .map_err(|_| format!("'{}' is not an IPv4 address", source_ip))?;
+        let started = DateTime::parse_from_rfc3339(started)
+            .map_err(|_| format!("'{}' is not an RFC 3339 time", started))?
+            .with_timezone(&Utc);
+        Ok(Self { source_ip, started })
+    }
+}
+
+/// A listener is identified by its address and network tag, like in its counter summaries.
+type Listener = (Ipv4Addr, String);
+
+/// The correction for each listener's clock: how far behind the scanners' clocks it
+/// is, the median over every marker the listener saw. A marker is paired with the
+/// listener's first packet from that scanner within `window` of it, so an earlier or
+/// later scan from the same address doesn't count.
+fn clock_offsets(
+    packets: &[PacketInfo],
+    markers: &[ScanMarker],
+    window: Duration,
+) -> BTreeMap<Listener, Duration> {
+    let mut samples: BTreeMap<Listener, Vec<Duration>> = BTreeMap::new();
+    for marker in markers {
+        let mut first_seen: BTreeMap<Listener, DateTime<Utc>> = BTreeMap::new();
+        for packet in packets.iter().filter(|packet| {
+            packet.source_ip == marker.source_ip
+                && (packet.timestamp - marker.started).abs() <= window
+        }) {
+            first_seen
+                .entry((packet.listener_ip, packet.network_tag.clone()))
+                .and_modify(|first| *first = (*first).min(packet.timestamp))
+                .or_insert(packet.timestamp);
+        }
+        for (listener, first) in first_seen {
+            samples
+                .entry(listener)
+                .or_default()
+                .push(marker.started - first);
+        }
+    }
+    samples
+        .into_iter()
+        .map(|(listener, mut offsets)| {
+            offsets.sort();
+            (listener, offsets[offsets.len() / 2])
+        })
+        .collect()
+}
+
+fn format_offset(offset: Duration) -> String {
+    let millis = offset.num_milliseconds();
+    let sign = if millis < 0 { '-' } else { '+' };
+    format!(
+        "{}{}.{:03}s",
+        sign,
+        millis.abs() / 1000,
+        millis.abs() % 1000
+    )
+}
+
+/// A second marker for a scanner would pair with the same first probe.
+fn check_markers(markers: &[ScanMarker]) -> Result<()> {
+    let mut marked = HashSet::new();
+    match markers
+        .iter()
+        .find(|marker| !marked.insert(marker.source_ip))
+    {
+        Some(marker) => Err(SegError::Input(format!(
+            "More than one --marker for {}, mark each scanner once",
+            marker.source_ip
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Writes records sorted by time, skipping exact duplicates. Copies of a record share
+/// its timestamp, so only the lines of the current timestamp need remembering.
+fn write_records(records: &[(DateTime<Utc>, LogRecord)], output: &mut impl Write) -> Result<usize> {
+    let mut current = None;
+    let mut seen = HashSet::new();
+    let mut written = 0;
+    for (timestamp, record) in records {
+        if current != Some(*timestamp) {
+            current = Some(*timestamp);
+            seen.clear();
+        }
+        let line = serde_json::to_string(record)
+            .map_err(io::Error::from)
+            .context("Failed to serialize merged record")?;
+        if !seen.insert(line.clone()) {
+            continue;
+        }
+        writeln!(output, "{}", line).context("Failed to write merged log")?;
+        written += 1;
+    }
+    output.flush().context("Failed to write merged log")?;
+    Ok(written)
+}
+
+/// Merges many listeners' logs into one v4 log ordered by time. Records read more than
+/// once, e.g. from overlapping copies of a log, are written once. With scan markers
+/// every listener's timestamps are shifted onto the scanners' clocks first.
+pub fn run_merge(options: MergeOptions) -> Result<()> {
+    check_markers(&options.markers)?;
+    let window = Duration::from_std(options.marker_window)
+        .map_err(|_| SegError::Input("--marker-window is too large".to_string()))?;
+
+    let mut logs = load_logs(&options.input_files, &options.input_dirs, None)?;
+
+    let offsets = clock_offsets(&logs.packets, &options.markers, window);
+    if !options.markers.is_empty() {
+        let mut listeners: Vec<Listener> = logs
+            .packets
+            .iter()
+            .map(|packet| (packet.listener_ip, packet.network_tag.clone()))
+            .collect();
+        listeners.sort();
+        listeners.dedup();
+        for listener in listeners {
+            match offsets.get(&listener) {
+                Some(offset) => eprintln!(
+                    "Correcting {} ({}) by {}",
+                    listener.0,
+                    listener.1,
+                    format_offset(*offset)
+                ),
+                None => eprintln!(
+                    "No packet from a marked scanner within {}s of its marker seen by {} ({}), leaving its clock as is",
+                    window.num_seconds(),
+                    listener.0,
+                    listener.1
+                ),
+            }
+        }
+    }
+
+    for packet in &mut logs.packets {
+        if let Some(offset) = offsets.get(&(packet.listener_ip, packet.network_tag.clone())) {
+            packet.timestamp += *offset;
+            packet.last_seen = packet.last_seen.map(|last_seen| last_seen + *offset);
+        }
+    }
+    for summary in &mut logs.counters {
+        if let Some(offset) = offsets.get(&(summary.listener_ip, summary.network_tag.clone())) {
+            summary.timestamp += *offset;
+        }
+    }
+
+    let mut records: Vec<(DateTime<Utc>, LogRecord)> = logs
+        .packets
+        .into_iter()
+        .map(|packet| (packet.timestamp, LogRecord::new(packet)))
+        .chain(
+            logs.counters
+                .into_iter()
+                .map(|summary| (summary.timestamp, LogRecord::new(summary))),
+        )
+        .collect();
+    // Stable, so records with the same timestamp keep the order they were logged in
+    records.sort_by_key(|(timestamp, _)| *timestamp);
+
+    let mut output = open_output(options.out.as_deref())?;
+    let written = write_records(&records, &mut output)?;
+
+    eprintln!(
+        "Merged {} records from {} files into {} ({} duplicates removed)",
+        written,
+        logs.files.len(),
+        options.out.as_deref().unwrap_or("stdout"),
+        records.len() - written
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_800_000_000 + seconds, 0).unwrap()
+    }
+
+    fn packet(listener: [u8; 4], source: [u8; 4], seconds: i64) -> PacketInfo {
+        PacketInfo {
+            listener_ip: listener.into(),
+            network_tag: "pci".to_string(),
+            source_ip: source.into(),
+            source_port: 40000,
+            target_port: 22,
+            protocol: "tcp".to_string(),
+            flags: vec!["SYN".to_string()],
+            timestamp: time(seconds),
+            metadata: None,
+            last_seen: None,
+            count: None,
+        }
+    }
+
+    fn marker(source: [u8; 4], seconds: i64) -> ScanMarker {
+        ScanMarker {
+            source_ip: source.into(),
+            started: time(seconds),
+        }
+    }
+
+    const LISTENER: [u8; 4] = [192, 0, 2, 2];
+    const SCANNER: [u8; 4] = [10, 0, 1, 5];
+
+    #[test]
+    fn pairs_markers_with_the_first_packet_in_the_window() {
+        let packets = vec![
+            // An earlier scan from the same address, outside the window
+            packet(LISTENER, SCANNER, -3600),
+            packet(LISTENER, SCANNER, 7),
+            packet(LISTENER, SCANNER, 5),
+            packet(LISTENER, [10, 0, 1, 6], 1),
+        ];
+        let offsets = clock_offsets(&packets, &[marker(SCANNER, 0)], Duration::seconds(60));
+        assert_eq!(
+            offsets,
+            BTreeMap::from([((LISTENER.into(), "pci".to_string()), Duration::seconds(-5))])
+        );
+    }
+
+    #[test]
+    fn takes_the_median_offset() {
+        let packets = vec![
+            packet(LISTENER, SCANNER, 5),
+            packet(LISTENER, [10, 0, 1, 6], 103),
+            packet(LISTENER, [10, 0, 1, 7], 190),
+        ];
+        let markers = [
+            marker(SCANNER, 0),
+            marker([10, 0, 1, 6], 100),
+            marker([10, 0, 1, 7], 200),
+        ];
+        let offsets = clock_offsets(&packets, &markers, Duration::seconds(60));
+        assert_eq!(
+            offsets[&(LISTENER.into(), "pci".to_string())],
+            Duration::seconds(-3)
+        );
+    }
+
+    #[test]
+    fn leaves_listeners_without_a_marked_packet_alone() {
+        let packets = vec![
+            packet(LISTENER, [10, 0, 1, 6], 0),
+            packet([192, 0, 2, 3], SCANNER, 600),
+        ];
+        assert!(clock_offsets(&packets, &[marker(SCANNER, 0)], Duration::seconds(60)).is_empty());
+        assert!(clock_offsets(&packets, &[], Duration::seconds(60)).is_empty());
+    }
+
+    #[test]
+    fn rejects_duplicate_markers() {
+        assert!(check_markers(&[marker(SCANNER, 0), marker([10, 0, 1, 6], 0)]).is_ok());
+        assert!(check_markers(&[marker(SCANNER, 0), marker(SCANNER, 100)]).is_err());
+    }
+
+    #[test]
+    fn writes_duplicates_once() {
+        let record = |seconds, port| {
+            let mut packet = packet(LISTENER, SCANNER, seconds);
+            packet.target_port = port;
+            (time(seconds), LogRecord::new(packet))
+        };
+        let records = vec![
+            record(0, 22),
+            record(0, 23),
+            record(0, 22),
+            record(1, 22),
+            record(1, 22),
+        ];
+        let mut output = vec![];
+        assert_eq!(write_records(&records, &mut output).unwrap(), 3);
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), 3);
+    }
+}
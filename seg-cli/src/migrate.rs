@@ -0,0 +1,127 @@
+use crate::error::{Context, Result};
+use data::*;
+
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Rewrites a listener log so every record is in the current schema version.
+/// When `out` is omitted the input file is replaced atomically. Lines that can't be
+/// migrated (unparseable, or from a newer version of seg) are copied through as they
+/// are, so migrating never loses data.
+pub fn run_migrate(input_file: String, out: Option<String>) -> Result<()> {
+    let input =
+        File::open(&input_file).context(format!("Unable to open input file {}", input_file))?;
+    let permissions = input
+        .metadata()
+        .context(format!("Unable to read metadata of {}", input_file))?
+        .permissions();
+    let reader = BufReader::new(input);
+
+    // Write next to the destination so the final rename stays on one filesystem
+    let destination = out.unwrap_or_else(|| input_file.clone());
+    let dir = Path::new(&destination)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let temp_file = tempfile::Builder::new()
+        .prefix("seg_migrate")
+        .tempfile_in(dir)
+        .context("Failed to create temp file for migrated log")?;
+    // Temp files are created 0600, keep the log readable by whoever could read it before
+    temp_file
+        .as_file()
+        .set_permissions(permissions)
+        .context("Failed to set permissions of migrated log")?;
+    let mut writer = BufWriter::new(temp_file.as_file());
+
+    let mut total = 0;
+    let mut upgraded = 0;
+    let mut kept = 0;
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line.context(format!("Failed to read {}", input_file))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match migrate_line(&line) {
+            Ok((record, version)) => {
+                serde_json::to_writer(&mut writer, &record)
+                    .map_err(io::Error::from)
+                    .and_then(|_| writer.write_all(b"\n"))
+                    .context("Failed to write migrated record")?;
+                if version < LOG_SCHEMA_VERSION {
+                    upgraded += 1;
+                }
+            }
+            Err(reason) => {
+                eprintln!("Keeping line {} unchanged: {}", idx + 1, reason);
+                writeln!(writer, "{}", line).context("Failed to write migrated record")?;
+                kept += 1;
+            }
+        }
+        total += 1;
+    }
+
+    writer.flush().context("Failed to flush migrated log")?;
+    drop(writer);
+
+    temp_file
+        .persist(&destination)
+        .map_err(|e| e.error)
+        .context(format!("Failed to write migrated log {}", destination))?;
+
+    println!(
+        "Migrated {} records ({} upgraded, {} kept unchanged) to schema v{} in {}",
+        total, upgraded, kept, LOG_SCHEMA_VERSION, destination
+    );
+    Ok(())
+}
+
+/// Upgrades one line to a current record, returning the version it was written in.
+fn migrate_line(line: &str) -> std::result::Result<(LogRecord, u64), String> {
+    let mut record: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let version = upgrade_record(&mut record).map_err(|version| {
+        format!(
+            "schema version {} is newer than {}",
+            version, LOG_SCHEMA_VERSION
+        )
+    })?;
+    // Round trip through the typed record so we never emit something we can't read back
+    let record = serde_json::from_value(record).map_err(|e| e.to_string())?;
+    Ok((record, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn keeps_unmigratable_lines_and_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("listener.log");
+        let lines = [
+            r#"{"listener_ip":"192.0.2.2","network_tag":"pci","source_ip":"10.0.1.5","source_port":40000,"target_port":22,"protocol":"tcp","flags":["SYN"],"timestamp":"2026-07-14T10:00:01Z"}"#,
+            "not json",
+            r#"{"version":99,"kind":"packet"}"#,
+        ];
+        fs::write(&log, lines.join("\n") + "\n").unwrap();
+        fs::set_permissions(&log, fs::Permissions::from_mode(0o644)).unwrap();
+
+        run_migrate(log.to_string_lossy().into_owned(), None).unwrap();
+
+        let migrated = fs::read_to_string(&log).unwrap();
+        let migrated: Vec<&str> = migrated.lines().collect();
+        assert_eq!(migrated.len(), 3);
+        let record = parse_record(migrated[0], 1).unwrap();
+        assert_eq!(record.version, LOG_SCHEMA_VERSION);
+        assert_eq!(record.packet().unwrap().target_port, 22);
+        assert_eq!(migrated[1..], lines[1..]);
+
+        let mode = fs::metadata(&log).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+}
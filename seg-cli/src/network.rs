@@ -1,33 +1,30 @@
+use crate::aggregate::*;
+use crate::bpf::*;
+use crate::capture::*;
 use crate::consts::*;
 //use crate::data::*;
+use crate::error::*;
 use crate::firewall::*;
+use crate::logfile::*;
+use crate::privileges::*;
+use crate::responder::*;
 use crate::util::*;
 
-use data::*;
-
-use pnet::datalink::{self, NetworkInterface};
-
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
-use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
-use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::tcp::TcpFlags;
-use pnet::packet::tcp::TcpPacket;
-use pnet::packet::udp::UdpPacket;
-use pnet::packet::Packet;
-
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use chrono::Utc;
+use data::CounterSummary;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::vec;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncBufReadExt, BufReader, BufWriter};
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::process::Command;
-use tokio::signal::ctrl_c;
-use tokio::sync::Semaphore;
+use tokio::sync::{mpsc, Semaphore};
 use tokio::time::timeout;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, clap::ValueEnum)]
 pub enum ScanProtocol {
     TCP,
@@ -35,17 +32,21 @@ pub enum ScanProtocol {
     BOTH,
 }
 
+// The native scanner isn't wired up to the CLI yet
+#[allow(dead_code)]
 enum ScannerSocketIpv4 {
     TCPSocket(TcpStream),
     UDPSocket(UdpSocket),
 }
 
+#[allow(dead_code)]
 struct Scanner {
     target: String,
     semaphore: Arc<Semaphore>,
     timeout_duration: Duration,
 }
 
+#[allow(dead_code)]
 impl Scanner {
     fn new(target: String, timeout_duration: Duration) -> Self {
         Self {
@@ -63,7 +64,7 @@ impl Scanner {
             // TCP
             let semaphore = self.semaphore.clone();
             let target = self.target.clone();
-            let timeout_duration = self.timeout_duration.clone();
+            let timeout_duration = self.timeout_duration;
             let tcp_handle = tokio::spawn(async move {
                 let scanner = Scanner {
                     target,
@@ -77,7 +78,7 @@ impl Scanner {
             // UDP
             let semaphore = self.semaphore.clone();
             let target = self.target.clone();
-            let timeout_duration = self.timeout_duration.clone();
+            let timeout_duration = self.timeout_duration;
             let udp_handle = tokio::spawn(async move {
                 let scanner = Scanner {
                     target,
@@ -99,55 +100,49 @@ impl Scanner {
     }
 
     async fn scan_udp_port(&self, port: u16) {
-        // This will wait until a permit can be grabbed
-        let _permit = self
-            .semaphore
-            .acquire()
-            .await
-            .expect("Failed to acquire permit");
+        // This will wait until a permit can be grabbed, it only fails once closed
+        let Ok(_permit) = self.semaphore.acquire().await else {
+            return;
+        };
         let addr = format!("{}:{}", self.target, port);
-        let addr: SocketAddr = addr
-            .parse()
-            .expect(format!("Failed to parse address {:?}", addr).as_str());
+        let Ok(addr) = addr.parse::<SocketAddr>() else {
+            eprintln!("Failed to parse address {:?}", addr);
+            return;
+        };
 
-        match timeout(self.timeout_duration, TcpStream::connect(&addr)).await {
-            Ok(Ok(_)) => {
-                println!("tcp/{}/{}", addr, port);
-            }
-            _ => {}
+        if let Ok(Ok(_)) = timeout(self.timeout_duration, TcpStream::connect(&addr)).await {
+            println!("tcp/{}/{}", addr, port);
         }
     }
 
     async fn scan_tcp_port(&self, port: u16) {
-        let _permit = self
-            .semaphore
-            .acquire()
-            .await
-            .expect("Failed to acquire permit");
+        let Ok(_permit) = self.semaphore.acquire().await else {
+            return;
+        };
 
         // TODO: consider UDP socket reuse
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .expect("Failed to bind UDP socket");
+        let socket = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("Failed to bind UDP socket: {}", e);
+                return;
+            }
+        };
 
         let addr = format!("{}:{}", self.target, port);
-        match socket.connect(addr.clone()).await {
-            Ok(_) => {
-                // Send probe
-                match socket.send(&[0; 1]).await {
-                    Ok(_) => println!("udp/{}/{}", addr, port),
-                    Err(_) => {}
-                }
+        if socket.connect(addr.clone()).await.is_ok() {
+            // Send probe
+            if socket.send(&[0; 1]).await.is_ok() {
+                println!("udp/{}/{}", addr, port);
             }
-            Err(_) => {}
-        };
+        }
     }
 }
 
-pub async fn run_scan(input_file: String, scan_type: ScanProtocol) {
+pub async fn run_scan(input_file: String, scan_type: ScanProtocol) -> Result<()> {
     let file = tokio::fs::File::open(&input_file)
         .await
-        .expect("Unable to open input file.");
+        .context(format!("Unable to open input file {}", input_file))?;
     let reader = tokio::io::BufReader::new(file);
 
     let mut lines = reader.lines();
@@ -167,11 +162,16 @@ pub async fn run_scan(input_file: String, scan_type: ScanProtocol) {
 
         let scan_type = scan_type.clone();
 
-        scan_nmap(&listener_ip, &format!("scan_{}", network_name), scan_type).await;
+        scan_nmap(&listener_ip, &format!("scan_{}", network_name), scan_type).await?;
     }
+    Ok(())
 }
 
-pub async fn scan_nmap(listener_ip: &str, output_file: &str, scan_type: ScanProtocol) {
+pub async fn scan_nmap(
+    listener_ip: &str,
+    output_file: &str,
+    scan_type: ScanProtocol,
+) -> Result<()> {
     let mut nmap_args = vec![];
 
     let output_file = match scan_type {
@@ -226,7 +226,7 @@ pub async fn scan_nmap(listener_ip: &str, output_file: &str, scan_type: ScanProt
         .args(&nmap_args_owned)
         .stdout(Stdio::piped())
         .spawn()
-        .expect("Failed to execute Nmap command.");
+        .map_err(|e| SegError::command("nmap", "Failed to execute Nmap command", e))?;
 
     if let Some(stdout) = output.stdout.take() {
         let reader = BufReader::new(stdout);
@@ -237,7 +237,7 @@ pub async fn scan_nmap(listener_ip: &str, output_file: &str, scan_type: ScanProt
         }
     }
 
-    let exit_status = output.wait().await.expect("Nmap process failed.");
+    let exit_status = output.wait().await.context("Nmap process failed")?;
     if exit_status.success() {
         println!(
             "Scan completed for {}. Results saved to {}.nmap",
@@ -246,164 +246,267 @@ pub async fn scan_nmap(listener_ip: &str, output_file: &str, scan_type: ScanProt
     } else {
         eprintln!("Nmap scan failed for {}", listener_ip);
     }
+    Ok(())
 }
 
-pub async fn run_listener(
-    access_port: String,
-    interface_name: String,
-    network_tag: String,
-    protocol: ScanProtocol,
-) {
-    let port: u16 = access_port.parse().expect(&format!(
-        "Error invalid access port specification {}",
-        access_port
-    ));
-
-    // Open log file and wrap it in a shared buffered writer for performance
-    let log_file_path = "connections.log";
-    let log_file = OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(log_file_path)
-        .await
-        .expect("Unable to open log file.");
+/// Everything `seg listen` was asked to do.
+pub struct ListenerOptions {
+    pub access_port: String,
+    pub interface_name: String,
+    pub network_tag: String,
+    pub protocol: ScanProtocol,
+    pub capture_metadata: bool,
+    pub responder: Option<ResponderOptions>,
+    pub log: String,
+    pub rotation: RotationPolicy,
+    pub aggregate: Option<Duration>,
+    pub quiet: bool,
+    pub excluded_ports: Vec<u16>,
+    pub bpf_filter: Option<String>,
+    pub rules: Option<String>,
+    pub firewall: FirewallKind,
+    pub dry_run: bool,
+    pub confirm_timeout: Option<Duration>,
+    pub confirm_check: bool,
+    pub user: Option<String>,
+}
 
-    let log_writer = BufWriter::new(log_file);
-    let log_writer = Arc::new(tokio::sync::Mutex::new(log_writer));
+/// Reads the counters of the applied firewall rules for the log. `None` when the
+/// backend doesn't count or the counters couldn't be read.
+fn counter_summary(
+    listener_ip: Ipv4Addr,
+    network_tag: &str,
+    stats: &CaptureStats,
+    is_final: bool,
+) -> Option<CounterSummary> {
+    let rules = match rule_counters() {
+        Ok(rules) => rules,
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return None,
+        Err(e) => {
+            eprintln!("Failed to read rule counters: {}", e);
+            return None;
+        }
+    };
 
-    // Setup rules to accept all ports on UDP and TCP
-    setup_firewall_rules(None, &access_port).await;
-
-    tokio::select! {
-        _ = handle_packet_log(interface_name, network_tag, port, protocol, log_writer) => {}
-        _ = ctrl_c() => {
-            println!("Shutting down... Cleaning up iptable rules");
-            teardown_firewall_rules().await;
-        },
-    }
+    Some(CounterSummary {
+        listener_ip,
+        network_tag: network_tag.to_string(),
+        timestamp: Utc::now(),
+        is_final,
+        captured: stats.captured.load(Ordering::Relaxed),
+        rules,
+    })
 }
 
-pub async fn handle_packet_log(
-    interface_name: String,
-    network_tag: String,
-    access_port: u16,
-    protocol: ScanProtocol,
-    log_writer: Arc<tokio::sync::Mutex<BufWriter<tokio::fs::File>>>,
-) {
-    use pnet::datalink::Channel::Ethernet;
-
-    // Find interfaces
-    let interfaces = datalink::interfaces();
-    let interface = interfaces
-        .into_iter()
-        .find(|iface: &NetworkInterface| {
-            iface.is_up()
-                && !iface.is_loopback()
-                && iface.ips.len() > 0
-                && iface.name == interface_name
-        })
-        .expect("Could not find interface");
-
-    let local_ip = match interface.ips.iter().find(|ip| ip.is_ipv4()) {
-        Some(ip) => match ip.ip() {
-            std::net::IpAddr::V4(ipv4) => ipv4,
-            _ => panic!("Expected an IPv4 address"),
-        },
-        None => panic!("No IPv4 address found on interface"),
+/// Sets up the capture and the firewall rules with the privileges that takes, drops
+/// everything but CAP_NET_ADMIN and only then starts the runtime. Capabilities are per
+/// thread, so every runtime thread has to inherit the reduced set.
+pub fn run_listener(options: ListenerOptions) -> Result<()> {
+    let ListenerOptions {
+        access_port,
+        interface_name,
+        network_tag,
+        protocol,
+        capture_metadata,
+        responder,
+        log,
+        rotation,
+        aggregate,
+        quiet,
+        excluded_ports,
+        bpf_filter,
+        rules,
+        firewall,
+        dry_run,
+        confirm_timeout,
+        confirm_check,
+        user,
+    } = options;
+
+    let port: u16 = access_port.parse().map_err(|_| {
+        SegError::Input(format!("Invalid access port specification {}", access_port))
+    })?;
+
+    let (interface, local_ip) = find_interface(&interface_name)?;
+
+    let mut excluded = vec![port];
+    excluded.extend(excluded_ports.iter().filter(|p| **p != port));
+
+    let rule_variables = RuleVariables {
+        access_port: port,
+        interface: interface_name.clone(),
+        listener_ip: local_ip,
+        excluded_ports: excluded.clone(),
     };
 
-    // Create a channel to receive on
-    let (_, mut rx) = match datalink::channel(&interface, Default::default()) {
-        Ok(Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => todo!(),
-        Err(_) => todo!(),
+    let backend = select_backend(firewall, Some(&interface_name));
+    println!("Using the {} firewall backend", backend.name());
+
+    // firewalld authorizes by uid over D-Bus, so a switched user couldn't tear down
+    if user.is_some() && backend.name() == "firewalld" {
+        return Err(SegError::Input(
+            "--user can't be used with the firewalld backend".to_string(),
+        ));
+    }
+
+    if dry_run {
+        if !preview_firewall_rules(backend.as_ref(), rules, &rule_variables, responder.as_ref()) {
+            return Err(SegError::Input(
+                "the ruleset would not apply cleanly".to_string(),
+            ));
+        }
+        return Ok(());
+    }
+
+    acquire_listener_capabilities()?;
+
+    // Filter in the kernel so busy segments don't flood the capture thread
+    let mut program = listener_filter(local_ip, &protocol, &excluded);
+    if let Some(filter) = &bpf_filter {
+        let user_program = compile_filter(&interface_name, filter)?;
+        program = combine(program, user_program);
+    }
+    let capture_rx = open_capture(&interface, &program)?;
+
+    // Setup rules to accept all ports on UDP and TCP
+    let firewall_capabilities = backend.capabilities();
+    setup_firewall_rules(backend, rules, &rule_variables, responder.as_ref())?;
+
+    // A panic anywhere from here on must not leave the host with our ruleset
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        teardown_firewall_rules_blocking();
+    }));
+
+    // The log is opened after the drop, so its directory has to be handed over
+    let log_file_path = log_path(&log, &network_tag);
+    let runtime = drop_listener_privileges(user.as_deref(), &log_file_path, firewall_capabilities)
+        .and_then(|()| tokio::runtime::Runtime::new().context("Failed to start the async runtime"));
+    let runtime = match runtime {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            teardown_firewall_rules_blocking();
+            return Err(e);
+        }
     };
 
-    loop {
-        match rx.next() {
-            Ok(packet) => {
-                if let Some(ethernet) = EthernetPacket::new(packet) {
-                    // Handle IPv4 packets
-                    if let Some(ip_packet) = Ipv4Packet::new(ethernet.payload()) {
-                        if ip_packet.get_destination() == local_ip {
-                            match ip_packet.get_next_level_protocol() {
-                                IpNextHeaderProtocols::Udp => {
-                                    if let Some(udp_packet) = UdpPacket::new(ip_packet.payload()) {
-                                        let source_port = udp_packet.get_source();
-                                        let destination_port = udp_packet.get_source();
-                                        if source_port != access_port
-                                            && destination_port != access_port
-                                        {
-                                            let packet_info = PacketInfo {
-                                                listener_ip: ip_packet.get_destination(),
-                                                network_tag: network_tag.clone(),
-                                                source_ip: ip_packet.get_source(),
-                                                source_port: udp_packet.get_source(),
-                                                target_port: udp_packet.get_destination(),
-                                                protocol: "udp".to_string(),
-                                                flags: vec![],
-                                                timestamp: chrono::Utc::now(),
-                                            };
-                                            write_packet_to_log(log_writer.clone(), &packet_info)
-                                                .await;
-                                            println!(
-                                                "UDP: {}:{} -> {}:{}",
-                                                ip_packet.get_source(),
-                                                udp_packet.get_source(),
-                                                ip_packet.get_destination(),
-                                                udp_packet.get_destination(),
-                                            );
-                                        }
-                                    }
-                                }
-                                IpNextHeaderProtocols::Tcp => {
-                                    if let Some(tcp_packet) = TcpPacket::new(ip_packet.payload()) {
-                                        let source_port = tcp_packet.get_source();
-                                        let destination_port = tcp_packet.get_source();
-                                        let flags = tcp_packet.get_flags();
-                                        if source_port != access_port
-                                            && destination_port != access_port
-                                        {
-                                            let source_port = tcp_packet.get_source();
-                                            let destination_port = tcp_packet.get_destination();
-                                            if source_port != access_port
-                                                && destination_port != access_port
-                                            {
-                                                let packet_info = PacketInfo {
-                                                    listener_ip: ip_packet.get_destination(),
-                                                    network_tag: network_tag.clone(),
-                                                    source_ip: ip_packet.get_source(),
-                                                    source_port: tcp_packet.get_source(),
-                                                    target_port: tcp_packet.get_destination(),
-                                                    protocol: "tcp".to_string(),
-                                                    flags: tcp_flags_to_iter(flags)
-                                                        .map(String::from)
-                                                        .collect(),
-                                                    timestamp: chrono::Utc::now(),
-                                                };
-                                                write_packet_to_log(
-                                                    log_writer.clone(),
-                                                    &packet_info,
-                                                )
-                                                .await;
-                                                println!(
-                                                    "TCP: {}:{} -> {}:{}",
-                                                    ip_packet.get_source(),
-                                                    tcp_packet.get_source(),
-                                                    ip_packet.get_destination(),
-                                                    tcp_packet.get_destination(),
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
+    runtime.block_on(async move {
+        let log_writer = match RotatingLog::open(log_file_path.clone(), rotation).await {
+            Ok(log_writer) => log_writer,
+            Err(e) => {
+                teardown_firewall_rules().await;
+                return Err(SegError::io(
+                    format!("Unable to open log file {:?}", log_file_path),
+                    e,
+                ));
+            }
+        };
+        println!("Logging connections to {:?}", log_writer.path());
+
+        if let Some(confirm_timeout) = confirm_timeout {
+            let check = confirm_check.then_some(port);
+            if !confirm_firewall_rules(confirm_timeout, check, local_ip).await {
+                return Err(SegError::Firewall(
+                    "the firewall rules were not confirmed and have been rolled back".to_string(),
+                ));
+            }
+        }
+
+        // The capture runs on its own thread and feeds a batching writer through a
+        // bounded queue, so slow disks show up as dropped packets instead of a stalled capture
+        let stats = Arc::new(CaptureStats::default());
+        let running = Arc::new(AtomicBool::new(true));
+        let (queue_tx, queue_rx) = mpsc::channel(LOG_QUEUE_CAPACITY);
+        let (summary_tx, summary_rx) = mpsc::channel(1);
+
+        let writer = tokio::spawn(run_log_writer(
+            log_writer,
+            queue_rx,
+            summary_rx,
+            aggregate.map(Aggregator::new),
+            stats.clone(),
+        ));
+
+        let capture_options = CaptureOptions {
+            local_ip,
+            network_tag: network_tag.clone(),
+            access_port: port,
+            excluded_ports,
+            protocol,
+            capture_metadata,
+            quiet,
+        };
+        let mut capture = {
+            let stats = stats.clone();
+            let running = running.clone();
+            tokio::task::spawn_blocking(move || {
+                capture_packets(capture_rx, capture_options, queue_tx, stats, running)
+            })
+        };
+
+        let responder = async {
+            match responder {
+                Some(responder) => run_responder(responder).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let report = {
+            let summary_tx = summary_tx.clone();
+            let stats = stats.clone();
+            let network_tag = network_tag.clone();
+            async move {
+                let mut interval = tokio::time::interval(STATS_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    println!("Listener stats: {}", stats.report());
+                    if let Some(summary) = counter_summary(local_ip, &network_tag, &stats, false) {
+                        let _ = summary_tx.send(summary).await;
                     }
                 }
             }
-            Err(_) => {}
+        };
+
+        // Errors end the listener like a signal does, so the rules are still torn down
+        let mut capture_finished = false;
+        let outcome = tokio::select! {
+            result = &mut capture => {
+                capture_finished = true;
+                Err(SegError::Interface(match result {
+                    Ok(Err(e)) => format!("packet capture on {} failed: {}", interface_name, e),
+                    _ => format!("packet capture on {} stopped unexpectedly", interface_name),
+                }))
+            }
+            result = responder => result,
+            _ = report => Ok(()),
+            signal = shutdown_signal() => {
+                println!("Received {}, shutting down...", signal);
+                Ok(())
+            },
+        };
+
+        // Stop capturing, then let the writer drain the queue, log the final counters and flush
+        running.store(false, Ordering::Relaxed);
+        if !capture_finished {
+            let _ = capture.await;
+        }
+        if let Some(summary) = counter_summary(local_ip, &network_tag, &stats, true) {
+            for counter in &summary.rules {
+                println!(
+                    "Rule {} {}: {} packets, {} bytes",
+                    counter.chain, counter.rule, counter.packets, counter.bytes
+                );
+            }
+            let _ = summary_tx.send(summary).await;
         }
-    }
+        drop(summary_tx);
+        let _ = writer.await;
+        println!("Listener stats: {}", stats.report());
+
+        println!("Cleaning up firewall rules");
+        teardown_firewall_rules().await;
+        outcome
+    })
 }
@@ -0,0 +1,289 @@
+use crate::database::*;
+use crate::error::{Context, Result, SegError};
+use crate::matrix::*;
+use crate::table::*;
+
+use data::*;
+use flate2::read::GzDecoder;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Gexf,
+    Graphml,
+    Mermaid,
+}
+
+pub struct ParseOptions {
+    pub input_files: Vec<String>,
+    pub input_dirs: Vec<String>,
+    pub filter: Option<Filter>,
+    pub out: Option<String>,
+    pub csv: bool,
+    pub netflow: bool,
+    pub matrix: Option<MatrixFormat>,
+    pub graph: Option<GraphFormat>,
+    pub sqlite: bool,
+    pub parquet: bool,
+    pub table: TableOptions,
+}
+
+/// The network a scanner sat in, its address masked to `--source-prefix` bits.
+/// Listener logs only know the scanner's address, the listener side is the network tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceNetwork {
+    pub address: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl SourceNetwork {
+    pub fn of(ip: Ipv4Addr, prefix: u8) -> Self {
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        Self {
+            address: Ipv4Addr::from(u32::from(ip) & mask),
+            prefix,
+        }
+    }
+}
+
+impl fmt::Display for SourceNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// Formats ports as ranges, e.g. `22,80,443-445`, in any order and with duplicates.
+pub fn format_port_ranges<'a>(ports: impl IntoIterator<Item = &'a u16>) -> String {
+    let ports: BTreeSet<&u16> = ports.into_iter().collect();
+    let mut ranges: Vec<(u16, u16)> = vec![];
+    for port in ports {
+        match ranges.last_mut() {
+            Some((_, end)) if end.checked_add(1) == Some(*port) => *end = *port,
+            _ => ranges.push((*port, *port)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Every log in `dirs`, including gzipped rotation archives, in name order.
+fn logs_in_dir(dir: &str) -> Result<Vec<PathBuf>> {
+    let mut logs: Vec<PathBuf> = std::fs::read_dir(dir)
+        .context(format!("Unable to read input dir {}", dir))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        [".log", ".jsonl", ".log.gz", ".jsonl.gz"]
+                            .iter()
+                            .any(|ext| name.ends_with(ext))
+                    })
+        })
+        .collect();
+    logs.sort();
+    Ok(logs)
+}
+
+fn open_log(path: &Path) -> io::Result<LogReader<Box<dyn BufRead>>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn BufRead> = if path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(BufReader::new(GzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+    Ok(LogReader::new(reader))
+}
+
+/// Everything read from a set of listener logs.
+pub struct Logs {
+    pub packets: Vec<PacketInfo>,
+    pub counters: Vec<CounterSummary>,
+    /// Each input file with the number of records read from it.
+    pub files: Vec<(PathBuf, usize)>,
+}
+
+/// Reads every input log, keeping only the packets `filter` matches. Records that
+/// fail to parse are skipped with a warning.
+pub fn load_logs(
+    input_files: &[String],
+    input_dirs: &[String],
+    filter: Option<&Filter>,
+) -> Result<Logs> {
+    let mut paths: Vec<PathBuf> = input_files.iter().map(PathBuf::from).collect();
+    for dir in input_dirs {
+        paths.extend(logs_in_dir(dir)?);
+    }
+
+    let mut logs = Logs {
+        packets: vec![],
+        counters: vec![],
+        files: vec![],
+    };
+    for path in paths {
+        let reader =
+            open_log(&path).context(format!("Unable to open input file {}", path.display()))?;
+        let mut records = 0;
+        for record in reader {
+            match record {
+                Ok(record) => {
+                    records += 1;
+                    match record.entry {
+                        LogEntry::Packet(packet) => {
+                            if filter.is_none_or(|filter| filter.matches(&packet)) {
+                                logs.packets.push(packet);
+                            }
+                        }
+                        LogEntry::Counters(summary) => logs.counters.push(summary),
+                    }
+                }
+                Err(e) => eprintln!("Skipping record in {}: {}", path.display(), e),
+            }
+        }
+        logs.files.push((path, records));
+    }
+    Ok(logs)
+}
+
+/// Reads the packets of every input log, counter summaries are skipped.
+pub fn load_packets(
+    input_files: &[String],
+    input_dirs: &[String],
+    filter: Option<&Filter>,
+) -> Result<Vec<PacketInfo>> {
+    Ok(load_logs(input_files, input_dirs, filter)?.packets)
+}
+
+/// Writes to `out`, or to stdout when it's omitted or `-`.
+pub fn open_output(out: Option<&str>) -> Result<Box<dyn Write>> {
+    match out {
+        Some(path) if path != "-" => {
+            let file = File::create(path).context(format!("Unable to create {}", path))?;
+            Ok(Box::new(io::BufWriter::new(file)))
+        }
+        _ => Ok(Box::new(io::stdout().lock())),
+    }
+}
+
+pub fn run_parse(options: ParseOptions) -> Result<()> {
+    if options.netflow {
+        return Err(SegError::Input(
+            "Netflow output is not implemented yet".to_string(),
+        ));
+    }
+    if !options.csv
+        && options.matrix.is_none()
+        && options.graph.is_none()
+        && !options.sqlite
+        && !options.parquet
+    {
+        return Err(SegError::Input(
+            "Choose an output format, e.g. --csv, --matrix, --graph or --sqlite".to_string(),
+        ));
+    }
+
+    let packets = load_packets(
+        &options.input_files,
+        &options.input_dirs,
+        options.filter.as_ref(),
+    )?;
+    if options.sqlite || options.parquet {
+        // clap requires --out with --sqlite and --parquet
+        let out = options.out.as_deref().unwrap_or_default();
+        let normalized = normalize(&packets, options.table.source_prefix);
+        if options.sqlite {
+            write_sqlite(out, &normalized)?;
+        } else {
+            write_parquet(out, &normalized)?;
+        }
+        eprintln!(
+            "Wrote {} records in {} sessions between {} hosts to {}",
+            normalized.records.len(),
+            normalized.sessions.len(),
+            normalized.hosts.len(),
+            out
+        );
+        return Ok(());
+    }
+    if let Some(format) = options.graph {
+        let graph = build_graph(&packets);
+        let text = match format {
+            GraphFormat::Dot => graph.to_dot(),
+            GraphFormat::Gexf => graph.to_gexf(),
+            GraphFormat::Graphml => graph.to_graphml(),
+            GraphFormat::Mermaid => graph.to_mermaid(),
+        };
+        let mut output = open_output(options.out.as_deref())?;
+        output
+            .write_all(text.as_bytes())
+            .and_then(|()| output.flush())
+            .context(format!(
+                "Failed to write the graph to {}",
+                options.out.as_deref().unwrap_or("stdout")
+            ))?;
+        if let Some(out) = &options.out {
+            eprintln!(
+                "Wrote a graph of {} nodes and {} links from {} packets to {}",
+                graph.nodes.len(),
+                graph.links.len(),
+                packets.len(),
+                out
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(format) = options.matrix {
+        let matrix = Matrix::new(&packets, options.table.source_prefix);
+        let output = open_output(options.out.as_deref())?;
+        write_matrix(output, &matrix, format).context(format!(
+            "Failed to write the matrix to {}",
+            options.out.as_deref().unwrap_or("stdout")
+        ))?;
+        if let Some(out) = &options.out {
+            eprintln!(
+                "Wrote a {}x{} matrix from {} packets to {}",
+                matrix.sources.len(),
+                matrix.destinations.len(),
+                packets.len(),
+                out
+            );
+        }
+        return Ok(());
+    }
+
+    let rows = build_rows(&packets, &options.table);
+    let output = open_output(options.out.as_deref())?;
+    write_csv(output, &rows, &options.table).context(format!(
+        "Failed to write CSV to {}",
+        options.out.as_deref().unwrap_or("stdout")
+    ))?;
+
+    if let Some(out) = &options.out {
+        eprintln!(
+            "Wrote {} rows from {} packets to {}",
+            rows.len(),
+            packets.len(),
+            out
+        );
+    }
+    Ok(())
+}
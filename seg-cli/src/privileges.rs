@@ -0,0 +1,292 @@
+use crate::consts::{CAP_NET_ADMIN, CAP_NET_RAW};
+use crate::error::{Context, Result, SegError};
+use crate::firewall::fork_teardown_helper;
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+/// Highest capability number we bother dropping from the bounding set.
+const CAP_LAST: u32 = 63;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// The process' capability sets as 64 bit masks.
+struct CapSets {
+    effective: u64,
+    permitted: u64,
+    inheritable: u64,
+}
+
+fn mask(cap: u32) -> u64 {
+    1 << cap
+}
+
+fn capget() -> io::Result<CapSets> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let join = |low: u32, high: u32| (low as u64) | ((high as u64) << 32);
+    Ok(CapSets {
+        effective: join(data[0].effective, data[1].effective),
+        permitted: join(data[0].permitted, data[1].permitted),
+        inheritable: join(data[0].inheritable, data[1].inheritable),
+    })
+}
+
+fn capset(sets: &CapSets) -> io::Result<()> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let split = |set: u64| (set as u32, (set >> 32) as u32);
+    let (effective, effective_high) = split(sets.effective);
+    let (permitted, permitted_high) = split(sets.permitted);
+    let (inheritable, inheritable_high) = split(sets.inheritable);
+    let data = [
+        CapData {
+            effective,
+            permitted,
+            inheritable,
+        },
+        CapData {
+            effective: effective_high,
+            permitted: permitted_high,
+            inheritable: inheritable_high,
+        },
+    ];
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn prctl(option: libc::c_int, arg: libc::c_ulong) -> io::Result<()> {
+    let zero: libc::c_ulong = 0;
+    if unsafe { libc::prctl(option, arg, zero, zero, zero) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes `caps` effective and passes them on to the commands we run (nft, iptables,
+/// ...). Root needs nothing, without root the binary must carry them as file
+/// capabilities, e.g. `setcap cap_net_raw,cap_net_admin+ep seg`.
+fn raise_capabilities(caps: &[u32]) -> io::Result<()> {
+    let wanted = caps.iter().fold(0, |set, cap| set | mask(*cap));
+    let mut sets = capget()?;
+    if sets.permitted & wanted != wanted {
+        return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+    }
+
+    sets.effective |= wanted;
+    sets.inheritable |= wanted;
+    capset(&sets)?;
+
+    // Without ambient capabilities a non-root exec drops everything
+    for cap in caps {
+        ambient_raise(*cap)?;
+    }
+    Ok(())
+}
+
+fn ambient_raise(cap: u32) -> io::Result<()> {
+    let zero: libc::c_ulong = 0;
+    if unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+            cap as libc::c_ulong,
+            zero,
+            zero,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes sure the listener has CAP_NET_RAW and CAP_NET_ADMIN, whether it runs as root
+/// or from a binary with file capabilities.
+pub fn acquire_listener_capabilities() -> Result<()> {
+    if unsafe { libc::geteuid() } == 0 {
+        return Ok(());
+    }
+
+    raise_capabilities(&[CAP_NET_RAW, CAP_NET_ADMIN]).map_err(|e| {
+        SegError::Permission(format!(
+            "seg listen needs root or the CAP_NET_RAW and CAP_NET_ADMIN capabilities \
+             (setcap cap_net_raw,cap_net_admin+ep $(which seg)): {}",
+            e
+        ))
+    })
+}
+
+/// The uid and gid of a local user.
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let c_name =
+        CString::new(name).map_err(|_| SegError::Input(format!("Invalid user name {}", name)))?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return Err(SegError::Input(format!("User {} does not exist", name)));
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Hands the log's directory to the user the listener switches to, since rotation and
+/// new logs create files after the drop. An existing directory is only handed over when
+/// it holds nothing but this log and its archives, so a log in the working directory
+/// doesn't give away someone's home. seg's state directory stays root's.
+fn chown_log_dir(log: &Path, uid: libc::uid_t, gid: libc::gid_t) -> Result<()> {
+    let dir = log
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let chown = |path: &Path| {
+        std::os::unix::fs::chown(path, Some(uid), Some(gid)).map_err(|e| {
+            SegError::io(
+                format!("Failed to hand {} to uid {}", path.display(), uid),
+                e,
+            )
+        })
+    };
+
+    if !dir.exists() {
+        // Create it now and hand over every level we created
+        let existing = dir
+            .ancestors()
+            .find(|ancestor| ancestor.exists())
+            .unwrap_or(Path::new("/"))
+            .to_path_buf();
+        std::fs::create_dir_all(dir)
+            .context(format!("Unable to create log directory {}", dir.display()))?;
+        for created in dir.ancestors().take_while(|ancestor| *ancestor != existing) {
+            chown(created)?;
+        }
+        return Ok(());
+    }
+
+    // The log and its archives are named after the log's stem
+    let stem = log
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let entries: Vec<std::fs::DirEntry> = std::fs::read_dir(dir)
+        .context(format!("Unable to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .collect();
+    let of_this_log = |entry: &std::fs::DirEntry| {
+        entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&format!("{}.", stem))
+    };
+
+    let metadata = std::fs::metadata(dir).context(format!("Unable to read {}", dir.display()))?;
+    if metadata.uid() != uid {
+        if !entries.iter().all(of_this_log) {
+            return Err(SegError::Input(format!(
+                "Log directory {} holds other files and doesn't belong to uid {}, point --log at a directory of its own",
+                dir.display(),
+                uid
+            )));
+        }
+        chown(dir)?;
+    }
+    for entry in entries.iter().filter(|entry| of_this_log(entry)) {
+        chown(&entry.path())?;
+    }
+    Ok(())
+}
+
+fn switch_user(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    // Keep the permitted set across the uid change, the effective set is re-raised after
+    prctl(libc::PR_SET_KEEPCAPS, 1)?;
+    unsafe {
+        if libc::setgroups(1, &gid) != 0
+            || libc::setresgid(gid, gid, gid) != 0
+            || libc::setresuid(uid, uid, uid) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Called once the capture socket is open and the rules are applied. Keeps only `caps`,
+/// what the firewall backend's tools need to restore the ruleset, and with `user` set
+/// switches to that user, handing it the log's directory. Teardown then runs in a
+/// helper forked beforehand that stays root, since seg's state directory doesn't
+/// belong to the user.
+pub fn drop_listener_privileges(user: Option<&str>, log: &Path, caps: &[u32]) -> Result<()> {
+    let keep = caps.iter().fold(0, |set, cap| set | mask(*cap));
+
+    let target = user.map(lookup_user).transpose()?;
+    if let Some((uid, gid)) = target {
+        chown_log_dir(log, uid, gid)?;
+        fork_teardown_helper()
+            .map_err(|e| SegError::io("Failed to start the teardown helper", e))?;
+    }
+
+    // Needs CAP_SETPCAP, which only root has, so a capability-only run skips it
+    for cap in (0..=CAP_LAST).filter(|cap| mask(*cap) & keep == 0) {
+        if prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong).is_err() {
+            break;
+        }
+    }
+
+    if let Some((uid, gid)) = target {
+        switch_user(uid, gid)
+            .map_err(|e| SegError::io(format!("Failed to switch to user {:?}", user), e))?;
+    }
+
+    capset(&CapSets {
+        effective: keep,
+        permitted: keep,
+        inheritable: keep,
+    })
+    .map_err(|e| SegError::io("Failed to drop capabilities", e))?;
+    if unsafe { libc::geteuid() } != 0 {
+        for cap in caps {
+            ambient_raise(*cap).map_err(|e| {
+                SegError::io("Failed to keep the firewall's capabilities for teardown", e)
+            })?;
+        }
+    }
+    prctl(libc::PR_SET_NO_NEW_PRIVS, 1)
+        .map_err(|e| SegError::io("Failed to set no_new_privs", e))?;
+
+    Ok(())
+}
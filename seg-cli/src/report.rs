@@ -0,0 +1,536 @@
+use crate::error::{Context, Result, SegError};
+use crate::matrix::*;
+use crate::parse::*;
+use crate::table::*;
+use crate::util::escape_html;
+
+use chrono::{DateTime, Utc};
+use data::{CounterSummary, Filter};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+pub struct ReportOptions {
+    pub input_files: Vec<String>,
+    pub input_dirs: Vec<String>,
+    pub filter: Option<Filter>,
+    pub scan_logs: Vec<String>,
+    pub policy: Option<String>,
+    pub out: String,
+    pub title: String,
+    pub source_prefix: u8,
+}
+
+/// One line of a policy file: traffic that is allowed to cross segments. A field left
+/// as `*` matches anything.
+struct PolicyRule {
+    source: Option<SourceNetwork>,
+    destination: Option<String>,
+    protocol: Option<String>,
+    ports: Option<Vec<(u16, u16)>>,
+}
+
+impl PolicyRule {
+    fn allows(&self, source: SourceNetwork, destination: &str, protocol: &str, port: u16) -> bool {
+        let source_matches = self.source.is_none_or(|allowed| {
+            source.prefix >= allowed.prefix
+                && SourceNetwork::of(source.address, allowed.prefix) == allowed
+        });
+        source_matches
+            && self.destination.as_deref().is_none_or(|d| d == destination)
+            && self.protocol.as_deref().is_none_or(|p| p == protocol)
+            && self.ports.as_ref().is_none_or(|ranges| {
+                ranges
+                    .iter()
+                    .any(|(start, end)| (*start..=*end).contains(&port))
+            })
+    }
+}
+
+fn parse_port_ranges(value: &str) -> Option<Vec<(u16, u16)>> {
+    value
+        .split(',')
+        .map(|range| match range.trim().split_once('-') {
+            Some((start, end)) => Some((start.trim().parse().ok()?, end.trim().parse().ok()?)),
+            None => {
+                let port = range.trim().parse().ok()?;
+                Some((port, port))
+            }
+        })
+        .collect()
+}
+
+/// Reads a policy file of `source,destination,protocol,ports` lines, e.g.
+/// `10.0.1.0/24,pci,tcp,22,443`. Blank lines and `#` comments are ignored.
+fn load_policy(path: &str) -> Result<Vec<PolicyRule>> {
+    let text = std::fs::read_to_string(path).context(format!("Unable to read policy {}", path))?;
+    let invalid = |line: usize, reason: &str| {
+        SegError::Input(format!("Invalid policy {} line {}: {}", path, line, reason))
+    };
+
+    let mut rules = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(4, ',').map(str::trim).collect();
+        let [source, destination, protocol, ports] = fields[..] else {
+            return Err(invalid(
+                idx + 1,
+                "expected source,destination,protocol,ports",
+            ));
+        };
+        let any = |field: &str| field == "*";
+
+        let source = if any(source) {
+            None
+        } else {
+            let (address, prefix) = source.split_once('/').unwrap_or((source, "32"));
+            let address: Ipv4Addr = address
+                .parse()
+                .map_err(|_| invalid(idx + 1, "bad source network"))?;
+            let prefix: u8 = prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= 32)
+                .ok_or_else(|| invalid(idx + 1, "bad source prefix"))?;
+            Some(SourceNetwork::of(address, prefix))
+        };
+        let ports = if any(ports) {
+            None
+        } else {
+            Some(parse_port_ranges(ports).ok_or_else(|| invalid(idx + 1, "bad ports"))?)
+        };
+
+        rules.push(PolicyRule {
+            source,
+            destination: (!any(destination)).then(|| destination.to_string()),
+            protocol: (!any(protocol)).then(|| protocol.to_lowercase()),
+            ports,
+        });
+    }
+    Ok(rules)
+}
+
+/// Ports a scan reported open, from nmap's normal output (`seg scan` writes these).
+struct ScanResult {
+    file: String,
+    target: String,
+    open: Vec<String>,
+}
+
+fn load_scan_log(path: &str) -> Result<Vec<ScanResult>> {
+    let text =
+        std::fs::read_to_string(path).context(format!("Unable to read scan log {}", path))?;
+    let file = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string());
+
+    let mut results: Vec<ScanResult> = vec![];
+    for line in text.lines() {
+        if let Some(target) = line.strip_prefix("Nmap scan report for ") {
+            results.push(ScanResult {
+                file: file.clone(),
+                target: target.trim().to_string(),
+                open: vec![],
+            });
+            continue;
+        }
+
+        // e.g. "22/tcp   open  ssh"
+        let mut words = line.split_whitespace();
+        if let (Some(port), Some(state), Some(result)) =
+            (words.next(), words.next(), results.last_mut())
+        {
+            if port.contains('/') && state == "open" {
+                result.open.push(port.to_string());
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Findings for one destination segment, reachable ports split by whether the policy
+/// allows them.
+struct Finding {
+    source_network: SourceNetwork,
+    protocol: String,
+    allowed: Option<bool>,
+    row: Row,
+}
+
+fn findings(rows: Vec<Row>, policy: Option<&[PolicyRule]>) -> BTreeMap<String, Vec<Finding>> {
+    let mut grouped: BTreeMap<(String, SourceNetwork, String, Option<bool>), Row> = BTreeMap::new();
+    for row in rows {
+        let port = *row.ports.first().unwrap_or(&0);
+        let allowed = policy.map(|rules| {
+            rules.iter().any(|rule| {
+                rule.allows(
+                    row.source_network,
+                    &row.destination_network,
+                    &row.protocol,
+                    port,
+                )
+            })
+        });
+        let key = (
+            row.destination_network.clone(),
+            row.source_network,
+            row.protocol.clone(),
+            allowed,
+        );
+        match grouped.get_mut(&key) {
+            Some(group) => group.merge(row),
+            None => {
+                grouped.insert(key, row);
+            }
+        }
+    }
+
+    let mut by_segment: BTreeMap<String, Vec<Finding>> = BTreeMap::new();
+    for ((destination, source_network, protocol, allowed), row) in grouped {
+        by_segment.entry(destination).or_default().push(Finding {
+            source_network,
+            protocol,
+            allowed,
+            row,
+        });
+    }
+    by_segment
+}
+
+/// Source networks on the left, segments on the right, an edge for every pair that
+/// reached anything. Red edges carry traffic the policy doesn't allow.
+fn graph_svg(matrix: &Matrix, violations: &BTreeSet<(SourceNetwork, String)>) -> String {
+    const ROW: usize = 40;
+    const WIDTH: usize = 720;
+    const NODE_WIDTH: usize = 180;
+    let rows = matrix.sources.len().max(matrix.destinations.len()).max(1);
+    let height = rows * ROW + 20;
+    let y = |idx: usize| 20 + idx * ROW + ROW / 2;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"sans-serif\" font-size=\"12\">\n",
+        WIDTH, height
+    );
+    for (s, source) in matrix.sources.iter().enumerate() {
+        for (d, destination) in matrix.destinations.iter().enumerate() {
+            let cell = matrix.cell(*source, destination);
+            if cell.is_empty() {
+                continue;
+            }
+            let color = if violations.contains(&(*source, destination.clone())) {
+                "#c0392b"
+            } else {
+                "#555"
+            };
+            let _ = writeln!(
+                svg,
+                "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\" stroke-width=\"2\"><title>{} to {}: {}</title></line>",
+                10 + NODE_WIDTH,
+                y(s),
+                WIDTH - 10 - NODE_WIDTH,
+                y(d),
+                color,
+                source,
+                escape_html(destination),
+                escape_html(&cell)
+            );
+        }
+    }
+
+    let mut node = |x: usize, idx: usize, label: &str, fill: &str| {
+        let _ = writeln!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" fill=\"{}\" stroke=\"#333\"/><text x=\"{}\" y=\"{}\" text-anchor=\"middle\" dominant-baseline=\"middle\">{}</text>",
+            x,
+            y(idx) - 12,
+            NODE_WIDTH,
+            24,
+            fill,
+            x + NODE_WIDTH / 2,
+            y(idx),
+            escape_html(label)
+        );
+    };
+    for (idx, source) in matrix.sources.iter().enumerate() {
+        node(10, idx, &source.to_string(), "#d6eaf8");
+    }
+    for (idx, destination) in matrix.destinations.iter().enumerate() {
+        node(WIDTH - 10 - NODE_WIDTH, idx, destination, "#fdebd0");
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+const REPORT_CSS: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+h1 { margin-bottom: 0; }
+table.data { border-collapse: collapse; margin-bottom: 1em; }
+table.data th, table.data td { border: 1px solid #999; padding: 4px 8px; text-align: left; }
+table.data th { background: #eee; }
+tr.violation td { background: #f8d7da; }
+.muted { color: #666; }
+";
+
+fn time_window(packets: &[data::PacketInfo]) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let first = packets.iter().map(|packet| packet.timestamp).min()?;
+    let last = packets.iter().map(|packet| packet.last_seen()).max()?;
+    Some((first, last))
+}
+
+/// The last counter summary of every listener, ideally the one written at shutdown.
+fn final_counters(counters: &[CounterSummary]) -> Vec<&CounterSummary> {
+    let mut latest: BTreeMap<(String, Ipv4Addr), &CounterSummary> = BTreeMap::new();
+    for summary in counters {
+        let key = (summary.network_tag.clone(), summary.listener_ip);
+        let newer = latest.get(&key).is_none_or(|current| {
+            (summary.is_final, summary.timestamp) >= (current.is_final, current.timestamp)
+        });
+        if newer {
+            latest.insert(key, summary);
+        }
+    }
+    latest.into_values().collect()
+}
+
+/// Writes a single self-contained HTML report: no scripts, fonts or images are
+/// loaded, so it can be mailed to a client and opened offline.
+pub fn run_report(options: ReportOptions) -> Result<()> {
+    let logs = load_logs(
+        &options.input_files,
+        &options.input_dirs,
+        options.filter.as_ref(),
+    )?;
+    let policy = options.policy.as_deref().map(load_policy).transpose()?;
+    let mut scans = vec![];
+    for scan_log in &options.scan_logs {
+        scans.extend(load_scan_log(scan_log)?);
+    }
+
+    let packets = &logs.packets;
+    let matrix = Matrix::new(packets, options.source_prefix);
+    let rows = build_rows(
+        packets,
+        &TableOptions {
+            columns: vec![],
+            aggregate: true,
+            collapse_ports: false,
+            source_prefix: options.source_prefix,
+        },
+    );
+    let findings = findings(rows, policy.as_deref());
+    let violations: BTreeSet<(SourceNetwork, String)> = findings
+        .iter()
+        .flat_map(|(segment, findings)| {
+            findings
+                .iter()
+                .filter(|finding| finding.allowed == Some(false))
+                .map(move |finding| (finding.source_network, segment.clone()))
+        })
+        .collect();
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\n{}{}</style>\n</head>\n<body>\n<h1>{title}</h1>\n<p class=\"muted\">Generated {} by seg {}</p>\n",
+        REPORT_CSS,
+        MATRIX_CSS,
+        Utc::now().to_rfc3339(),
+        env!("CARGO_PKG_VERSION"),
+        title = escape_html(&options.title)
+    );
+
+    // Summary
+    let scanners: BTreeSet<Ipv4Addr> = packets.iter().map(|packet| packet.source_ip).collect();
+    let ports: BTreeSet<(&str, u16)> = packets
+        .iter()
+        .map(|packet| (packet.protocol.as_str(), packet.target_port))
+        .collect();
+    let reachable_pairs = matrix
+        .sources
+        .iter()
+        .flat_map(|source| {
+            matrix
+                .destinations
+                .iter()
+                .filter(|destination| !matrix.cell(*source, destination).is_empty())
+        })
+        .count();
+    html.push_str("<h2>Summary</h2>\n<table class=\"data\">\n");
+    let mut summary_row = |name: &str, value: String| {
+        let _ = writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            name,
+            escape_html(&value)
+        );
+    };
+    summary_row(
+        "Packets captured",
+        packets
+            .iter()
+            .map(|packet| packet.count())
+            .sum::<u64>()
+            .to_string(),
+    );
+    summary_row("Scanner addresses", scanners.len().to_string());
+    summary_row("Source networks", matrix.sources.len().to_string());
+    summary_row(
+        "Destination segments",
+        matrix.destinations.len().to_string(),
+    );
+    summary_row(
+        "Reachable network pairs",
+        format!(
+            "{} of {}",
+            reachable_pairs,
+            matrix.sources.len() * matrix.destinations.len()
+        ),
+    );
+    summary_row("Distinct ports reached", ports.len().to_string());
+    if policy.is_some() {
+        summary_row("Pairs violating the policy", violations.len().to_string());
+    }
+    html.push_str("</table>\n");
+
+    // Reachability
+    html.push_str("<h2>Reachability matrix</h2>\n<p>Ports that got from each source network into each segment.</p>\n");
+    html.push_str(&matrix.html_table());
+    html.push_str("<h2>Graph</h2>\n");
+    html.push_str(&graph_svg(&matrix, &violations));
+
+    // Findings
+    html.push_str("<h2>Findings per segment</h2>\n");
+    if findings.is_empty() {
+        html.push_str("<p>No traffic reached any listener.</p>\n");
+    }
+    for (segment, segment_findings) in &findings {
+        let listeners: BTreeSet<Ipv4Addr> = segment_findings
+            .iter()
+            .flat_map(|finding| finding.row.listener_ips.iter().copied())
+            .collect();
+        let _ = write!(
+            html,
+            "<h3>{}</h3>\n<p class=\"muted\">Listeners: {}</p>\n<table class=\"data\">\n<tr><th>Source network</th><th>Protocol</th><th>Ports</th><th>Packets</th><th>Scanners</th><th>First seen</th><th>Last seen</th>{}</tr>\n",
+            escape_html(segment),
+            listeners
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            if policy.is_some() { "<th>Policy</th>" } else { "" }
+        );
+        for finding in segment_findings {
+            let (class, status) = match finding.allowed {
+                Some(true) => ("", "<td>allowed</td>"),
+                Some(false) => (" class=\"violation\"", "<td>violation</td>"),
+                None => ("", ""),
+            };
+            let _ = writeln!(
+                html,
+                "<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>{}</tr>",
+                class,
+                finding.source_network,
+                escape_html(&finding.protocol),
+                format_port_ranges(&finding.row.ports),
+                finding.row.count,
+                finding.row.source_ips.len(),
+                finding.row.first_seen.to_rfc3339(),
+                finding.row.last_seen.to_rfc3339(),
+                status
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    if !scans.is_empty() {
+        html.push_str("<h2>Scanner view</h2>\n<table class=\"data\">\n<tr><th>Scan log</th><th>Target</th><th>Open ports</th></tr>\n");
+        for scan in &scans {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&scan.file),
+                escape_html(&scan.target),
+                escape_html(&scan.open.join(", "))
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    // Methodology
+    html.push_str("<h2>Methodology</h2>\n<p>Listeners in each segment accepted and logged every TCP and UDP probe reaching them while scanners in the source networks swept all ports. Anything in this report got through the segmentation controls between the two.</p>\n<table class=\"data\">\n");
+    let mut method_row = |name: &str, value: String| {
+        let _ = writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            name,
+            escape_html(&value)
+        );
+    };
+    if let Some((first, last)) = time_window(packets) {
+        method_row(
+            "Observation window",
+            format!("{} to {}", first.to_rfc3339(), last.to_rfc3339()),
+        );
+    }
+    method_row(
+        "Source networks",
+        format!(
+            "scanner addresses grouped into /{} networks",
+            options.source_prefix
+        ),
+    );
+    if let Some(filter) = &options.filter {
+        method_row("Filter", filter.to_string());
+    }
+    method_row(
+        "Policy",
+        options
+            .policy
+            .clone()
+            .unwrap_or_else(|| "none, findings are not classified".to_string()),
+    );
+    for (path, records) in &logs.files {
+        method_row(
+            "Listener log",
+            format!("{} ({} records)", path.display(), records),
+        );
+    }
+    for scan_log in &options.scan_logs {
+        method_row("Scan log", scan_log.clone());
+    }
+    for summary in final_counters(&logs.counters) {
+        let rules = summary
+            .rules
+            .iter()
+            .map(|rule| format!("{} {}: {} packets", rule.chain, rule.rule, rule.packets))
+            .collect::<Vec<_>>()
+            .join(", ");
+        method_row(
+            "Firewall counters",
+            format!(
+                "{} {}: {} captured, {}",
+                summary.network_tag, summary.listener_ip, summary.captured, rules
+            ),
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    let mut output =
+        std::fs::File::create(&options.out).context(format!("Unable to create {}", options.out))?;
+    output
+        .write_all(html.as_bytes())
+        .context(format!("Failed to write report {}", options.out))?;
+
+    println!(
+        "Wrote report on {} packets across {} segments to {}",
+        packets.len(),
+        matrix.destinations.len(),
+        options.out
+    );
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use crate::error::{Context, Result};
+use crate::network::ScanProtocol;
+
+use tokio::net::{TcpListener, UdpSocket};
+
+#[derive(Clone, clap::ValueEnum)]
+pub enum UdpReply {
+    /// Don't answer UDP probes
+    None,
+    /// Send the probe payload (or --udp-payload) back to the sender
+    Echo,
+}
+
+/// Settings for answering probes so scanners see reachable ports as open.
+/// Probes are redirected to `port` by the firewall, which means the kernel completes
+/// TCP handshakes for us and conntrack rewrites replies back to the probed port.
+#[derive(Clone)]
+pub struct ResponderOptions {
+    pub port: u16,
+    pub protocol: ScanProtocol,
+    pub udp_reply: UdpReply,
+    pub udp_payload: Option<String>,
+}
+
+/// Answers probes until a responder socket fails.
+pub async fn run_responder(options: ResponderOptions) -> Result<()> {
+    let tcp = async {
+        if matches!(options.protocol, ScanProtocol::UDP) {
+            return std::future::pending().await;
+        }
+        respond_tcp(options.port).await
+    };
+    let udp = async {
+        if matches!(options.protocol, ScanProtocol::TCP)
+            || matches!(options.udp_reply, UdpReply::None)
+        {
+            return std::future::pending().await;
+        }
+        respond_udp(options.port, options.udp_payload.clone()).await
+    };
+
+    tokio::try_join!(tcp, udp)?;
+    Ok(())
+}
+
+async fn respond_tcp(port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await.context(format!(
+        "Failed to bind TCP responder socket on port {}",
+        port
+    ))?;
+
+    println!("Responding to TCP probes via port {}", port);
+    loop {
+        // The handshake is already complete by the time we accept, so just close it
+        if let Err(e) = listener.accept().await {
+            eprintln!("Failed to accept responder connection: {}", e);
+        }
+    }
+}
+
+async fn respond_udp(port: u16, payload: Option<String>) -> Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port)).await.context(format!(
+        "Failed to bind UDP responder socket on port {}",
+        port
+    ))?;
+
+    println!("Responding to UDP probes via port {}", port);
+    let mut buf = [0u8; 65535];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("Failed to receive on UDP responder: {}", e);
+                continue;
+            }
+        };
+
+        let reply = match &payload {
+            Some(payload) => payload.as_bytes(),
+            None => &buf[..len],
+        };
+        if let Err(e) = socket.send_to(reply, peer).await {
+            eprintln!("Failed to answer UDP probe from {}: {}", peer, e);
+        }
+    }
+}
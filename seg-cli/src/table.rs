@@ -0,0 +1,323 @@
+use crate::parse::*;
+
+use chrono::{DateTime, Utc};
+use data::PacketInfo;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+
+/// A CSV column. Values that a row aggregates over are joined with spaces, ports are
+/// written as ranges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum Column {
+    FirstSeen,
+    LastSeen,
+    SourceNetwork,
+    SourceIp,
+    SourcePort,
+    DestinationNetwork,
+    ListenerIp,
+    Port,
+    Protocol,
+    Flags,
+    /// Packets the row stands for.
+    Count,
+    /// Distinct scanner addresses.
+    Sources,
+}
+
+const PACKET_COLUMNS: &[Column] = &[
+    Column::FirstSeen,
+    Column::SourceNetwork,
+    Column::SourceIp,
+    Column::SourcePort,
+    Column::DestinationNetwork,
+    Column::ListenerIp,
+    Column::Port,
+    Column::Protocol,
+    Column::Flags,
+    Column::Count,
+];
+
+const AGGREGATE_COLUMNS: &[Column] = &[
+    Column::SourceNetwork,
+    Column::DestinationNetwork,
+    Column::Port,
+    Column::Protocol,
+    Column::Count,
+    Column::Sources,
+    Column::FirstSeen,
+    Column::LastSeen,
+];
+
+pub struct TableOptions {
+    /// Defaults depend on whether rows are aggregated.
+    pub columns: Vec<Column>,
+    /// One row per (source network, destination network, port, protocol).
+    pub aggregate: bool,
+    /// With `aggregate`, one row per network pair and protocol listing all its ports.
+    pub collapse_ports: bool,
+    pub source_prefix: u8,
+}
+
+pub struct Row {
+    pub source_network: SourceNetwork,
+    pub destination_network: String,
+    pub source_ips: BTreeSet<Ipv4Addr>,
+    pub source_ports: BTreeSet<u16>,
+    pub listener_ips: BTreeSet<Ipv4Addr>,
+    pub ports: BTreeSet<u16>,
+    pub protocol: String,
+    pub flags: BTreeSet<String>,
+    pub count: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl Row {
+    fn new(packet: &PacketInfo, source_prefix: u8) -> Self {
+        Self {
+            source_network: SourceNetwork::of(packet.source_ip, source_prefix),
+            destination_network: packet.network_tag.clone(),
+            source_ips: BTreeSet::from([packet.source_ip]),
+            source_ports: BTreeSet::from([packet.source_port]),
+            listener_ips: BTreeSet::from([packet.listener_ip]),
+            ports: BTreeSet::from([packet.target_port]),
+            protocol: packet.protocol.clone(),
+            flags: packet.flags.iter().cloned().collect(),
+            count: packet.count(),
+            first_seen: packet.timestamp,
+            last_seen: packet.last_seen(),
+        }
+    }
+
+    pub fn merge(&mut self, other: Row) {
+        self.source_ips.extend(other.source_ips);
+        self.source_ports.extend(other.source_ports);
+        self.listener_ips.extend(other.listener_ips);
+        self.ports.extend(other.ports);
+        self.flags.extend(other.flags);
+        self.count += other.count;
+        self.first_seen = self.first_seen.min(other.first_seen);
+        self.last_seen = self.last_seen.max(other.last_seen);
+    }
+
+    fn value(&self, column: Column) -> String {
+        let join = |values: Vec<String>| values.join(" ");
+        match column {
+            Column::FirstSeen => self.first_seen.to_rfc3339(),
+            Column::LastSeen => self.last_seen.to_rfc3339(),
+            Column::SourceNetwork => self.source_network.to_string(),
+            Column::SourceIp => join(self.source_ips.iter().map(|ip| ip.to_string()).collect()),
+            Column::SourcePort => format_port_ranges(&self.source_ports),
+            Column::DestinationNetwork => self.destination_network.clone(),
+            Column::ListenerIp => join(self.listener_ips.iter().map(|ip| ip.to_string()).collect()),
+            Column::Port => format_port_ranges(&self.ports),
+            Column::Protocol => self.protocol.clone(),
+            Column::Flags => join(self.flags.iter().cloned().collect()),
+            Column::Count => self.count.to_string(),
+            Column::Sources => self.source_ips.len().to_string(),
+        }
+    }
+}
+
+/// Turns packets into table rows. Rows come out in a stable order: by time for plain
+/// packets, by network pair, protocol and port when aggregated.
+pub fn build_rows(packets: &[PacketInfo], options: &TableOptions) -> Vec<Row> {
+    let rows = packets
+        .iter()
+        .map(|packet| Row::new(packet, options.source_prefix));
+
+    if !options.aggregate {
+        let mut rows: Vec<Row> = rows.collect();
+        // Stable, so packets with identical keys keep their order in the log
+        rows.sort_by(|a, b| {
+            (
+                a.first_seen,
+                a.source_network,
+                &a.source_ips,
+                &a.destination_network,
+            )
+                .cmp(&(
+                    b.first_seen,
+                    b.source_network,
+                    &b.source_ips,
+                    &b.destination_network,
+                ))
+                .then_with(|| (&a.ports, &a.protocol).cmp(&(&b.ports, &b.protocol)))
+        });
+        return rows;
+    }
+
+    let mut groups: BTreeMap<(SourceNetwork, String, String, u16), Row> = BTreeMap::new();
+    for row in rows {
+        // Collapsed rows share port 0 in the key, so one row collects all the ports
+        let port = if options.collapse_ports {
+            0
+        } else {
+            *row.ports.first().unwrap_or(&0)
+        };
+        let key = (
+            row.source_network,
+            row.destination_network.clone(),
+            row.protocol.clone(),
+            port,
+        );
+        match groups.get_mut(&key) {
+            Some(group) => group.merge(row),
+            None => {
+                groups.insert(key, row);
+            }
+        }
+    }
+    groups.into_values().collect()
+}
+
+pub fn write_csv(output: impl Write, rows: &[Row], options: &TableOptions) -> io::Result<()> {
+    let columns = match (options.columns.is_empty(), options.aggregate) {
+        (false, _) => &options.columns[..],
+        (true, false) => PACKET_COLUMNS,
+        (true, true) => AGGREGATE_COLUMNS,
+    };
+
+    let mut writer = csv::Writer::from_writer(output);
+    writer.write_record(columns.iter().map(|column| column_name(*column)))?;
+    for row in rows {
+        writer.write_record(columns.iter().map(|column| row.value(*column)))?;
+    }
+    writer.flush()
+}
+
+fn column_name(column: Column) -> String {
+    use clap::ValueEnum;
+    column
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(source: [u8; 4], tag: &str, port: u16, protocol: &str, seconds: i64) -> PacketInfo {
+        PacketInfo {
+            listener_ip: Ipv4Addr::new(192, 0, 2, 2),
+            network_tag: tag.to_string(),
+            source_ip: source.into(),
+            source_port: 40000,
+            target_port: port,
+            protocol: protocol.to_string(),
+            flags: vec![],
+            timestamp: DateTime::from_timestamp(1_800_000_000 + seconds, 0).unwrap(),
+            metadata: None,
+            last_seen: None,
+            count: None,
+        }
+    }
+
+    fn options(aggregate: bool, collapse_ports: bool) -> TableOptions {
+        TableOptions {
+            columns: vec![],
+            aggregate,
+            collapse_ports,
+            source_prefix: 24,
+        }
+    }
+
+    #[test]
+    fn port_ranges() {
+        assert_eq!(format_port_ranges(&[22]), "22");
+        assert_eq!(format_port_ranges(&[22, 23, 24, 80]), "22-24,80");
+        assert_eq!(
+            format_port_ranges(&[443, 80, 444, 22, 445, 80]),
+            "22,80,443-445"
+        );
+        assert_eq!(format_port_ranges(&[65534, 65535, 1]), "1,65534-65535");
+        assert_eq!(format_port_ranges(&[]), "");
+    }
+
+    #[test]
+    fn rows_in_time_order() {
+        let packets = [
+            packet([10, 0, 1, 5], "pci", 80, "tcp", 2),
+            packet([10, 0, 1, 5], "pci", 22, "tcp", 1),
+            packet([10, 0, 1, 6], "pci", 22, "tcp", 1),
+        ];
+        let rows = build_rows(&packets, &options(false, false));
+        let rows: Vec<(String, u16, u64)> = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.value(Column::SourceIp),
+                    *row.ports.first().unwrap(),
+                    row.count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("10.0.1.5".to_string(), 22, 1),
+                ("10.0.1.6".to_string(), 22, 1),
+                ("10.0.1.5".to_string(), 80, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregated_rows() {
+        let mut aggregated = packet([10, 0, 1, 7], "pci", 23, "tcp", 5);
+        aggregated.count = Some(4);
+        let packets = [
+            packet([10, 0, 1, 5], "pci", 24, "tcp", 3),
+            packet([10, 0, 1, 5], "pci", 22, "tcp", 1),
+            packet([10, 0, 1, 6], "pci", 22, "tcp", 2),
+            aggregated,
+            packet([10, 0, 1, 5], "pci", 53, "udp", 4),
+            packet([10, 0, 2, 5], "pci", 22, "tcp", 6),
+            packet([10, 0, 1, 5], "dmz", 22, "tcp", 7),
+        ];
+        let columns = [
+            Column::SourceNetwork,
+            Column::DestinationNetwork,
+            Column::Port,
+            Column::Protocol,
+            Column::Count,
+            Column::Sources,
+        ];
+        let table = |rows: Vec<Row>| -> Vec<String> {
+            rows.iter()
+                .map(|row| {
+                    let values: Vec<String> =
+                        columns.iter().map(|column| row.value(*column)).collect();
+                    values.join(" | ")
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            table(build_rows(&packets, &options(true, false))),
+            vec![
+                "10.0.1.0/24 | dmz | 22 | tcp | 1 | 1",
+                "10.0.1.0/24 | pci | 22 | tcp | 2 | 2",
+                "10.0.1.0/24 | pci | 23 | tcp | 4 | 1",
+                "10.0.1.0/24 | pci | 24 | tcp | 1 | 1",
+                "10.0.1.0/24 | pci | 53 | udp | 1 | 1",
+                "10.0.2.0/24 | pci | 22 | tcp | 1 | 1",
+            ]
+        );
+
+        // Adjacent ports collapse into one range per network pair and protocol
+        assert_eq!(
+            table(build_rows(&packets, &options(true, true))),
+            vec![
+                "10.0.1.0/24 | dmz | 22 | tcp | 1 | 1",
+                "10.0.1.0/24 | pci | 22-24 | tcp | 7 | 3",
+                "10.0.1.0/24 | pci | 53 | udp | 1 | 1",
+                "10.0.2.0/24 | pci | 22 | tcp | 1 | 1",
+            ]
+        );
+    }
+}
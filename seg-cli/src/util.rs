@@ -1,13 +1,14 @@
-use serde_json::to_string;
 use std::process::Stdio;
-use std::sync::Arc;
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::process::Command;
+use std::time::Duration;
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, SignalKind};
 
 use data::*;
 
-use chrono::{DateTime, Utc};
-use pnet::packet::tcp::TcpFlags;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::{TcpFlags, TcpOptionNumbers, TcpPacket};
+use pnet::packet::Packet;
 
 pub fn tcp_flags_to_iter(flags: u8) -> impl Iterator<Item = &'static str> {
     let mut flag_strings = vec![];
@@ -40,69 +41,425 @@ pub fn tcp_flags_to_iter(flags: u8) -> impl Iterator<Item = &'static str> {
     flag_strings.into_iter()
 }
 
-pub async fn run_command(
+/// Renders TCP options in the order they appear on the wire, e.g. `MSS=1460`, `NOP`, `WS=7`.
+/// The order is as useful as the values for telling scanner stacks apart.
+pub fn tcp_options_to_strings(tcp_packet: &TcpPacket) -> Vec<String> {
+    tcp_packet
+        .get_options_iter()
+        .map(|option| {
+            let data = option.payload();
+            match option.get_number() {
+                TcpOptionNumbers::EOL => "EOL".to_string(),
+                TcpOptionNumbers::NOP => "NOP".to_string(),
+                TcpOptionNumbers::MSS if data.len() == 2 => {
+                    format!("MSS={}", u16::from_be_bytes([data[0], data[1]]))
+                }
+                TcpOptionNumbers::WSCALE if data.len() == 1 => format!("WS={}", data[0]),
+                TcpOptionNumbers::SACK_PERMITTED => "SACK_PERM".to_string(),
+                TcpOptionNumbers::SACK => "SACK".to_string(),
+                TcpOptionNumbers::TIMESTAMPS => "TS".to_string(),
+                number => format!("OPT{}", number.0),
+            }
+        })
+        .collect()
+}
+
+/// Collects the header fields we keep when `--capture-metadata` is set.
+pub fn packet_metadata(
+    ethernet: &EthernetPacket,
+    ip_packet: &Ipv4Packet,
+    tcp_packet: Option<&TcpPacket>,
+) -> PacketMetadata {
+    PacketMetadata {
+        source_mac: ethernet.get_source().to_string(),
+        destination_mac: ethernet.get_destination().to_string(),
+        ttl: ip_packet.get_ttl(),
+        ip_id: ip_packet.get_identification(),
+        dscp: ip_packet.get_dscp(),
+        length: ip_packet.get_total_length(),
+        tcp_window: tcp_packet.map(|tcp| tcp.get_window()),
+        tcp_options: tcp_packet.map(tcp_options_to_strings).unwrap_or_default(),
+    }
+}
+
+/// Runs a command, feeding it `input` on stdin, and returns its stdout. Blocking so it
+/// can be used from panic hooks. Fails if the command exits unsuccessfully.
+pub fn run_command_blocking(
     command: &str,
     args: &[&str],
     input: Option<String>,
-) -> tokio::io::Result<()> {
-    let mut child = Command::new(command)
+) -> std::io::Result<String> {
+    use std::io::Write;
+
+    let mut child = std::process::Command::new(command)
         .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()?;
+        .spawn()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("'{}': {}", command, e)))?;
 
     if let Some(mut stdin) = child.stdin.take() {
-        match input {
-            Some(input) => {
-                stdin
-                    .write_all(input.as_bytes())
-                    .await
-                    .expect("Failed to write rules to nft stdin");
-                stdin.shutdown().await.expect("Failed to flush stdin");
-            }
-            None => {}
+        if let Some(input) = input {
+            stdin.write_all(input.as_bytes())?;
         }
     }
 
-    let output = child.wait_with_output().await?;
+    let output = child.wait_with_output()?;
 
     if !output.status.success() {
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        eprintln!(
-            "Command '{}' failed with status {}\nSTDOUT:\n{}\nSTDERR:\n{}",
-            command, output.status, stdout, stderr
-        );
-        return Err(tokio::io::Error::new(
-            tokio::io::ErrorKind::Other,
-            "Command execution failed",
-        ));
+        return Err(std::io::Error::other(format!(
+            "'{} {}' failed with status {}: {}",
+            command,
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
     }
-    Ok(())
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
 }
 
-pub async fn write_packet_to_log(
-    log_writer: Arc<tokio::sync::Mutex<BufWriter<tokio::fs::File>>>,
-    connection: &PacketInfo,
-) {
-    let json = to_string(connection).unwrap();
+/// Line based diff of two texts. Unchanged lines are prefixed with two spaces,
+/// removed lines with `- ` and added lines with `+ `. Blank lines are ignored.
+///
+/// Uses Myers' linear space algorithm, so diffing large rulesets takes memory
+/// proportional to their length and time proportional to length times changes.
+pub fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().filter(|l| !l.trim().is_empty()).collect();
+    let new: Vec<&str> = new.lines().filter(|l| !l.trim().is_empty()).collect();
+    let old_keys: Vec<&str> = old.iter().map(|l| l.trim()).collect();
+    let new_keys: Vec<&str> = new.iter().map(|l| l.trim()).collect();
+
+    let mut edits = vec![];
+    diff_range(&old_keys, &new_keys, 0, 0, &mut edits);
+    edits
+        .into_iter()
+        .map(|edit| match edit {
+            Edit::Same(j) => format!("  {}", new[j]),
+            Edit::Removed(i) => format!("- {}", old[i]),
+            Edit::Added(j) => format!("+ {}", new[j]),
+        })
+        .collect()
+}
 
-    let mut writer = log_writer.lock().await;
-    writer.write_all(json.as_bytes()).await.unwrap();
-    writer.write_all("\n".as_bytes()).await.unwrap();
-    writer.flush().await.unwrap();
+/// One line of a diff, by its index in the old or new text.
+enum Edit {
+    Same(usize),
+    Removed(usize),
+    Added(usize),
 }
 
-pub async fn write_to_log(
-    log_writer: Arc<tokio::sync::Mutex<BufWriter<tokio::fs::File>>>,
-    entry: String,
+/// Diffs `old` and `new`, which start at `old_start` and `new_start` in the full texts.
+/// Splits the changed middle at its middle snake and diffs both halves.
+fn diff_range(
+    old: &[&str],
+    new: &[&str],
+    old_start: usize,
+    new_start: usize,
+    edits: &mut Vec<Edit>,
 ) {
-    let mut writer = log_writer.lock().await;
-    if let Err(e) = writer.write_all(entry.as_bytes()).await {
-        eprintln!("Failed to write to log: {}", e);
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let (old, new) = (&old[prefix..], &new[prefix..]);
+    let suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old, new) = (&old[..old.len() - suffix], &new[..new.len() - suffix]);
+    let (old_start, new_start) = (old_start + prefix, new_start + prefix);
+
+    edits.extend((new_start - prefix..new_start).map(Edit::Same));
+    if old.is_empty() {
+        edits.extend((new_start..new_start + new.len()).map(Edit::Added));
+    } else if new.is_empty() {
+        edits.extend((old_start..old_start + old.len()).map(Edit::Removed));
+    } else {
+        // Neither half is the whole range: with prefix and suffix stripped there are at
+        // least two edits, and the snake has some on either side
+        let (x, y) = middle_snake(old, new);
+        diff_range(&old[..x], &new[..y], old_start, new_start, edits);
+        diff_range(&old[x..], &new[y..], old_start + x, new_start + y, edits);
+    }
+    let end = new_start + new.len();
+    edits.extend((end..end + suffix).map(Edit::Same));
+}
+
+/// Finds where a shortest edit script crosses its middle, searching from both ends
+/// at once and keeping only the furthest point reached on each diagonal.
+fn middle_snake(old: &[&str], new: &[&str]) -> (usize, usize) {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2 + 1;
+    // Furthest x on each diagonal k = x - y, counted from the start going forwards
+    // and from the end going backwards
+    let mut forward = vec![0isize; 2 * max as usize + 3];
+    let mut backward = vec![0isize; 2 * max as usize + 3];
+    let index = |k: isize| (k + max + 1) as usize;
+
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && forward[index(k - 1)] < forward[index(k + 1)]) {
+                forward[index(k + 1)]
+            } else {
+                forward[index(k - 1)] + 1
+            };
+            let (start_x, start_y) = (x, x - k);
+            let mut y = start_y;
+            while x < n && y >= 0 && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[index(k)] = x;
+            if odd && (delta - k).abs() < d && x + backward[index(delta - k)] >= n {
+                return (start_x as usize, start_y as usize);
+            }
+        }
+
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && backward[index(k - 1)] < backward[index(k + 1)]) {
+                backward[index(k + 1)]
+            } else {
+                backward[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y >= 0 && y < m && old[(n - 1 - x) as usize] == new[(m - 1 - y) as usize]
+            {
+                x += 1;
+                y += 1;
+            }
+            backward[index(k)] = x;
+            if !odd && (delta - k).abs() <= d && x + forward[index(delta - k)] >= n {
+                return ((n - x) as usize, (m - y) as usize);
+            }
+        }
+    }
+    unreachable!("every pair of texts has an edit script")
+}
+
+/// Parses durations such as `90`, `90s`, `15m`, `12h` or `1d`. Bare numbers are seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(format!("unknown duration unit '{}' in '{}'", unit, value)),
+    };
+
+    amount
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too large", value))
+}
+
+/// Parses sizes such as `1048576`, `512K`, `100M` or `2G` into bytes.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown size unit '{}' in '{}'", unit, value)),
+    };
+
+    amount
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", value))
+}
+
+/// Whether the process holds capability `cap` in its effective set, read from
+/// `/proc/self/status`.
+pub fn has_capability(cap: u32) -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))
+                .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        })
+        .is_some_and(|mask| mask & (1 << cap) != 0)
+}
+
+/// Escapes text for use in HTML element content and attribute values.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Resolves with the signal's name once we're asked to stop, whether that's Ctrl-C,
+/// systemd/Docker stopping us or the controlling terminal going away.
+pub async fn shutdown_signal() -> &'static str {
+    tokio::select! {
+        _ = ctrl_c() => "SIGINT",
+        _ = receive_signal(SignalKind::terminate(), "SIGTERM") => "SIGTERM",
+        _ = receive_signal(SignalKind::hangup(), "SIGHUP") => "SIGHUP",
+    }
+}
+
+/// Resolves once `kind` is received. If the handler can't be installed we carry on
+/// without it, the other signals still work.
+async fn receive_signal(kind: SignalKind, name: &str) {
+    match signal(kind) {
+        Ok(mut signal) => {
+            signal.recv().await;
+        }
+        Err(e) => {
+            eprintln!("Failed to listen for {}: {}", name, e);
+            std::future::pending().await
+        }
     }
-    if let Err(e) = writer.flush().await {
-        eprintln!("Failed to flush log writer: {}", e);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Length of the longest common subsequence, the number of unchanged lines in a
+    /// shortest diff.
+    fn lcs(old: &[&str], new: &[&str]) -> usize {
+        let mut table = vec![vec![0; new.len() + 1]; old.len() + 1];
+        for i in (0..old.len()).rev() {
+            for j in (0..new.len()).rev() {
+                table[i][j] = if old[i] == new[j] {
+                    table[i + 1][j + 1] + 1
+                } else {
+                    table[i + 1][j].max(table[i][j + 1])
+                };
+            }
+        }
+        table[0][0]
+    }
+
+    #[test]
+    fn diff_hunks() {
+        let old = "table ip seg {\n    chain input {\n        tcp dport 22 accept\n        tcp dport 23 accept\n\n    }\n}\n";
+        let new = "table ip seg {\n  chain input {\n        tcp dport 22 accept\n        udp dport 53 accept\n    }\n}\n";
+        assert_eq!(
+            diff_lines(old, new),
+            vec![
+                "  table ip seg {",
+                // Indentation doesn't count as a change, the new line is shown
+                "    chain input {",
+                "          tcp dport 22 accept",
+                "-         tcp dport 23 accept",
+                "+         udp dport 53 accept",
+                "      }",
+                "  }",
+            ]
+        );
+        assert_eq!(diff_lines("", "a\nb"), vec!["+ a", "+ b"]);
+        assert_eq!(diff_lines("a\nb", ""), vec!["- a", "- b"]);
+        assert!(diff_lines("", "").is_empty());
+    }
+
+    #[test]
+    fn diff_is_shortest() {
+        // Every pair of short texts over a small alphabet, which covers odd and even
+        // length differences and changes at either end
+        let texts: Vec<Vec<&str>> = (0..3u32.pow(5))
+            .flat_map(|seed| {
+                (0..=5).map(move |len| {
+                    (0..len)
+                        .map(|i| ["a", "b", "c"][(seed / 3u32.pow(i)) as usize % 3])
+                        .collect()
+                })
+            })
+            .step_by(7)
+            .collect();
+        for old in &texts {
+            for new in &texts {
+                let diff = diff_lines(&old.join("\n"), &new.join("\n"));
+                let kept: Vec<&str> = diff
+                    .iter()
+                    .filter(|line| !line.starts_with('+'))
+                    .map(|line| &line[2..])
+                    .collect();
+                let added: Vec<&str> = diff
+                    .iter()
+                    .filter(|line| !line.starts_with('-'))
+                    .map(|line| &line[2..])
+                    .collect();
+                assert_eq!(&kept, old, "{:?} -> {:?}", old, new);
+                assert_eq!(&added, new, "{:?} -> {:?}", old, new);
+                let same = diff.iter().filter(|line| line.starts_with(' ')).count();
+                assert_eq!(same, lcs(old, new), "{:?} -> {:?}", old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 90s "), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(43_200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86_400)));
+
+        for invalid in ["", "m", "-5m", "1.5h", "5 m", "5w", "5M", "5ms"] {
+            assert!(parse_duration(invalid).is_err(), "accepted {:?}", invalid);
+        }
+        assert_eq!(
+            parse_duration("5w"),
+            Err("unknown duration unit 'w' in '5w'".to_string())
+        );
+        // Too large for u64 seconds once multiplied, and too large to parse at all
+        assert_eq!(
+            parse_duration("213503982334602d"),
+            Err("duration '213503982334602d' is too large".to_string())
+        );
+        assert!(parse_duration("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1048576"), Ok(1_048_576));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size("512K"), Ok(524_288));
+        assert_eq!(parse_size("100mb"), Ok(104_857_600));
+        assert_eq!(parse_size("2G"), Ok(2_147_483_648));
+
+        for invalid in ["", "K", "-1K", "1.5G", "1 G", "1T", "1KiB"] {
+            assert!(parse_size(invalid).is_err(), "accepted {:?}", invalid);
+        }
+        assert_eq!(
+            parse_size("1T"),
+            Err("unknown size unit 'T' in '1T'".to_string())
+        );
+        assert_eq!(
+            parse_size("17179869184G"),
+            Err("size '17179869184G' is too large".to_string())
+        );
+        assert!(parse_size("18446744073709551616").is_err());
     }
 }
@@ -1,40 +1,9 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use data::PacketInfo;
-use std::{
-    collections::HashSet,
-    env,
-    fs::File,
-    hash::{Hash, Hasher},
-    io::{self, BufRead},
-};
-
-use serde::{Deserialize, Serialize};
-
-#[derive(Clone, Debug, Serialize, PartialEq, Eq, Hash)]
-struct NodeDatum {
-    id: String,
-    label: String,
-    shape: String,
-    color: String,
-}
-
-#[derive(Clone, Debug, Serialize)]
-struct LinkDatum {
-    id: String,
-    label: String,
-    source: String,
-    target: String,
-    active: bool,
-    color: String,
-}
-
-#[derive(Clone, Debug, Serialize)]
-struct GraphData {
-    nodes: Vec<NodeDatum>,
-    links: Vec<LinkDatum>,
-}
+use chrono::{DateTime, Utc};
+use data::{build_graph, Filter, LogReader, PacketInfo, PacketMetadata};
+use rusqlite::Connection;
 
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 #[tauri::command]
@@ -42,69 +11,113 @@ fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
-#[tauri::command]
-fn load_data(file_path: &str) -> String {
-    println!("Loading file {}", file_path);
-
-    let file = File::open(file_path).unwrap();
-    let lines = io::BufReader::new(file).lines();
-    let mut nodes: HashSet<NodeDatum> = HashSet::new();
-    let mut links: Vec<LinkDatum> = Vec::new();
-
-    for (idx, line) in lines.enumerate() {
-        let line = line.unwrap();
-        let packet_info: PacketInfo = serde_json::from_str(line.as_str())
-            .expect(format!("Failed to parse data - line number {:?}\n{:?}", idx, line,).as_str());
-
-        // Gather the hosts as nodes
+fn conversion_error(
+    idx: usize,
+    e: impl std::error::Error + Send + Sync + 'static,
+) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(e))
+}
 
-        // Source / Scanner Node
-        nodes.insert(NodeDatum {
-            id: format!("{}:{}", packet_info.source_ip.to_string(), "scanner"),
-            label: format!(
-                "{}:{}:{}",
-                packet_info.network_tag,
-                packet_info.source_ip.to_string(),
-                "scanner",
-            ),
-            shape: "hexagon".to_string(),
-            color: "#35D068".to_string(),
-        });
+/// Reads packets from a database written by `seg parse --sqlite`. The graph only needs
+/// the first packet of every session, so big datasets load quickly, but with
+/// `all_records` every record is read so a filter sees what the logs held, e.g. a
+/// `flags has ACK` that only a later record of a session matches.
+fn load_database(file_path: &str, all_records: bool) -> rusqlite::Result<Vec<PacketInfo>> {
+    let connection = Connection::open(file_path)?;
+    let first_only = if all_records {
+        ""
+    } else {
+        "JOIN (SELECT MIN(id) AS id FROM records GROUP BY session_id) first ON p.id = first.id"
+    };
+    let mut query = connection.prepare(&format!(
+        "SELECT p.listener_ip, p.network_tag, p.source_ip, p.source_port, p.target_port,
+                p.protocol, p.flags, p.timestamp, p.last_seen, p.count,
+                r.source_mac, r.destination_mac, r.ttl, r.ip_id, r.dscp, r.length,
+                r.tcp_window, r.tcp_options
+         FROM packets p
+         JOIN records r ON r.id = p.id
+         {}
+         ORDER BY p.id",
+        first_only
+    ))?;
+    let packets = query.query_map([], |row| {
+        let ip = |idx: usize| -> rusqlite::Result<_> {
+            row.get::<_, String>(idx)?
+                .parse()
+                .map_err(|e| conversion_error(idx, e))
+        };
+        let time = |idx: usize| -> rusqlite::Result<DateTime<Utc>> {
+            DateTime::parse_from_rfc3339(&row.get::<_, String>(idx)?)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|e| conversion_error(idx, e))
+        };
+        let words = |text: String| text.split_whitespace().map(String::from).collect();
+        // Only records captured with --capture-metadata have it
+        let metadata = match row.get::<_, Option<u8>>(12)? {
+            Some(ttl) => Some(PacketMetadata {
+                source_mac: row.get::<_, Option<String>>(10)?.unwrap_or_default(),
+                destination_mac: row.get::<_, Option<String>>(11)?.unwrap_or_default(),
+                ttl,
+                ip_id: row.get::<_, Option<u16>>(13)?.unwrap_or_default(),
+                dscp: row.get::<_, Option<u8>>(14)?.unwrap_or_default(),
+                length: row.get::<_, Option<u16>>(15)?.unwrap_or_default(),
+                tcp_window: row.get(16)?,
+                tcp_options: row
+                    .get::<_, Option<String>>(17)?
+                    .map(words)
+                    .unwrap_or_default(),
+            }),
+            None => None,
+        };
+        Ok(PacketInfo {
+            listener_ip: ip(0)?,
+            network_tag: row.get(1)?,
+            source_ip: ip(2)?,
+            source_port: row.get(3)?,
+            target_port: row.get(4)?,
+            protocol: row.get(5)?,
+            flags: words(row.get(6)?),
+            timestamp: time(7)?,
+            metadata,
+            last_seen: Some(time(8)?),
+            count: Some(row.get(9)?),
+        })
+    })?;
+    packets.collect()
+}
 
-        // Destination / listner node
-        nodes.insert(NodeDatum {
-            id: format!("{}:{}", packet_info.listener_ip.to_string(), "listener"),
-            label: format!(
-                "{}:{}:{}",
-                packet_info.network_tag,
-                packet_info.listener_ip.to_string(),
-                "listener",
-            ),
-            shape: "square".to_string(),
-            color: "#35D068".to_string(),
-        });
+/// Builds the graph of a log or database. Errors are returned as text for the page to
+/// show, a bad filter included.
+#[tauri::command]
+fn load_data(file_path: &str, filter: Option<String>) -> Result<String, String> {
+    println!("Loading file {}", file_path);
 
-        // Create links
-        links.push(LinkDatum {
-            id: format!(
-                "{}:{}:{}",
-                packet_info.source_ip.to_string(),
-                packet_info.listener_ip.to_string(),
-                packet_info.target_port.to_string(),
-            ),
-            label: format!("{} -> {}", packet_info.source_port, packet_info.target_port),
-            source: format!("{}:{}", packet_info.source_ip.to_string(), "scanner"),
-            target: format!("{}:{}", packet_info.listener_ip.to_string(), "listener"),
-            active: true,
-            color: "#35D068".to_string(),
-        });
+    let filter = filter
+        .filter(|filter| !filter.trim().is_empty())
+        .map(|filter| Filter::parse(&filter))
+        .transpose()
+        .map_err(|e| format!("Invalid filter: {}", e))?;
+
+    let mut packets: Vec<_> = if file_path.ends_with(".db") || file_path.ends_with(".sqlite") {
+        load_database(file_path, filter.is_some())
+            .map_err(|e| format!("Failed to read database: {}", e))?
+    } else {
+        let records = LogReader::open(file_path)
+            .map_err(|e| format!("Failed to open {}: {}", file_path, e))?;
+        // Older log versions are upgraded by the reader, counter summaries aren't drawn
+        let mut packets = vec![];
+        for record in records {
+            let record = record.map_err(|e| format!("Failed to parse data: {}", e))?;
+            packets.extend(record.into_packet());
+        }
+        packets
+    };
+    if let Some(filter) = filter {
+        packets.retain(|packet| filter.matches(packet));
     }
+    let graph = build_graph(&packets);
 
-    let nodes: Vec<_> = nodes.into_iter().collect();
-    let graph = GraphData { nodes, links };
-
-    serde_json::to_string(&graph)
-        .expect(format!("Failed to serialize graph data: {:?}", graph).as_str())
+    serde_json::to_string(&graph).map_err(|e| format!("Failed to serialize graph data: {}", e))
 }
 
 fn main() {
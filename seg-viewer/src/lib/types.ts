@@ -9,6 +9,20 @@ export type PacketInfo = {
   protocol: "tcp" | "udp";
   flags: string[];
   timestamp: string;
+  metadata?: PacketMetadata;
+  last_seen?: string;
+  count?: number;
+};
+
+export type PacketMetadata = {
+  source_mac: string;
+  destination_mac: string;
+  ttl: number;
+  ip_id: number;
+  dscp: number;
+  length: number;
+  tcp_window?: number;
+  tcp_options?: string[];
 };
 
 export const PacketInfoSchema = z.object({
@@ -20,4 +34,18 @@ export const PacketInfoSchema = z.object({
   protocol: z.enum(["tcp", "udp"]),
   flags: z.array(z.string()),
   timestamp: z.string(),
+  metadata: z
+    .object({
+      source_mac: z.string(),
+      destination_mac: z.string(),
+      ttl: z.number().int().min(0).max(255),
+      ip_id: z.number().int().min(0).max(65535),
+      dscp: z.number().int().min(0).max(63),
+      length: z.number().int().min(0).max(65535),
+      tcp_window: z.number().int().min(0).max(65535).optional(),
+      tcp_options: z.array(z.string()).optional(),
+    })
+    .optional(),
+  last_seen: z.string().optional(),
+  count: z.number().int().min(1).optional(),
 });